@@ -0,0 +1,657 @@
+//! Local copy of the events of a calendar collection, kept up to date with the changes of the
+//! server rather than downloaded again for every agenda. Every sync is persisted to the
+//! [`EventStore`], from which the cache is loaded again after a restart.
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::cal::{
+    get_ctag, get_etags, multiget, sync_collection, CalDavCredentials, Resource, SyncChanges,
+};
+use crate::event::Event;
+use crate::parser;
+use crate::store::{EventStore, StoredResource};
+
+/// How long a sync is considered fresh, so that bursts of requests are served from the cache
+const MIN_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of resources read by a single `calendar-multiget`
+const MULTIGET_BATCH: usize = 100;
+
+/// The resources a sync added, changed or removed
+#[derive(Debug, Default)]
+pub struct SyncDiff {
+    pub added: Vec<Url>,
+    pub changed: Vec<Url>,
+    pub removed: Vec<Url>,
+    /// Resources read again with the same data, whose new ETag still needs storing
+    refreshed: Vec<Url>,
+}
+
+impl SyncDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct CalendarCache {
+    /// The URL of the calendar collection
+    url: Url,
+    store: Arc<EventStore>,
+    resources: HashMap<Url, StoredResource>,
+    sync_token: Option<String>,
+    ctag: Option<String>,
+    last_sync: Option<Instant>,
+    /// Whether the cache was ever synced, and holds the events of the calendar
+    synced: bool,
+    /// Whether the server supports `sync-collection`, assumed until it fails
+    supports_sync_collection: bool,
+}
+
+impl CalendarCache {
+    /// The cache of the calendar at `url`, holding the events stored at its last sync if any
+    pub fn load(url: Url, store: Arc<EventStore>) -> Self {
+        let stored = store.load(&url).unwrap_or_else(|err| {
+            log::warn!("Unable to load the stored events of {}: {}", url, err);
+            None
+        });
+        let mut cache = Self {
+            url,
+            store,
+            resources: HashMap::new(),
+            sync_token: None,
+            ctag: None,
+            last_sync: None,
+            synced: false,
+            supports_sync_collection: true,
+        };
+        if let Some(stored) = stored {
+            log::debug!(
+                "Loaded {} resources of {}, stored at {}",
+                stored.resources.len(),
+                cache.url,
+                stored.synced_at
+            );
+            cache.resources = stored.resources;
+            cache.sync_token = stored.sync_token;
+            cache.ctag = stored.ctag;
+            cache.synced = true;
+        }
+        cache
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Makes the next sync happen right away, after the calendar was changed by the bot
+    pub fn invalidate(&mut self) {
+        self.last_sync = None;
+    }
+
+    /// Fetches the changes of the calendar since the last sync.
+    ///
+    /// RFC6578 `sync-collection` is used when the server supports it. Otherwise the `getctag` of
+    /// the collection tells whether anything changed, and the ETags of its resources which ones.
+    ///
+    /// The changes are stored even when the sync fails halfway, along with the previous sync
+    /// state, so that the store never misses a change the cache has seen.
+    pub async fn sync(
+        &mut self,
+        credentials: &CalDavCredentials,
+        default_timezone: &Tz,
+    ) -> Result<SyncDiff, Box<dyn Error>> {
+        let mut diff = SyncDiff::default();
+        if self
+            .last_sync
+            .is_some_and(|last_sync| last_sync.elapsed() < MIN_SYNC_INTERVAL)
+        {
+            return Ok(diff);
+        }
+
+        let result = self
+            .sync_with(credentials, default_timezone, &mut diff)
+            .await;
+        self.persist(&diff, result.is_ok());
+        result.map(|()| diff)
+    }
+
+    async fn sync_with(
+        &mut self,
+        credentials: &CalDavCredentials,
+        default_timezone: &Tz,
+        diff: &mut SyncDiff,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.supports_sync_collection {
+            match self.sync_changes(credentials, default_timezone, diff).await {
+                Ok(()) => {
+                    self.last_sync = Some(Instant::now());
+                    self.synced = true;
+                    return Ok(());
+                }
+                Err(err) => self.sync_collection_failed(credentials.url(), err)?,
+            }
+        }
+
+        self.sync_etags(credentials, default_timezone, diff).await?;
+        self.last_sync = Some(Instant::now());
+        self.synced = true;
+        Ok(())
+    }
+
+    /// Handles a failed `sync-collection`: the error is returned when the server could not be
+    /// reached, which says nothing of what it supports. Otherwise the server is assumed not to
+    /// support it, and ETags are compared from then on.
+    fn sync_collection_failed(
+        &mut self,
+        url: &Url,
+        err: Box<dyn Error>,
+    ) -> Result<(), Box<dyn Error>> {
+        if is_unreachable(err.as_ref()) {
+            return Err(err);
+        }
+        log::info!(
+            "No sync-collection support for {}, comparing ETags instead: {}",
+            url,
+            err
+        );
+        self.supports_sync_collection = false;
+        self.sync_token = None;
+        Ok(())
+    }
+
+    /// The occurrences of the cached events in the `start..end` window, in chronological order
+    pub fn events(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Vec<Event> {
+        let mut events: Vec<Event> = self
+            .resources
+            .values()
+            .filter_map(|resource| Some((resource.series.as_ref()?, &resource.etag)))
+            .flat_map(|(series, etag)| {
+                series.occurrences(start, end).into_iter().map(|mut event| {
+                    event.set_etag(etag.clone());
+                    event
+                })
+            })
+            .collect();
+        events.sort();
+        events
+    }
+
+    /// Writes the changes of a sync to the store
+    fn persist(&self, diff: &SyncDiff, succeeded: bool) {
+        let written: Vec<(&Url, &StoredResource)> = diff
+            .added
+            .iter()
+            .chain(&diff.changed)
+            .chain(&diff.refreshed)
+            .filter_map(|url| Some((url, self.resources.get(url)?)))
+            .collect();
+        if let Err(err) = self.store.save(
+            &self.url,
+            self.sync_token.as_deref(),
+            self.ctag.as_deref(),
+            succeeded,
+            &written,
+            &diff.removed,
+        ) {
+            log::warn!("Unable to store the events of {}: {}", self.url, err);
+        }
+    }
+
+    async fn sync_changes(
+        &mut self,
+        credentials: &CalDavCredentials,
+        default_timezone: &Tz,
+        diff: &mut SyncDiff,
+    ) -> Result<(), Box<dyn Error>> {
+        let changes = match sync_collection(credentials, self.sync_token.as_deref()).await {
+            Ok(changes) => Some(changes),
+            Err(err) if self.sync_token.is_some() && !is_unreachable(err.as_ref()) => {
+                log::warn!(
+                    "Sync token of {} rejected, syncing again: {}",
+                    credentials.url(),
+                    err
+                );
+                None
+            }
+            Err(err) => return Err(err),
+        };
+        let changes = match changes {
+            Some(changes) => changes,
+            None => {
+                // The token may have expired: start over from an empty one
+                self.sync_token = None;
+                sync_collection(credentials, None).await?
+            }
+        };
+
+        self.apply_sync_changes(&changes, diff);
+        self.fetch(credentials, changes.changed, default_timezone, diff)
+            .await?;
+        self.sync_token = changes.token;
+        Ok(())
+    }
+
+    /// Removes the resources a `sync-collection` report tells are gone
+    fn apply_sync_changes(&mut self, changes: &SyncChanges, diff: &mut SyncDiff) {
+        if self.sync_token.is_none() {
+            // A sync from scratch lists every resource, so anything else is gone
+            let listed: Vec<&Url> = changes.changed.iter().map(|(url, _)| url).collect();
+            self.remove(diff, |url| !listed.contains(&url));
+        }
+        self.remove(diff, |url| changes.removed.contains(url));
+    }
+
+    async fn sync_etags(
+        &mut self,
+        credentials: &CalDavCredentials,
+        default_timezone: &Tz,
+        diff: &mut SyncDiff,
+    ) -> Result<(), Box<dyn Error>> {
+        let ctag = get_ctag(credentials).await.unwrap_or_else(|err| {
+            log::debug!("No ctag for {}: {}", credentials.url(), err);
+            None
+        });
+        if self.is_unchanged(ctag.as_deref()) {
+            return Ok(());
+        }
+
+        let etags = get_etags(credentials).await?;
+        self.remove_unlisted(&etags, diff);
+        self.fetch(credentials, etags, default_timezone, diff)
+            .await?;
+        self.ctag = ctag;
+        Ok(())
+    }
+
+    /// Whether the collection is unchanged since the last sync, according to its `ctag`
+    fn is_unchanged(&self, ctag: Option<&str>) -> bool {
+        ctag.is_some() && ctag == self.ctag.as_deref()
+    }
+
+    /// Removes the cached resources missing from the listing of the collection
+    fn remove_unlisted(&mut self, etags: &[(Url, Option<String>)], diff: &mut SyncDiff) {
+        self.remove(diff, |url| !etags.iter().any(|(listed, _)| listed == url));
+    }
+
+    /// Removes the cached resources whose URL matches `predicate`
+    fn remove(&mut self, diff: &mut SyncDiff, predicate: impl Fn(&Url) -> bool) {
+        let removed: Vec<Url> = self
+            .resources
+            .keys()
+            .filter(|url| predicate(url))
+            .cloned()
+            .collect();
+        for url in &removed {
+            self.resources.remove(url);
+        }
+        diff.removed.extend(removed);
+    }
+
+    /// Reads the resources whose ETag differs from the cached one
+    async fn fetch(
+        &mut self,
+        credentials: &CalDavCredentials,
+        etags: Vec<(Url, Option<String>)>,
+        default_timezone: &Tz,
+        diff: &mut SyncDiff,
+    ) -> Result<(), Box<dyn Error>> {
+        let batches = self.fetch_batches(etags);
+        if !batches.is_empty() {
+            log::debug!(
+                "Fetching {} changed resources of {}",
+                batches.iter().map(Vec::len).sum::<usize>(),
+                credentials.url()
+            );
+        }
+        for batch in batches {
+            let resources = multiget(credentials, &batch).await?;
+            self.apply_resources(&batch, resources, default_timezone, diff);
+        }
+        Ok(())
+    }
+
+    /// The resources whose ETag differs from the cached one, in batches of at most
+    /// `MULTIGET_BATCH` to read at once
+    fn fetch_batches(&self, etags: Vec<(Url, Option<String>)>) -> Vec<Vec<Url>> {
+        let changed: Vec<Url> = etags
+            .into_iter()
+            .filter(|(url, etag)| {
+                etag.is_none()
+                    || self
+                        .resources
+                        .get(url)
+                        .is_none_or(|cached| &cached.etag != etag)
+            })
+            .map(|(url, _)| url)
+            .collect();
+        changed
+            .chunks(MULTIGET_BATCH)
+            .map(<[Url]>::to_vec)
+            .collect()
+    }
+
+    /// Caches the resources read for a batch of URLs. Those missing from them were removed
+    /// since they were listed.
+    fn apply_resources(
+        &mut self,
+        batch: &[Url],
+        resources: Vec<Resource>,
+        default_timezone: &Tz,
+        diff: &mut SyncDiff,
+    ) {
+        let mut missing: Vec<&Url> = batch.iter().collect();
+        for resource in resources {
+            missing.retain(|url| **url != resource.url);
+            let series = match parser::parse(&resource.data, resource.url.clone(), default_timezone)
+            {
+                Ok(series) => Some(series),
+                Err(err) => {
+                    log::warn!("Skipping {}: {}", resource.url, err);
+                    None
+                }
+            };
+            match self.resources.get(&resource.url) {
+                Some(cached) if cached.data == resource.data => {
+                    diff.refreshed.push(resource.url.clone())
+                }
+                Some(_) => diff.changed.push(resource.url.clone()),
+                None => diff.added.push(resource.url.clone()),
+            }
+            self.resources.insert(
+                resource.url,
+                StoredResource {
+                    etag: resource.etag,
+                    data: resource.data,
+                    series,
+                },
+            );
+        }
+        self.remove(diff, |url| missing.contains(&url));
+    }
+}
+
+/// Whether a request failed because the server could not be reached, rather than because of its
+/// response
+fn is_unreachable(err: &(dyn Error + 'static)) -> bool {
+    err.is::<reqwest::Error>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cal::parse_sync_changes;
+    use crate::event::CalendarLabel;
+    use chrono::TimeZone;
+    use std::path::Path;
+
+    fn collection() -> Url {
+        Url::parse("https://dav.example.org/cal/work/").unwrap()
+    }
+
+    fn url(name: &str) -> Url {
+        collection().join(name).unwrap()
+    }
+
+    /// An empty cache of the collection, along with its in-memory store
+    fn cache() -> (CalendarCache, Arc<EventStore>) {
+        let store = Arc::new(EventStore::open(Path::new(":memory:")).unwrap());
+        store
+            .save_calendars(
+                "work",
+                &[(collection(), CalendarLabel::new("Work".to_string(), None))],
+            )
+            .unwrap();
+        (CalendarCache::load(collection(), store.clone()), store)
+    }
+
+    fn resource(name: &str, etag: &str, summary: &str) -> Resource {
+        Resource {
+            url: url(name),
+            etag: Some(etag.to_string()),
+            data: [
+                "BEGIN:VCALENDAR",
+                "VERSION:2.0",
+                "BEGIN:VEVENT",
+                &format!("UID:{}", name),
+                &format!("SUMMARY:{}", summary),
+                "DTSTAMP:20240101T000000Z",
+                "DTSTART:20241104T180000Z",
+                "DTEND:20241104T190000Z",
+                "END:VEVENT",
+                "END:VCALENDAR",
+                "",
+            ]
+            .join("\r\n"),
+        }
+    }
+
+    /// A `sync-collection` report with the given token, and responses made of an href and of
+    /// either an ETag or a 404 status when it is `None`
+    fn report(token: &str, responses: &[(&str, Option<&str>)]) -> String {
+        let responses: String = responses
+            .iter()
+            .map(|(href, etag)| match etag {
+                Some(etag) => format!(
+                    "<D:response><D:href>/cal/work/{}</D:href><D:propstat><D:prop>\
+                     <D:getetag>{}</D:getetag></D:prop>\
+                     <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+                    href, etag
+                ),
+                None => format!(
+                    "<D:response><D:href>/cal/work/{}</D:href>\
+                     <D:status>HTTP/1.1 404 Not Found</D:status></D:response>",
+                    href
+                ),
+            })
+            .collect();
+        format!(
+            "<?xml version=\"1.0\"?><D:multistatus xmlns:D=\"DAV:\">{}\
+             <D:sync-token>{}</D:sync-token></D:multistatus>",
+            responses, token
+        )
+    }
+
+    /// Applies a `sync-collection` report like a sync, with the resources the server returns
+    fn sync_report(cache: &mut CalendarCache, report: &str, resources: &[Resource]) -> SyncDiff {
+        let mut diff = SyncDiff::default();
+        let changes = parse_sync_changes(report, &collection()).unwrap();
+        cache.apply_sync_changes(&changes, &mut diff);
+        for batch in cache.fetch_batches(changes.changed) {
+            let read = resources
+                .iter()
+                .filter(|resource| batch.contains(&resource.url))
+                .cloned()
+                .collect();
+            cache.apply_resources(&batch, read, &chrono_tz::UTC, &mut diff);
+        }
+        cache.sync_token = changes.token;
+        diff
+    }
+
+    fn names(cache: &CalendarCache) -> Vec<String> {
+        let start = Utc.with_ymd_and_hms(2024, 11, 1, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap();
+        cache
+            .events(&start, &end)
+            .iter()
+            .map(|event| event.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn applies_the_changes_of_sync_collection_reports() {
+        let (mut cache, _) = cache();
+        let diff = sync_report(
+            &mut cache,
+            &report(
+                "1",
+                &[
+                    ("", Some("\"0\"")),
+                    ("a.ics", Some("\"1\"")),
+                    ("b.ics", Some("\"1\"")),
+                ],
+            ),
+            &[
+                resource("a.ics", "\"1\"", "A"),
+                resource("b.ics", "\"1\"", "B"),
+            ],
+        );
+        assert_eq!(diff.added, vec![url("a.ics"), url("b.ics")]);
+        assert!(diff.changed.is_empty() && diff.removed.is_empty());
+        assert_eq!(cache.sync_token.as_deref(), Some("1"));
+        assert_eq!(names(&cache), vec!["A", "B"]);
+
+        let diff = sync_report(
+            &mut cache,
+            &report(
+                "2",
+                &[
+                    ("a.ics", Some("\"2\"")),
+                    ("b.ics", None),
+                    ("c.ics", Some("\"1\"")),
+                ],
+            ),
+            &[
+                resource("a.ics", "\"2\"", "A renamed"),
+                resource("c.ics", "\"1\"", "C"),
+            ],
+        );
+        assert_eq!(diff.added, vec![url("c.ics")]);
+        assert_eq!(diff.changed, vec![url("a.ics")]);
+        assert_eq!(diff.removed, vec![url("b.ics")]);
+        assert_eq!(names(&cache), vec!["A renamed", "C"]);
+
+        // A new ETag for the same data is no change
+        let diff = sync_report(
+            &mut cache,
+            &report("3", &[("c.ics", Some("\"3\""))]),
+            &[resource("c.ics", "\"3\"", "C")],
+        );
+        assert!(diff.is_empty());
+        assert_eq!(diff.refreshed, vec![url("c.ics")]);
+        assert_eq!(
+            cache.resources[&url("c.ics")].etag.as_deref(),
+            Some("\"3\"")
+        );
+
+        // A resource listed but gone when read is removed
+        let diff = sync_report(&mut cache, &report("4", &[("c.ics", Some("\"4\""))]), &[]);
+        assert_eq!(diff.removed, vec![url("c.ics")]);
+        assert_eq!(names(&cache), vec!["A renamed"]);
+    }
+
+    #[test]
+    fn removes_unlisted_resources_when_syncing_from_scratch() {
+        let (mut cache, _) = cache();
+        sync_report(
+            &mut cache,
+            &report("1", &[("a.ics", Some("\"1\"")), ("b.ics", Some("\"1\""))]),
+            &[
+                resource("a.ics", "\"1\"", "A"),
+                resource("b.ics", "\"1\"", "B"),
+            ],
+        );
+
+        // As after a rejected sync token
+        cache.sync_token = None;
+        let diff = sync_report(&mut cache, &report("5", &[("b.ics", Some("\"1\""))]), &[]);
+        assert_eq!(diff.removed, vec![url("a.ics")]);
+        assert!(diff.added.is_empty() && diff.changed.is_empty());
+        assert_eq!(names(&cache), vec!["B"]);
+    }
+
+    #[test]
+    fn falls_back_to_etags_without_sync_collection() {
+        let (mut cache, _) = cache();
+        cache.sync_token = Some("1".to_string());
+        assert!(cache
+            .sync_collection_failed(&collection(), "Unsupported report".into())
+            .is_ok());
+        assert!(!cache.supports_sync_collection);
+        assert_eq!(cache.sync_token, None);
+
+        let mut diff = SyncDiff::default();
+        let etags = vec![(url("a.ics"), Some("\"1\"".to_string()))];
+        let batches = cache.fetch_batches(etags.clone());
+        assert_eq!(batches, vec![vec![url("a.ics")]]);
+        cache.apply_resources(
+            &batches[0],
+            vec![resource("a.ics", "\"1\"", "A")],
+            &chrono_tz::UTC,
+            &mut diff,
+        );
+        assert!(cache.fetch_batches(etags).is_empty());
+
+        cache.remove_unlisted(&[], &mut diff);
+        assert_eq!(diff.added, vec![url("a.ics")]);
+        assert_eq!(diff.removed, vec![url("a.ics")]);
+
+        cache.ctag = Some("c1".to_string());
+        assert!(cache.is_unchanged(Some("c1")));
+        assert!(!cache.is_unchanged(Some("c2")));
+        assert!(!cache.is_unchanged(None));
+    }
+
+    #[test]
+    fn reads_changed_resources_in_batches() {
+        let (mut cache, _) = cache();
+        let mut diff = SyncDiff::default();
+        cache.apply_resources(
+            &[url("a.ics")],
+            vec![resource("a.ics", "\"1\"", "A")],
+            &chrono_tz::UTC,
+            &mut diff,
+        );
+
+        let mut etags: Vec<(Url, Option<String>)> = (0..250)
+            .map(|index| (url(&format!("{}.ics", index)), Some("\"1\"".to_string())))
+            .collect();
+        // Unchanged, and without an ETag to compare
+        etags.push((url("a.ics"), Some("\"1\"".to_string())));
+        etags.push((url("b.ics"), None));
+
+        let batches = cache.fetch_batches(etags);
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![MULTIGET_BATCH, MULTIGET_BATCH, 51]);
+        assert_eq!(batches[2].last(), Some(&url("b.ics")));
+        assert!(!batches
+            .iter()
+            .flatten()
+            .any(|url| url.as_str().ends_with("/a.ics")));
+    }
+
+    #[tokio::test]
+    async fn serves_stored_events_while_the_server_is_unreachable() {
+        let (mut cache, store) = cache();
+        sync_report(
+            &mut cache,
+            &report("1", &[("a.ics", Some("\"1\""))]),
+            &[resource("a.ics", "\"1\"", "A")],
+        );
+        let mut diff = SyncDiff::default();
+        diff.added.push(url("a.ics"));
+        cache.persist(&diff, true);
+
+        // After a restart, with nothing listening on the port of the server
+        let mut cache = CalendarCache::load(collection(), store);
+        assert!(cache.is_synced());
+        let credentials = CalDavCredentials::new(
+            Url::parse("http://127.0.0.1:1/cal/work/").unwrap(),
+            "alice".to_string(),
+            "secret".to_string(),
+        );
+        let err = cache.sync(&credentials, &chrono_tz::UTC).await.unwrap_err();
+        assert!(is_unreachable(err.as_ref()));
+        assert!(cache.supports_sync_collection);
+        assert_eq!(cache.sync_token.as_deref(), Some("1"));
+        assert!(cache.is_synced());
+        assert_eq!(names(&cache), vec!["A"]);
+    }
+}
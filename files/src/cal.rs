@@ -1,16 +1,9 @@
-use chrono::{DateTime, Utc};
-use core::panic;
 use minidom::Element;
-use reqwest::header::CONTENT_TYPE;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use reqwest::StatusCode;
 use std::error::Error;
-use url;
-
-use crate::event::Event;
-use crate::parser;
-
-fn main() {
-    panic!("This file is not supposed to be executed");
-}
+use std::fmt;
+use url::Url;
 
 #[derive(Clone, Debug)]
 pub struct CalDavCredentials {
@@ -28,6 +21,14 @@ impl CalDavCredentials {
         }
     }
 
+    /// The same credentials, for another resource of the server
+    pub fn with_url(&self, url: url::Url) -> Self {
+        Self {
+            url,
+            ..self.clone()
+        }
+    }
+
     pub fn url(&self) -> &url::Url {
         &self.url
     }
@@ -39,97 +40,262 @@ impl CalDavCredentials {
     }
 }
 
-/// Initializes a Provider, and run an initial sync from the server
-pub async fn get_calendar_events(
-    credentials: CalDavCredentials,
-    start: &DateTime<Utc>,
-    end: &DateTime<Utc>,
-) -> Result<Vec<Event>, String> {
-    let cal_body = format!(
+/// The changes of a collection since a sync token, from an RFC6578 `sync-collection` report
+pub struct SyncChanges {
+    /// The token to ask for the next changes with
+    pub token: Option<String>,
+    /// The resources added or changed, with their new ETag
+    pub changed: Vec<(Url, Option<String>)>,
+    pub removed: Vec<Url>,
+}
+
+/// Lists the resources of a collection changed since `token`, or all of them without a token
+pub async fn sync_collection(
+    credentials: &CalDavCredentials,
+    token: Option<&str>,
+) -> Result<SyncChanges, Box<dyn Error>> {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:sync-collection xmlns:D="DAV:">
+  <D:sync-token>{token}</D:sync-token>
+  <D:sync-level>1</D:sync-level>
+  <D:prop>
+    <D:getetag/>
+  </D:prop>
+</D:sync-collection>
+"#,
+        token = escape_xml(token.unwrap_or_default())
+    );
+
+    let text = sub_request(credentials, "REPORT", body, 0).await?;
+    parse_sync_changes(&text, credentials.url())
+}
+
+/// Reads the changes listed by a `sync-collection` report of `collection`
+pub fn parse_sync_changes(text: &str, collection: &Url) -> Result<SyncChanges, Box<dyn Error>> {
+    let root: Element = text.parse()?;
+    let mut changes = SyncChanges {
+        token: root
+            .children()
+            .find(|child| child.name() == "sync-token")
+            .map(|token| token.text().trim().to_string())
+            .filter(|token| !token.is_empty()),
+        changed: Vec::new(),
+        removed: Vec::new(),
+    };
+    for response in find_elems(&root, "response") {
+        let Some((url, etag)) = response_etag(response, collection) else {
+            continue;
+        };
+        let removed = response
+            .children()
+            .any(|child| child.name() == "status" && child.text().contains(" 404"));
+        if removed {
+            changes.removed.push(url);
+        } else {
+            changes.changed.push((url, etag));
+        }
+    }
+    Ok(changes)
+}
+
+/// The `getctag` of a collection, which changes whenever any of its resources does
+pub async fn get_ctag(credentials: &CalDavCredentials) -> Result<Option<String>, Box<dyn Error>> {
+    let body = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:" xmlns:CS="http://calendarserver.org/ns/">
+  <D:prop>
+    <CS:getctag/>
+  </D:prop>
+</D:propfind>
+"#;
+    let text = sub_request(credentials, "PROPFIND", body.to_string(), 0).await?;
+    let root: Element = text.parse()?;
+    Ok(find_elems(&root, "getctag")
+        .first()
+        .map(|ctag| ctag.text().trim().to_string())
+        .filter(|ctag| !ctag.is_empty()))
+}
+
+/// Lists the resources of a collection with their ETag
+pub async fn get_etags(
+    credentials: &CalDavCredentials,
+) -> Result<Vec<(Url, Option<String>)>, Box<dyn Error>> {
+    let body = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop>
+    <D:getetag/>
+  </D:prop>
+</D:propfind>
+"#;
+    let responses =
+        sub_request_and_extract_elems(credentials, "PROPFIND", body.to_string(), 1, "response")
+            .await?;
+    Ok(responses
+        .iter()
+        .filter_map(|response| response_etag(response, credentials.url()))
+        .collect())
+}
+
+/// Reads several resources of a collection at once
+pub async fn multiget(
+    credentials: &CalDavCredentials,
+    urls: &[Url],
+) -> Result<Vec<Resource>, Box<dyn Error>> {
+    let hrefs: String = urls
+        .iter()
+        .map(|url| format!("  <D:href>{}</D:href>\n", escape_xml(url.path())))
+        .collect();
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <D:getetag/>
+    <C:calendar-data/>
+  </D:prop>
+{hrefs}</C:calendar-multiget>
+"#
+    );
+
+    // The resources are named by their href, so the report only applies to the collection
+    // itself (RFC4791 7.9)
+    let responses =
+        sub_request_and_extract_elems(credentials, "REPORT", body, 0, "response").await?;
+    Ok(extract_calendar_data(&responses, credentials.url()))
+}
+
+/// The resource URL and ETag of a `DAV:response`, leaving out the collection itself
+fn response_etag(response: &Element, collection: &Url) -> Option<(Url, Option<String>)> {
+    let href = response
+        .children()
+        .find(|child| child.name() == "href")?
+        .text();
+    let url = collection.join(href.trim()).ok()?;
+    if url.path().trim_end_matches('/') == collection.path().trim_end_matches('/') {
+        return None;
+    }
+    let etag = find_elems(response, "getetag")
+        .first()
+        .map(|etag| etag.text().trim().to_string())
+        .filter(|etag| !etag.is_empty());
+    Some((url, etag))
+}
+
+/// A calendar object resource, as stored on the server
+#[derive(Clone, Debug)]
+pub struct Resource {
+    pub url: Url,
+    pub etag: Option<String>,
+    /// The raw iCal data
+    pub data: String,
+}
+
+/// Finds the resources of a calendar holding an event whose UID contains `uid`
+pub async fn find_resources_by_uid(
+    credentials: &CalDavCredentials,
+    uid: &str,
+) -> Result<Vec<Resource>, Box<dyn Error>> {
+    let body = format!(
         r#"<?xml version="1.0" encoding="UTF-8" ?>
 <C:calendar-query xmlns:C="urn:ietf:params:xml:ns:caldav">
   <D:prop xmlns:D="DAV:">
     <D:getetag/>
-    <C:calendar-data>
-      <C:comp name="VCALENDAR">
-        <C:comp name="VEVENT"/>
-      </C:comp>
-    </C:calendar-data>
+    <C:calendar-data/>
   </D:prop>
   <C:filter>
     <C:comp-filter name="VCALENDAR">
       <C:comp-filter name="VEVENT">
-        <C:time-range start="{start}" end="{end}"/>
+        <C:prop-filter name="UID">
+          <C:text-match collation="i;octet">{uid}</C:text-match>
+        </C:prop-filter>
       </C:comp-filter>
     </C:comp-filter>
   </C:filter>
 </C:calendar-query>
 "#,
-        start = start.format("%Y%m%dT%H%M%SZ").to_string(),
-        end = end.format("%Y%m%dT%H%M%SZ").to_string()
+        uid = escape_xml(uid)
     );
 
-    log::info!("Requesting items from my calendar");
-    let responses_result =
-        sub_request_and_extract_elems(&credentials, "REPORT", cal_body, "response").await;
-    let responses = match responses_result {
-        Ok(responses) => responses,
-        Err(err) => {
-            log::error!("Error: {}", err);
-            return Ok(Vec::new());
-        }
-    };
-
-    log::debug!("Response: {:?}", responses);
-
-    let calendar_data_vec = extract_calendar_data(&responses);
-    log::debug!("calendar_data_vec: {:?}", calendar_data_vec.len());
+    let responses =
+        sub_request_and_extract_elems(credentials, "REPORT", body, 1, "response").await?;
+    Ok(extract_calendar_data(&responses, credentials.url()))
+}
 
-    let mut events = Vec::new();
+/// Reads a resource
+pub async fn get_resource(credentials: &CalDavCredentials) -> Result<Resource, Box<dyn Error>> {
+    let res = reqwest::Client::new()
+        .get(credentials.url().clone())
+        .basic_auth(credentials.username(), Some(credentials.password()))
+        .send()
+        .await?;
 
-    for calendar_data in calendar_data_vec {
-        log::debug!("calendar_data: {}", calendar_data);
-        let resource_url = credentials.url().clone();
-        match parser::parse(&calendar_data, resource_url) {
-            Ok(parsed) => events.push(parsed),
-            Err(err) => {
-                log::error!("Error: {}", err);
-            }
-        };
+    let status = res.status();
+    if !status.is_success() {
+        return Err(format!("Unexpected HTTP status code {:?}", status).into());
     }
+    let etag = res
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
 
-    log::debug!("events: {:?}", events.len());
-
-    events.sort();
+    Ok(Resource {
+        url: credentials.url().clone(),
+        etag,
+        data: res.text().await?,
+    })
+}
 
-    Ok(events)
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
-// Function to extract the calendar data from the XML element
-fn extract_calendar_data(root: &Vec<Element>) -> Vec<String> {
-    let mut calendar_data_vec = Vec::new();
+/// Extracts the resources from the `DAV:response` elements of a REPORT, resolving their href
+/// against the URL of the request
+fn extract_calendar_data(root: &Vec<Element>, base_url: &Url) -> Vec<Resource> {
+    let mut resources = Vec::new();
 
     for response in root {
         if response.name() == "response" && response.ns() == "DAV:" {
-            for propstat in response.children() {
-                if propstat.name() == "propstat" && propstat.ns() == "DAV:" {
-                    for prop in propstat.children() {
+            let mut href = None;
+            let mut etag = None;
+            let mut data = None;
+            for child in response.children() {
+                if child.name() == "href" && child.ns() == "DAV:" {
+                    href = Some(child.text().trim().to_string());
+                }
+                if child.name() == "propstat" && child.ns() == "DAV:" {
+                    for prop in child.children() {
                         if prop.name() == "prop" && prop.ns() == "DAV:" {
-                            for calendar_data in prop.children() {
-                                if calendar_data.name() == "calendar-data"
-                                    && calendar_data.ns() == "urn:ietf:params:xml:ns:caldav"
+                            for value in prop.children() {
+                                if value.name() == "getetag" && value.ns() == "DAV:" {
+                                    etag = Some(value.text().trim().to_string())
+                                        .filter(|etag| !etag.is_empty());
+                                }
+                                if value.name() == "calendar-data"
+                                    && value.ns() == "urn:ietf:params:xml:ns:caldav"
                                 {
-                                    calendar_data_vec.push(calendar_data.text());
+                                    data = Some(value.text());
                                 }
                             }
                         }
                     }
                 }
             }
+
+            let (Some(href), Some(data)) = (href, data) else {
+                continue;
+            };
+            match base_url.join(&href) {
+                Ok(url) => resources.push(Resource { url, etag, data }),
+                Err(err) => log::warn!("Invalid href {}: {}", href, err),
+            }
         }
     }
 
-    calendar_data_vec
+    resources
 }
 
 pub async fn sub_request(
@@ -155,7 +321,7 @@ pub async fn sub_request(
     log::debug!("Response status: {:?}", status);
     log::debug!("Response body: {}", text);
 
-    if status.is_success() == false {
+    if !status.is_success() {
         return Err(format!("Unexpected HTTP status code {:?}", status).into());
     }
 
@@ -164,6 +330,72 @@ pub async fn sub_request(
     Ok(text)
 }
 
+/// The condition a resource must meet for a write to apply, so that concurrent changes are not
+/// overwritten
+pub enum Precondition {
+    /// The resource must not exist yet (`If-None-Match: *`)
+    Absent,
+    /// The resource must still have the given ETag (`If-Match`)
+    Matches(String),
+}
+
+/// The server refused a write because its precondition failed (HTTP 412)
+#[derive(Debug)]
+pub struct PreconditionFailed;
+
+impl fmt::Display for PreconditionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The resource was changed on the server")
+    }
+}
+
+impl Error for PreconditionFailed {}
+
+/// Writes iCal data to a resource (or deletes it, without data), and returns its new ETag if the
+/// server sent one
+pub async fn write_request(
+    resource: &CalDavCredentials,
+    method: &str,
+    body: Option<String>,
+    precondition: Precondition,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let method = method.parse().expect("invalid method name");
+
+    let mut request = reqwest::Client::new()
+        .request(method, resource.url().clone())
+        .basic_auth(resource.username(), Some(resource.password()));
+    if let Some(body) = body {
+        request = request
+            .header(CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(body);
+    }
+    let request = match precondition {
+        Precondition::Absent => request.header(IF_NONE_MATCH, "*"),
+        Precondition::Matches(etag) => request.header(IF_MATCH, etag),
+    };
+    let res = request.send().await?;
+
+    let status = res.status();
+    let etag = res
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let text = res.text().await?;
+
+    log::debug!("Response status: {:?}", status);
+    log::debug!("Response body: {}", text);
+
+    if status == StatusCode::PRECONDITION_FAILED {
+        return Err(PreconditionFailed.into());
+    }
+    if !status.is_success() {
+        return Err(format!("Unexpected HTTP status code {:?}", status).into());
+    }
+
+    Ok(etag)
+}
+
 /// Walks an XML tree and returns every element that has the given name
 pub fn find_elems<S: AsRef<str>>(root: &Element, searched_name: S) -> Vec<&Element> {
     let searched_name = searched_name.as_ref();
@@ -184,14 +416,15 @@ pub async fn sub_request_and_extract_elems(
     resource: &CalDavCredentials,
     method: &str,
     body: String,
+    depth: u32,
     item: &str,
 ) -> Result<Vec<Element>, Box<dyn Error>> {
-    let text = sub_request(resource, method, body, 1).await?;
+    let text = sub_request(resource, method, body, depth).await?;
 
     let element: &Element = &text.parse()?;
     // log::debug!("sub request for {}", resource.url());
     // log::debug!("Response: {:?}", text);
-    Ok(find_elems(&element, item)
+    Ok(find_elems(element, item)
         .iter()
         .map(|elem| (*elem).clone())
         .collect())
@@ -0,0 +1,539 @@
+//! Aggregation of several calendar collections, possibly on different servers, into one agenda
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+use url::Url;
+
+use crate::cache::CalendarCache;
+use crate::cal::{
+    find_resources_by_uid, get_resource, write_request, CalDavCredentials, Precondition, Resource,
+};
+use crate::discovery;
+use crate::event::{CalendarLabel, Event, Series};
+use crate::parser;
+use crate::serializer;
+use crate::store::EventStore;
+
+/// A configured calendar source: a collection URL, or a server root or user address from which
+/// collections are discovered
+#[derive(Clone, Debug)]
+pub struct CalendarSource {
+    /// Short name used in the configuration, and as a fallback label
+    pub key: String,
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    /// Display name of the collection to pick among the discovered ones. All of them are used
+    /// when unset.
+    pub calendar_name: Option<String>,
+    pub label: Option<String>,
+    pub color: Option<String>,
+}
+
+/// A calendar collection to read events from
+#[derive(Clone, Debug)]
+pub struct Calendar {
+    credentials: CalDavCredentials,
+    label: CalendarLabel,
+    cache: Arc<Mutex<CalendarCache>>,
+}
+
+impl Calendar {
+    fn new(credentials: CalDavCredentials, label: CalendarLabel, store: &Arc<EventStore>) -> Self {
+        let cache = CalendarCache::load(credentials.url().clone(), store.clone());
+        Self {
+            credentials,
+            label,
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    pub fn label(&self) -> &CalendarLabel {
+        &self.label
+    }
+
+    pub fn url(&self) -> &Url {
+        self.credentials.url()
+    }
+
+    /// The events of this calendar in the `start..end` window, synced with the server first.
+    /// The events of the last sync are used when the server cannot be reached.
+    pub async fn events(
+        &self,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        default_timezone: &Tz,
+    ) -> Result<Vec<Event>, Box<dyn Error>> {
+        let mut cache = self.cache.lock().await;
+        match cache.sync(&self.credentials, default_timezone).await {
+            Ok(diff) if !diff.is_empty() => log::info!(
+                "Synced {}: {} added, {} changed, {} removed",
+                self.url(),
+                diff.added.len(),
+                diff.changed.len(),
+                diff.removed.len()
+            ),
+            Ok(_) => {}
+            Err(err) => {
+                if !cache.is_synced() {
+                    return Err(err);
+                }
+                log::warn!(
+                    "Unable to sync {}, using cached events: {}",
+                    self.url(),
+                    err
+                );
+            }
+        }
+        Ok(cache.events(start, end))
+    }
+
+    /// A new UID, and the URL of the resource to create for it in this calendar
+    pub fn new_resource(&self) -> Result<(String, Url), Box<dyn Error>> {
+        let uid: String = thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        let url = collection_url(self.url()).join(&format!("{}.ics", uid))?;
+        Ok((uid, url))
+    }
+
+    /// Creates an event at its URL in this calendar. Fails with
+    /// [`PreconditionFailed`](crate::cal::PreconditionFailed) if a resource already exists there.
+    pub async fn create_event(&self, event: &Event) -> Result<(), Box<dyn Error>> {
+        write_request(
+            &self.credentials.with_url(event.url().clone()),
+            "PUT",
+            Some(serializer::serialize(event)),
+            Precondition::Absent,
+        )
+        .await?;
+        self.cache.lock().await.invalidate();
+        Ok(())
+    }
+
+    /// Finds the resources of this calendar whose event UID starts with `prefix`, along with
+    /// their parsed events
+    pub async fn find_resources(
+        &self,
+        prefix: &str,
+        default_timezone: &Tz,
+    ) -> Result<Vec<(Resource, Series)>, Box<dyn Error>> {
+        let mut found = Vec::new();
+        for resource in find_resources_by_uid(&self.credentials, prefix).await? {
+            match parser::parse(&resource.data, resource.url.clone(), default_timezone) {
+                Ok(series) if series.uid().is_some_and(|uid| uid.starts_with(prefix)) => {
+                    found.push((resource, series))
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("Skipping {}: {}", resource.url, err),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Reads the resource at `url` in this calendar, along with its parsed events.
+    ///
+    /// When the ETag the resource was seen with is given, it is kept instead of the current one,
+    /// so that changes made since then are detected when writing.
+    pub async fn get_resource(
+        &self,
+        url: &Url,
+        seen_etag: Option<String>,
+        default_timezone: &Tz,
+    ) -> Result<(Resource, Series), Box<dyn Error>> {
+        let mut resource = get_resource(&self.credentials.with_url(url.clone())).await?;
+        if seen_etag.is_some() {
+            resource.etag = seen_etag;
+        }
+        let series = parser::parse(&resource.data, resource.url.clone(), default_timezone)?;
+        Ok((resource, series))
+    }
+
+    /// Whether a resource URL belongs to this calendar
+    pub fn contains(&self, url: &Url) -> bool {
+        is_in_collection(url, self.url())
+    }
+
+    /// Replaces the data of a resource, provided it was not changed since it was read
+    pub async fn update_resource(
+        &self,
+        resource: &Resource,
+        data: String,
+    ) -> Result<(), Box<dyn Error>> {
+        write_request(
+            &self.credentials.with_url(resource.url.clone()),
+            "PUT",
+            Some(data),
+            precondition(resource)?,
+        )
+        .await?;
+        self.cache.lock().await.invalidate();
+        Ok(())
+    }
+
+    /// Deletes a resource, provided it was not changed since it was read
+    pub async fn delete_resource(&self, resource: &Resource) -> Result<(), Box<dyn Error>> {
+        write_request(
+            &self.credentials.with_url(resource.url.clone()),
+            "DELETE",
+            None,
+            precondition(resource)?,
+        )
+        .await?;
+        self.cache.lock().await.invalidate();
+        Ok(())
+    }
+}
+
+/// The URL of a collection with a trailing slash, so that resources can be resolved against it
+fn collection_url(url: &Url) -> Url {
+    let mut collection = url.clone();
+    if !collection.path().ends_with('/') {
+        collection.set_path(&format!("{}/", collection.path()));
+    }
+    collection
+}
+
+/// Whether `url` is a resource inside the collection at `collection`, and not a sibling sharing
+/// its path as a prefix
+fn is_in_collection(url: &Url, collection: &Url) -> bool {
+    let collection = collection_url(collection);
+    url.as_str().starts_with(collection.as_str()) && url.as_str() != collection.as_str()
+}
+
+/// Changes are only made to the version of a resource that was read, so that concurrent changes
+/// are not overwritten
+fn precondition(resource: &Resource) -> Result<Precondition, String> {
+    resource
+        .etag
+        .clone()
+        .map(Precondition::Matches)
+        .ok_or_else(|| format!("No ETag for {}, it cannot be changed safely", resource.url))
+}
+
+impl CalendarSource {
+    /// Resolves this source into the calendar collections it designates.
+    ///
+    /// The collections are recorded in the store, and the recorded ones are used when the server
+    /// cannot be reached, so that their stored events can still be shown.
+    pub async fn resolve(&self, store: &Arc<EventStore>) -> Result<Vec<Calendar>, Box<dyn Error>> {
+        let credentials = CalDavCredentials::new(
+            discovery::resolve_server(&self.server).await?,
+            self.username.clone(),
+            self.password.clone(),
+        );
+
+        let collections = match self.discover(&credentials).await {
+            Ok(collections) => {
+                if let Err(err) = store.save_calendars(&self.key, &collections) {
+                    log::warn!("Unable to store the calendars of {}: {}", self.key, err);
+                }
+                collections
+            }
+            Err(err) => match store.calendars(&self.key) {
+                Ok(stored) if !stored.is_empty() => {
+                    log::warn!(
+                        "Unable to find the calendars of {}, using the stored ones: {}",
+                        self.key,
+                        err
+                    );
+                    stored
+                }
+                _ => return Err(err),
+            },
+        };
+
+        Ok(collections
+            .into_iter()
+            .map(|(url, label)| Calendar::new(credentials.with_url(url), label, store))
+            .collect())
+    }
+
+    /// Finds the URL and label of the calendar collections this source designates
+    async fn discover(
+        &self,
+        credentials: &CalDavCredentials,
+    ) -> Result<Vec<(Url, CalendarLabel)>, Box<dyn Error>> {
+        if discovery::is_calendar_collection(credentials).await {
+            return Ok(vec![(credentials.url().clone(), self.label(None, None))]);
+        }
+
+        let discovered = discovery::discover_calendars(credentials).await?;
+        for calendar in &discovered {
+            log::info!(
+                "Found calendar {} ({}) at {}",
+                calendar.display_name().unwrap_or("<unnamed>"),
+                calendar.color().unwrap_or("no color"),
+                calendar.url()
+            );
+        }
+
+        let calendars: Vec<(Url, CalendarLabel)> = discovered
+            .iter()
+            .filter(|calendar| {
+                self.calendar_name
+                    .as_deref()
+                    .is_none_or(|name| calendar.display_name() == Some(name))
+            })
+            .map(|calendar| {
+                (
+                    calendar.url().clone(),
+                    self.label(calendar.display_name(), calendar.color()),
+                )
+            })
+            .collect();
+
+        if calendars.is_empty() {
+            return Err(format!("No matching calendar found on {}", self.server).into());
+        }
+        Ok(calendars)
+    }
+
+    /// The label of a calendar of this source. The configured label only applies to a source that
+    /// designates a single calendar, as discovered calendars are told apart by their names.
+    fn label(&self, display_name: Option<&str>, color: Option<&str>) -> CalendarLabel {
+        let name = match (&self.label, &self.calendar_name, display_name) {
+            (Some(label), Some(_), _) | (Some(label), _, None) => label.clone(),
+            (_, _, Some(display_name)) => display_name.to_string(),
+            (None, _, None) => self.key.clone(),
+        };
+        CalendarLabel::new(name, self.color.as_deref().or(color))
+    }
+}
+
+/// The calendar collections of the configured sources, each resolved on first use. A source that
+/// cannot be resolved is tried again the next time its calendars are needed, without holding back
+/// the others.
+pub struct SourceCalendars {
+    sources: Vec<(CalendarSource, OnceCell<Vec<Calendar>>)>,
+    store: Arc<EventStore>,
+}
+
+impl SourceCalendars {
+    pub fn new(sources: &[CalendarSource], store: Arc<EventStore>) -> Self {
+        Self {
+            sources: sources
+                .iter()
+                .map(|source| (source.clone(), OnceCell::new()))
+                .collect(),
+            store,
+        }
+    }
+
+    /// The calendars of the sources whose key is accepted by `shows`, along with the errors of
+    /// the sources that cannot be resolved
+    pub async fn get(&self, shows: impl Fn(&str) -> bool) -> Calendars<'_> {
+        let mut calendars = Calendars::default();
+        for (source, cell) in &self.sources {
+            if !shows(&source.key) {
+                continue;
+            }
+            match cell.get_or_try_init(|| source.resolve(&self.store)).await {
+                Ok(resolved) => calendars.calendars.extend(resolved),
+                Err(err) => calendars
+                    .errors
+                    .push(format!("Error finding calendar {}: {}", source.key, err)),
+            }
+        }
+        calendars
+    }
+}
+
+/// The calendars of some sources
+#[derive(Debug, Default)]
+pub struct Calendars<'a> {
+    pub calendars: Vec<&'a Calendar>,
+    /// Why the sources left out of the calendars could not be resolved
+    pub errors: Vec<String>,
+}
+
+impl<'a> Calendars<'a> {
+    /// Whether no calendar could be resolved because of errors
+    pub fn failed(&self) -> bool {
+        self.calendars.is_empty() && !self.errors.is_empty()
+    }
+
+    /// Logs why the sources left out could not be resolved
+    pub fn log_errors(&self) {
+        for err in &self.errors {
+            log::error!("{}", err);
+        }
+    }
+
+    /// The calendars that could be resolved, logging why the others could not
+    pub fn available(self) -> Vec<&'a Calendar> {
+        self.log_errors();
+        self.calendars
+    }
+}
+
+/// The events of several calendars, merged in chronological order
+#[derive(Debug, Default)]
+pub struct Agenda {
+    pub events: Vec<Event>,
+    /// Why the calendars or sources left out of the events could not be read
+    pub errors: Vec<String>,
+}
+
+impl Agenda {
+    /// The events of the calendars that could be read, logging why the others could not
+    pub fn available_events(self) -> Vec<Event> {
+        for err in &self.errors {
+            log::error!("{}", err);
+        }
+        self.events
+    }
+
+    /// The events, or an error when any calendar could not be read, so that its events are not
+    /// mistaken for removed ones
+    pub fn complete_events(self) -> Result<Vec<Event>, String> {
+        if self.errors.is_empty() {
+            Ok(self.events)
+        } else {
+            Err(self.errors.join(", "))
+        }
+    }
+}
+
+/// Fetches the events of every calendar in the `start..end` window, tagged with the label of
+/// their calendar and merged in chronological order, along with the errors of the calendars and
+/// sources that cannot be read.
+pub async fn get_agenda(
+    calendars: Calendars<'_>,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+    default_timezone: &Tz,
+) -> Agenda {
+    let mut agenda = Agenda {
+        events: Vec::new(),
+        errors: calendars.errors,
+    };
+
+    for calendar in calendars.calendars {
+        match calendar.events(start, end, default_timezone).await {
+            Ok(events) => agenda.events.extend(events.into_iter().map(|mut event| {
+                event.set_calendar(calendar.label.clone());
+                event
+            })),
+            Err(err) => agenda.errors.push(format!(
+                "Error reading calendar {}: {}",
+                calendar.label.name(),
+                err
+            )),
+        }
+    }
+
+    agenda.events.sort();
+    agenda
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// A source on a server with nothing listening on its port
+    fn source(key: &str) -> CalendarSource {
+        CalendarSource {
+            key: key.to_string(),
+            server: format!("http://127.0.0.1:1/cal/{}/", key),
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+            calendar_name: None,
+            label: None,
+            color: None,
+        }
+    }
+
+    fn store_calendar(store: &EventStore, key: &str) {
+        store
+            .save_calendars(
+                key,
+                &[(
+                    Url::parse(&format!("http://127.0.0.1:1/cal/{}/", key)).unwrap(),
+                    CalendarLabel::new(key.to_string(), None),
+                )],
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_the_sources_that_resolve_and_retries_the_others() {
+        let store = Arc::new(EventStore::open(Path::new(":memory:")).unwrap());
+        // Only the calendars of the work source were found before
+        store_calendar(&store, "work");
+        let sources = SourceCalendars::new(&[source("work"), source("home")], store.clone());
+
+        let calendars = sources.get(|_| true).await;
+        assert!(!calendars.failed());
+        let names: Vec<&str> = calendars
+            .calendars
+            .iter()
+            .map(|c| c.label().name())
+            .collect();
+        assert_eq!(names, vec!["work"]);
+        assert_eq!(calendars.errors.len(), 1);
+        assert!(calendars.errors[0].contains("home"));
+
+        let agenda = get_agenda(calendars, &Utc::now(), &Utc::now(), &chrono_tz::UTC).await;
+        assert!(agenda
+            .errors
+            .iter()
+            .any(|err| err.starts_with("Error finding calendar home")));
+        assert!(agenda.complete_events().is_err());
+
+        // A room not showing the failing source is not affected by it
+        let calendars = sources.get(|key| key == "work").await;
+        assert_eq!(calendars.calendars.len(), 1);
+        assert!(calendars.errors.is_empty());
+
+        // The failing source is resolved once it can be
+        store_calendar(&store, "home");
+        let calendars = sources.get(|_| true).await;
+        let names: Vec<&str> = calendars
+            .calendars
+            .iter()
+            .map(|c| c.label().name())
+            .collect();
+        assert_eq!(names, vec!["work", "home"]);
+        assert!(calendars.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_source_resolves() {
+        let store = Arc::new(EventStore::open(Path::new(":memory:")).unwrap());
+        let sources = SourceCalendars::new(&[source("home")], store);
+        let calendars = sources.get(|_| true).await;
+        assert!(calendars.failed());
+        assert!(calendars.available().is_empty());
+    }
+
+    #[test]
+    fn resources_are_matched_inside_the_collection_only() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        for collection in [
+            "https://dav.example/cal/work",
+            "https://dav.example/cal/work/",
+        ] {
+            let collection = url(collection);
+            assert!(is_in_collection(
+                &url("https://dav.example/cal/work/a.ics"),
+                &collection
+            ));
+            assert!(!is_in_collection(
+                &url("https://dav.example/cal/work-archive/a.ics"),
+                &collection
+            ));
+            assert!(!is_in_collection(
+                &url("https://dav.example/cal/work/"),
+                &collection
+            ));
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! Changes made to the events of a room between two snapshots of its agenda, and the notices
+//! posted to the room about them
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId},
+    Client,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use tokio::time::Instant;
+
+use crate::calendars::get_agenda;
+use crate::config::RoomConfig;
+use crate::event::Event;
+use crate::locale::Locale;
+use crate::render::Message;
+use crate::{format_calendar_label, room_calendars, State};
+
+/// The occurrence of an event: its UID, and its original start if it belongs to a series
+type OccurrenceKey = (String, Option<NaiveDateTime>);
+
+/// The events of an agenda window at some point in time
+#[derive(Debug)]
+pub struct Snapshot {
+    taken_at: DateTime<Utc>,
+    /// The end of the window
+    end: DateTime<Utc>,
+    events: HashMap<OccurrenceKey, Event>,
+    /// The events starting after the window, to tell events moved out of it from cancelled ones
+    later: HashMap<OccurrenceKey, Event>,
+}
+
+#[derive(Debug)]
+pub enum Change {
+    Added(Event),
+    Moved {
+        previous: Event,
+        event: Event,
+    },
+    /// The title, location or status of the event changed
+    Updated {
+        previous: Event,
+        event: Event,
+    },
+    Cancelled(Event),
+}
+
+impl Change {
+    /// The event as it is now, or as it was for a cancelled one
+    pub fn event(&self) -> &Event {
+        match self {
+            Change::Added(event)
+            | Change::Moved { event, .. }
+            | Change::Updated { event, .. }
+            | Change::Cancelled(event) => event,
+        }
+    }
+}
+
+impl Snapshot {
+    /// A snapshot of the `events` of the window from `taken_at` to `end`. The events starting
+    /// after `end` are only kept to find the events moved out of the window.
+    pub fn new(events: Vec<Event>, taken_at: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        let (events, later): (HashMap<_, _>, HashMap<_, _>) = events
+            .into_iter()
+            .map(|event| {
+                let key = (
+                    event.uid().to_string(),
+                    event.recurrence_id().map(|time| time.naive_utc()),
+                );
+                (key, event)
+            })
+            .partition(|(_, event)| event.dtstart().naive_utc() < end.naive_utc());
+        Self {
+            taken_at,
+            end,
+            events,
+            later,
+        }
+    }
+
+    /// The changes made to the events since the `previous` snapshot, in chronological order.
+    ///
+    /// Events that only entered the window because time passed, or left it because they are
+    /// over, are not changes. An event that disappeared is cancelled unless it starts after the
+    /// window now, and when the occurrences of a series are all moved, those that disappeared are
+    /// paired with those that appeared.
+    pub fn changes_since(&self, previous: &Snapshot) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let mut added: HashMap<&str, Vec<&Event>> = HashMap::new();
+        let mut removed: HashMap<&str, Vec<&Event>> = HashMap::new();
+
+        for (key, event) in &self.events {
+            match previous.events.get(key) {
+                Some(before) => {
+                    if event.dtstart() != before.dtstart() || event.dtend() != before.dtend() {
+                        changes.push(Change::Moved {
+                            previous: before.clone(),
+                            event: event.clone(),
+                        });
+                    } else if event.name() != before.name()
+                        || event.location() != before.location()
+                        || event.status() != before.status()
+                    {
+                        changes.push(Change::Updated {
+                            previous: before.clone(),
+                            event: event.clone(),
+                        });
+                    }
+                }
+                None => match previous.later.get(key) {
+                    // Moved into the window from after it
+                    Some(before)
+                        if event.dtstart() != before.dtstart()
+                            || event.dtend() != before.dtend() =>
+                    {
+                        changes.push(Change::Moved {
+                            previous: before.clone(),
+                            event: event.clone(),
+                        })
+                    }
+                    Some(_) => {}
+                    None if event.dtstart().naive_utc() < previous.end.naive_utc() => {
+                        added.entry(event.uid()).or_default().push(event)
+                    }
+                    None => {}
+                },
+            }
+        }
+        for (key, before) in &previous.events {
+            if self.events.contains_key(key)
+                || before.dtend().naive_utc() <= self.taken_at.naive_utc()
+            {
+                continue;
+            }
+            match self.later.get(key) {
+                Some(event) => changes.push(Change::Moved {
+                    previous: before.clone(),
+                    event: event.clone(),
+                }),
+                None => removed.entry(before.uid()).or_default().push(before),
+            }
+        }
+
+        for (uid, mut events) in added {
+            let mut removed = removed.remove(uid).unwrap_or_default();
+            events.sort();
+            removed.sort();
+            let mut removed = removed.into_iter();
+            for event in events {
+                changes.push(match removed.next() {
+                    Some(before) => Change::Moved {
+                        previous: before.clone(),
+                        event: event.clone(),
+                    },
+                    None => Change::Added(event.clone()),
+                });
+            }
+            changes.extend(removed.map(|before| Change::Cancelled(before.clone())));
+        }
+        changes.extend(
+            removed
+                .into_values()
+                .flatten()
+                .map(|before| Change::Cancelled(before.clone())),
+        );
+
+        changes.sort_by(|a, b| a.event().cmp(b.event()));
+        changes
+    }
+}
+
+/// How often the calendars of a room are checked for changes
+const CHANGE_POLL_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// How long the events must stay unchanged before their changes are posted, so that a burst of
+/// edits is posted as one message
+const CHANGE_DEBOUNCE: StdDuration = StdDuration::from_secs(3 * 60);
+
+/// How far ahead changes to events are posted
+const CHANGE_HORIZON_DAYS: i64 = 30;
+
+/// How far ahead events are looked for, so that those moved past the horizon are not mistaken
+/// for cancelled ones
+const CHANGE_LOOKAHEAD_DAYS: i64 = 366;
+
+/// Posts the changes made to the upcoming events of a room, once they settle
+pub async fn post_change_notices(client: Arc<Client>, state: Arc<State>, room_id: OwnedRoomId) {
+    let Some(room_config) = state.config.room(&room_id) else {
+        log::error!("No configuration for room {}", room_id);
+        return;
+    };
+
+    let mut interval = tokio::time::interval(CHANGE_POLL_INTERVAL);
+    // The events the room was last notified of
+    let mut notified: Option<Snapshot> = None;
+    // The latest events when they differ from those, and when they last changed
+    let mut pending: Option<(Snapshot, Instant)> = None;
+
+    loop {
+        interval.tick().await;
+
+        let snapshot = match take_snapshot(&state, room_config).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::warn!(
+                    "Unable to check the events of {} for changes: {}",
+                    room_id,
+                    err
+                );
+                continue;
+            }
+        };
+        let Some(previous) = notified.take() else {
+            notified = Some(snapshot);
+            continue;
+        };
+
+        let latest = pending.as_ref().map_or(&previous, |(latest, _)| latest);
+        let changed_at = if snapshot.changes_since(latest).is_empty() {
+            pending.take().map(|(_, changed_at)| changed_at)
+        } else {
+            Some(Instant::now())
+        };
+        let Some(changed_at) = changed_at else {
+            // Nothing changed, the window just moved forward
+            notified = Some(snapshot);
+            continue;
+        };
+        if changed_at.elapsed() < CHANGE_DEBOUNCE {
+            notified = Some(previous);
+            pending = Some((snapshot, changed_at));
+            continue;
+        }
+
+        let changes = snapshot.changes_since(&previous);
+        if !changes.is_empty() {
+            if let Some(room) = client.get_room(&room_id) {
+                let (body, html_body) =
+                    format_change_notices(&changes, &room_config.locale, &room_config.timezone);
+                match room
+                    .send(RoomMessageEventContent::text_html(body, html_body))
+                    .await
+                {
+                    Ok(_) => log::info!("Change notices sent to {}", room_id),
+                    Err(error) => log::error!("Error sending change notices: {error}"),
+                }
+            } else {
+                log::error!("Failed to find room with ID {}", room_id);
+            }
+        }
+        notified = Some(snapshot);
+        pending = None;
+    }
+}
+
+/// The upcoming events of a room, to compare with later ones
+async fn take_snapshot(state: &State, room: &RoomConfig) -> Result<Snapshot, String> {
+    let calendars = room_calendars(state, room).await;
+    let start = Utc::now();
+    let lookahead = start + Duration::days(CHANGE_LOOKAHEAD_DAYS);
+    let events = get_agenda(
+        calendars,
+        &start,
+        &lookahead,
+        &state.config.default_timezone,
+    )
+    .await
+    .complete_events()?;
+    Ok(Snapshot::new(
+        events,
+        start,
+        start + Duration::days(CHANGE_HORIZON_DAYS),
+    ))
+}
+
+/// Describes changes to the events, one per line
+fn format_change_notices(changes: &[Change], locale: &Locale, timezone: &Tz) -> (String, String) {
+    let mut message = Message::new();
+    message.paragraph(|message| {
+        for (index, change) in changes.iter().enumerate() {
+            let event = change.event();
+            let times = locale.format_event_times(event.dtstart(), event.dtend(), timezone);
+
+            let (notice, detail) = match change {
+                Change::Added(_) => ("new_event", times),
+                Change::Moved { previous, .. } => (
+                    "moved",
+                    locale.message(
+                        "moved_times",
+                        &[
+                            ("times", &times),
+                            (
+                                "previous",
+                                &locale.format_event_times(
+                                    previous.dtstart(),
+                                    previous.dtend(),
+                                    timezone,
+                                ),
+                            ),
+                        ],
+                    ),
+                ),
+                Change::Cancelled(_) => ("cancelled", times),
+                Change::Updated { previous, event } => {
+                    let mut details = Vec::new();
+                    if event.name() != previous.name() {
+                        details.push(locale.message("renamed", &[("name", &previous.name())]));
+                    }
+                    if event.location() != previous.location() {
+                        details.push(match event.location() {
+                            Some(location) => {
+                                locale.message("relocated", &[("location", location)])
+                            }
+                            None => locale.message("location_removed", &[]),
+                        });
+                    }
+                    if event.status() != previous.status() {
+                        if let Some(status) = event.status() {
+                            let status = locale.message(
+                                &format!("status_{}", status.to_ical().to_lowercase()),
+                                &[],
+                            );
+                            details.push(locale.message("status_changed", &[("status", &status)]));
+                        }
+                    }
+                    ("updated", format!("{} ({})", times, details.join(", ")))
+                }
+            };
+
+            if index > 0 {
+                message.line_break();
+            }
+            message.text(&format!("{}: ", locale.message(notice, &[])));
+            format_calendar_label(message, event);
+            message.strong(event.name()).text(&format!(", {}", detail));
+        }
+    });
+    message.into_parts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventTime;
+    use chrono::TimeZone;
+    use url::Url;
+
+    /// When the first snapshot is taken
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 4, 12, 0, 0).unwrap()
+    }
+
+    fn event(uid: &str, name: &str, start: DateTime<Utc>) -> Event {
+        Event::new_timed(
+            name.to_string(),
+            uid.to_string(),
+            start,
+            start + Duration::hours(1),
+            None,
+            None,
+            Url::parse(&format!("https://example.org/cal/{}.ics", uid)).unwrap(),
+            now(),
+            None,
+        )
+    }
+
+    fn occurrence(uid: &str, start: DateTime<Utc>, recurrence_id: DateTime<Utc>) -> Event {
+        let mut event = event(uid, "Weekly meeting", start);
+        event.set_recurrence_id(EventTime::DateTime(recurrence_id));
+        event
+    }
+
+    /// A snapshot taken `days` after the first one
+    fn snapshot(days: i64, events: Vec<Event>) -> Snapshot {
+        let taken_at = now() + Duration::days(days);
+        Snapshot::new(
+            events,
+            taken_at,
+            taken_at + Duration::days(CHANGE_HORIZON_DAYS),
+        )
+    }
+
+    /// The changes, described by their kind and the start of the event
+    fn describe(changes: &[Change]) -> Vec<(&'static str, DateTime<Utc>)> {
+        changes
+            .iter()
+            .map(|change| {
+                let kind = match change {
+                    Change::Added(_) => "added",
+                    Change::Moved { .. } => "moved",
+                    Change::Updated { .. } => "updated",
+                    Change::Cancelled(_) => "cancelled",
+                };
+                let EventTime::DateTime(start) = change.event().dtstart() else {
+                    panic!("Unexpected all-day event");
+                };
+                (kind, *start)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_added_moved_updated_and_cancelled_events() {
+        let day = |days| now() + Duration::days(days);
+        let previous = snapshot(
+            0,
+            vec![
+                event("moved", "Meeting", day(1)),
+                event("renamed", "Meeting", day(2)),
+                event("cancelled", "Meeting", day(3)),
+                event("unchanged", "Meeting", day(4)),
+            ],
+        );
+        let current = snapshot(
+            0,
+            vec![
+                event("moved", "Meeting", day(5)),
+                event("renamed", "Board meeting", day(2)),
+                event("unchanged", "Meeting", day(4)),
+                event("added", "Meeting", day(6)),
+            ],
+        );
+
+        let changes = current.changes_since(&previous);
+        assert_eq!(
+            describe(&changes),
+            vec![
+                ("updated", day(2)),
+                ("cancelled", day(3)),
+                ("moved", day(5)),
+                ("added", day(6)),
+            ]
+        );
+        let Change::Moved { previous, .. } = &changes[2] else {
+            panic!("Expected a moved event");
+        };
+        assert_eq!(previous.dtstart(), &EventTime::DateTime(day(1)));
+        assert!(current.changes_since(&current).is_empty());
+    }
+
+    #[test]
+    fn pairs_the_occurrences_of_a_series_moved_as_a_group() {
+        let week = |weeks| now() + Duration::weeks(weeks);
+        let hour_later = |weeks| week(weeks) + Duration::hours(1);
+        let previous = snapshot(
+            0,
+            (1..=3)
+                .map(|weeks| occurrence("weekly", week(weeks), week(weeks)))
+                .collect(),
+        );
+        // Moving the whole series moves the original start of its occurrences too
+        let current = snapshot(
+            0,
+            (1..=3)
+                .map(|weeks| occurrence("weekly", hour_later(weeks), hour_later(weeks)))
+                .collect(),
+        );
+
+        let changes = current.changes_since(&previous);
+        assert_eq!(
+            describe(&changes),
+            vec![
+                ("moved", hour_later(1)),
+                ("moved", hour_later(2)),
+                ("moved", hour_later(3)),
+            ]
+        );
+        for (change, weeks) in changes.iter().zip(1..) {
+            let Change::Moved { previous, .. } = change else {
+                panic!("Expected a moved event");
+            };
+            assert_eq!(previous.dtstart(), &EventTime::DateTime(week(weeks)));
+        }
+    }
+
+    #[test]
+    fn ignores_events_entering_or_leaving_the_window_as_time_passes() {
+        let ending = event("ending", "Meeting", now() - Duration::minutes(30));
+        let entering = event(
+            "entering",
+            "Meeting",
+            now() + Duration::days(CHANGE_HORIZON_DAYS) + Duration::hours(12),
+        );
+        let previous = snapshot(0, vec![ending, entering.clone()]);
+        let current = snapshot(1, vec![entering.clone()]);
+        assert!(current.changes_since(&previous).is_empty());
+
+        // Without looking past the window, as when the previous snapshot did not reach it
+        let previous = snapshot(0, Vec::new());
+        assert!(current.changes_since(&previous).is_empty());
+    }
+
+    #[test]
+    fn finds_events_moved_past_the_horizon() {
+        let soon = now() + Duration::days(7);
+        let later = now() + Duration::days(60);
+        let previous = snapshot(0, vec![event("postponed", "Meeting", soon)]);
+        let current = snapshot(0, vec![event("postponed", "Meeting", later)]);
+
+        assert_eq!(
+            describe(&current.changes_since(&previous)),
+            vec![("moved", later)]
+        );
+        assert_eq!(
+            describe(&previous.changes_since(&current)),
+            vec![("moved", soon)]
+        );
+        assert_eq!(
+            describe(&snapshot(0, Vec::new()).changes_since(&previous)),
+            vec![("cancelled", soon)]
+        );
+    }
+}
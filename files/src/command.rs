@@ -0,0 +1,816 @@
+//! Parsing of the `!cal` commands sent in the rooms
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use url::Url;
+
+use crate::event::{Event, EventTime};
+use crate::locale::{Locale, Phrase};
+use crate::timezone::EventTimeZone;
+
+/// Longest period that can be queried with a date range
+const MAX_RANGE_DAYS: i64 = 366;
+
+/// Last year of the dates of events, which iCal writes with four digits
+const MAX_YEAR: i32 = 9999;
+
+/// Length of an added event that has a start time but no duration
+const DEFAULT_EVENT_HOURS: i64 = 1;
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Lists the events of a period
+    Agenda(Period),
+    /// Shows the next event to start
+    Next,
+    /// Adds an event to the calendar of the room
+    Add(NewEvent),
+    Edit(EventRef, EventChanges),
+    Delete(EventRef),
+    /// Shows the time zone of the sender
+    ShowTimezone,
+    /// Sets the time zone of the sender, or goes back to that of the room with `None`
+    SetTimezone(Option<Tz>),
+    Help,
+    /// An unknown subcommand or invalid arguments, with the reason
+    Invalid(Phrase),
+}
+
+#[derive(Clone, Debug)]
+pub enum Period {
+    /// The agenda window configured for the room
+    Upcoming,
+    Today,
+    Tomorrow,
+    Week,
+    Month,
+    /// From the start of the first day to the end of the last one
+    Days(NaiveDate, NaiveDate),
+}
+
+/// An event described in a `!cal add` command
+#[derive(Clone, Debug)]
+pub struct NewEvent {
+    pub title: String,
+    pub date: NaiveDate,
+    /// The local start time, in the zone of the room. All-day events have none.
+    pub time: Option<NaiveTime>,
+    pub duration: Option<Duration>,
+    pub location: Option<String>,
+}
+
+/// Designates an event in a `!cal edit` or `!cal delete` command
+#[derive(Clone, Debug)]
+pub enum EventRef {
+    /// The number of the event in the last listing of the room, starting from 1
+    Index(usize),
+    /// The start of the UID of the event
+    UidPrefix(String),
+}
+
+/// The changes requested by a `!cal edit` command
+#[derive(Clone, Debug, Default)]
+pub struct EventChanges {
+    pub title: Option<String>,
+    /// The new location, empty to remove it
+    pub location: Option<String>,
+    pub date: Option<NaiveDate>,
+    /// The new local start time, in the zone of the room
+    pub time: Option<NaiveTime>,
+    pub duration: Option<Duration>,
+}
+
+impl Command {
+    /// Parses a message body. Only messages starting with `!cal` or `!calendar` are commands, so
+    /// a mention of the command later in a message, or in the quote of a reply, is ignored.
+    pub fn parse(body: &str) -> Option<Self> {
+        let (prefix, rest) = split_word(body);
+        if !(prefix.eq_ignore_ascii_case("!cal") || prefix.eq_ignore_ascii_case("!calendar")) {
+            return None;
+        }
+
+        let (subcommand, arguments) = split_word(rest);
+        if subcommand.is_empty() {
+            return Some(Command::Agenda(Period::Upcoming));
+        }
+        if subcommand.eq_ignore_ascii_case("add") {
+            return Some(match NewEvent::parse(arguments) {
+                Ok(event) => Command::Add(event),
+                Err(err) => Command::Invalid(err),
+            });
+        }
+        if subcommand.eq_ignore_ascii_case("edit") {
+            let (reference, changes) = split_word(arguments);
+            return Some(
+                match (EventRef::parse(reference), EventChanges::parse(changes)) {
+                    (Ok(reference), Ok(changes)) => Command::Edit(reference, changes),
+                    (Err(err), _) | (_, Err(err)) => Command::Invalid(err),
+                },
+            );
+        }
+        if subcommand.eq_ignore_ascii_case("delete") {
+            let (reference, extra) = split_word(arguments);
+            let (extra, _) = split_word(extra);
+            if !extra.is_empty() {
+                return Some(Command::Invalid(
+                    Phrase::new("unexpected_argument").with("argument", extra),
+                ));
+            }
+            return Some(match EventRef::parse(reference) {
+                Ok(reference) => Command::Delete(reference),
+                Err(err) => Command::Invalid(err),
+            });
+        }
+        if subcommand.eq_ignore_ascii_case("tz") {
+            let (timezone, extra) = split_word(arguments);
+            let (extra, _) = split_word(extra);
+            if !extra.is_empty() {
+                return Some(Command::Invalid(
+                    Phrase::new("unexpected_argument").with("argument", extra),
+                ));
+            }
+            return Some(match timezone {
+                "" => Command::ShowTimezone,
+                _ if timezone.eq_ignore_ascii_case("reset") => Command::SetTimezone(None),
+                _ => match timezone.parse() {
+                    Ok(timezone) => Command::SetTimezone(Some(timezone)),
+                    Err(_) => {
+                        Command::Invalid(Phrase::new("unknown_timezone").with("timezone", timezone))
+                    }
+                },
+            });
+        }
+        let (extra, _) = split_word(arguments);
+        if !extra.is_empty() {
+            return Some(Command::Invalid(
+                Phrase::new("unexpected_argument").with("argument", extra),
+            ));
+        }
+
+        Some(match subcommand.to_lowercase().as_str() {
+            "today" => Command::Agenda(Period::Today),
+            "tomorrow" => Command::Agenda(Period::Tomorrow),
+            "week" => Command::Agenda(Period::Week),
+            "month" => Command::Agenda(Period::Month),
+            "next" => Command::Next,
+            "help" => Command::Help,
+            _ if subcommand.starts_with(|c: char| c.is_ascii_digit()) => {
+                match parse_days(subcommand) {
+                    Ok((first, last)) => Command::Agenda(Period::Days(first, last)),
+                    Err(err) => Command::Invalid(err),
+                }
+            }
+            _ => Command::Invalid(Phrase::new("unknown_subcommand").with("subcommand", subcommand)),
+        })
+    }
+
+    /// The reply to an invalid command: the reason it is invalid, followed by the usage
+    pub fn invalid_reply(reason: &Phrase, locale: &Locale) -> String {
+        format!(
+            "{}\n\n{}",
+            locale.phrase(reason),
+            locale.message("usage", &[])
+        )
+    }
+}
+
+impl Period {
+    /// The `start..end` window of this period, with days as observed in `timezone`
+    pub fn window(
+        &self,
+        now: DateTime<Utc>,
+        timezone: &Tz,
+        upcoming: Duration,
+        first_weekday: Weekday,
+    ) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.with_timezone(timezone).date_naive();
+        let day_start = |date: NaiveDate| start_of_day(date, timezone);
+
+        match self {
+            Period::Upcoming => (now, now + upcoming),
+            Period::Today => (day_start(today), day_start(today + Duration::days(1))),
+            Period::Tomorrow => (
+                day_start(today + Duration::days(1)),
+                day_start(today + Duration::days(2)),
+            ),
+            Period::Week => {
+                let days_into_week = today.weekday().days_since(first_weekday);
+                let week_start = today - Duration::days(i64::from(days_into_week));
+                (
+                    day_start(week_start),
+                    day_start(week_start + Duration::days(7)),
+                )
+            }
+            Period::Month => (
+                day_start(today),
+                day_start(
+                    today
+                        .checked_add_months(Months::new(1))
+                        .unwrap_or(today + Duration::days(31)),
+                ),
+            ),
+            Period::Days(first, last) => (day_start(*first), day_start(*last + Duration::days(1))),
+        }
+    }
+
+    /// Describes the period, to complete "Events …" or "No events …". `None` for the room's
+    /// own agenda window, whose wording is configured.
+    pub fn description(&self, locale: &Locale) -> Option<String> {
+        match self {
+            Period::Upcoming => None,
+            Period::Today => Some(locale.message("today", &[])),
+            Period::Tomorrow => Some(locale.message("tomorrow", &[])),
+            Period::Week => Some(locale.message("this_week", &[])),
+            Period::Month => Some(locale.message("this_month", &[])),
+            Period::Days(first, last) if first == last => {
+                Some(locale.message("on_day", &[("date", &locale.format_date(first))]))
+            }
+            Period::Days(first, last) => Some(locale.message(
+                "between_days",
+                &[
+                    ("first", &locale.format_short_date(first)),
+                    ("last", &locale.format_short_date(last)),
+                ],
+            )),
+        }
+    }
+}
+
+impl NewEvent {
+    /// Parses the arguments of `!cal add`: a title, quoted unless it is a single word, a date, an
+    /// optional start time and duration, and an optional location after `@`
+    fn parse(arguments: &str) -> Result<Self, Phrase> {
+        let arguments = arguments.trim();
+        let (title, rest) = match arguments.chars().next() {
+            Some(quote @ ('"' | '“')) => {
+                let closing = if quote == '"' { '"' } else { '”' };
+                let inner = &arguments[quote.len_utf8()..];
+                let end = inner.find(closing).ok_or(Phrase::new("unclosed_title"))?;
+                (&inner[..end], &inner[end + closing.len_utf8()..])
+            }
+            _ => split_word(arguments),
+        };
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(Phrase::new("missing_title"));
+        }
+
+        let (details, location) = match rest.split_once('@') {
+            Some((details, location)) => (details, Some(location.trim().to_string())),
+            None => (rest, None),
+        };
+        let location = location.filter(|location| !location.is_empty());
+
+        let mut words = details.split_whitespace();
+        let date = words.next().ok_or(Phrase::new("missing_date"))?;
+        let date =
+            parse_date(date).ok_or_else(|| Phrase::new("invalid_date").with("date", date))?;
+
+        let mut time = None;
+        let mut duration = None;
+        for word in words {
+            if time.is_none() && duration.is_none() && word.contains(':') {
+                time = Some(
+                    NaiveTime::parse_from_str(word, "%H:%M")
+                        .map_err(|_| Phrase::new("invalid_time").with("time", word))?,
+                );
+            } else if duration.is_none() {
+                duration = Some(parse_duration(word)?);
+            } else {
+                return Err(Phrase::new("unexpected_argument").with("argument", word));
+            }
+        }
+
+        if time.is_none() && duration.is_some_and(|duration| duration.num_seconds() % 86400 != 0) {
+            return Err(Phrase::new("whole_days"));
+        }
+
+        Ok(Self {
+            title: title.to_string(),
+            date,
+            time,
+            duration,
+            location,
+        })
+    }
+
+    /// Builds the event, with its start time taken in `timezone`. Fails when it would end
+    /// after the last year iCal can hold.
+    pub fn to_event(&self, uid: String, url: Url, timezone: &Tz) -> Result<Event, Phrase> {
+        let now = Utc::now();
+        let event = match self.time {
+            Some(time) => {
+                let start = EventTimeZone::Iana(*timezone).to_utc(&self.date.and_time(time));
+                let duration = self
+                    .duration
+                    .unwrap_or(Duration::hours(DEFAULT_EVENT_HOURS));
+                let end = end_after(&start, duration)?;
+                Event::new_timed(
+                    self.title.clone(),
+                    uid,
+                    start,
+                    end,
+                    self.location.clone(),
+                    None,
+                    url,
+                    now,
+                    Some(now),
+                )
+            }
+            None => {
+                let end = self
+                    .date
+                    .checked_add_signed(self.duration.unwrap_or(Duration::days(1)))
+                    .filter(|end| end.year() <= MAX_YEAR)
+                    .ok_or(Phrase::new("too_late"))?;
+                Event::new_all_day(
+                    self.title.clone(),
+                    uid,
+                    self.date,
+                    end,
+                    self.location.clone(),
+                    None,
+                    url,
+                    now,
+                    Some(now),
+                )
+            }
+        };
+        Ok(event)
+    }
+}
+
+impl EventRef {
+    /// Parses a number from the last listing, or else a UID prefix
+    fn parse(value: &str) -> Result<Self, Phrase> {
+        if value.is_empty() {
+            return Err(Phrase::new("missing_reference"));
+        }
+        match value.parse::<usize>() {
+            Ok(0) => Err(Phrase::new("numbered_from_one")),
+            Ok(index) => Ok(EventRef::Index(index)),
+            Err(_) => Ok(EventRef::UidPrefix(value.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for EventRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventRef::Index(index) => write!(f, "#{}", index),
+            EventRef::UidPrefix(prefix) => write!(f, "UID {}", prefix),
+        }
+    }
+}
+
+impl EventChanges {
+    /// Parses `key=value` pairs, whose values may contain spaces
+    fn parse(arguments: &str) -> Result<Self, Phrase> {
+        const KEYS: [&str; 4] = ["title", "location", "time", "duration"];
+
+        let mut pairs: Vec<(&str, Vec<&str>)> = Vec::new();
+        for word in arguments.split_whitespace() {
+            let key = word
+                .split_once('=')
+                .map(|(key, _)| key)
+                .filter(|key| KEYS.contains(key));
+            match (key, pairs.last_mut()) {
+                (Some(key), _) => pairs.push((key, vec![&word[key.len() + 1..]])),
+                (None, Some((_, value))) => value.push(word),
+                (None, None) => return Err(Phrase::new("unexpected_change").with("argument", word)),
+            }
+        }
+        if pairs.is_empty() {
+            return Err(Phrase::new("nothing_to_change"));
+        }
+
+        let mut changes = Self::default();
+        for (key, words) in pairs {
+            let value = words.join(" ");
+            let value = value
+                .trim_matches(|c| matches!(c, '"' | '“' | '”'))
+                .to_string();
+            match key {
+                "title" if value.is_empty() => return Err(Phrase::new("empty_title")),
+                "title" => changes.title = Some(value),
+                "location" => changes.location = Some(value),
+                "time" => {
+                    for part in value.split(|c: char| c.is_whitespace() || c == 'T') {
+                        if let Some(date) = parse_date(part) {
+                            changes.date = Some(date);
+                        } else if let Ok(time) = NaiveTime::parse_from_str(part, "%H:%M") {
+                            changes.time = Some(time);
+                        } else {
+                            return Err(Phrase::new("invalid_new_time").with("time", value));
+                        }
+                    }
+                }
+                _ => changes.duration = Some(parse_duration(&value)?),
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Whether the changes move or resize the event
+    pub fn reschedules(&self) -> bool {
+        self.date.is_some() || self.time.is_some() || self.duration.is_some()
+    }
+
+    /// The new start and end of `event`, with times taken in `timezone`. An all-day event
+    /// becomes a timed one when given a time. Fails when the event would end after the last
+    /// year iCal can hold.
+    pub fn reschedule(
+        &self,
+        event: &Event,
+        timezone: &Tz,
+    ) -> Result<(EventTime, EventTime), Phrase> {
+        let timezone = EventTimeZone::Iana(*timezone);
+
+        match (event.dtstart(), event.dtend(), self.time) {
+            (EventTime::Date(start), EventTime::Date(end), None) => {
+                let duration = self.duration.unwrap_or(*end - *start);
+                if duration.num_seconds() % 86400 != 0 {
+                    return Err(Phrase::new("whole_days"));
+                }
+                let date = self.date.unwrap_or(*start);
+                let end = date
+                    .checked_add_signed(duration)
+                    .filter(|end| end.year() <= MAX_YEAR)
+                    .ok_or(Phrase::new("too_late"))?;
+                Ok((EventTime::Date(date), EventTime::Date(end)))
+            }
+            (EventTime::DateTime(start), EventTime::DateTime(end), _) => {
+                let local = timezone.to_local(start);
+                let date = self.date.unwrap_or(local.date());
+                let time = self.time.unwrap_or(local.time());
+                let new_start = timezone.to_utc(&date.and_time(time));
+                let duration = self.duration.unwrap_or(*end - *start);
+                Ok((
+                    EventTime::DateTime(new_start),
+                    EventTime::DateTime(end_after(&new_start, duration)?),
+                ))
+            }
+            (EventTime::Date(start), _, Some(time)) => {
+                let date = self.date.unwrap_or(*start);
+                let new_start = timezone.to_utc(&date.and_time(time));
+                let duration = self
+                    .duration
+                    .unwrap_or(Duration::hours(DEFAULT_EVENT_HOURS));
+                Ok((
+                    EventTime::DateTime(new_start),
+                    EventTime::DateTime(end_after(&new_start, duration)?),
+                ))
+            }
+            _ => Err(Phrase::new("mixed_times")),
+        }
+    }
+}
+
+/// Splits the first word off a string
+fn split_word(value: &str) -> (&str, &str) {
+    let value = value.trim_start();
+    value.split_at(value.find(char::is_whitespace).unwrap_or(value.len()))
+}
+
+/// The end of an event lasting `duration` from `start`, unless it is after the last year iCal
+/// can hold
+fn end_after(start: &DateTime<Utc>, duration: Duration) -> Result<DateTime<Utc>, Phrase> {
+    start
+        .checked_add_signed(duration)
+        .filter(|end| end.year() <= MAX_YEAR)
+        .ok_or(Phrase::new("too_late"))
+}
+
+/// Parses a date such as `2024-11-04`, within the years iCal can hold
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .filter(|date| (1..=MAX_YEAR).contains(&date.year()))
+}
+
+/// Parses a duration such as `2h`, `90m`, `1h30m` or `2d`
+pub fn parse_duration(value: &str) -> Result<Duration, Phrase> {
+    let invalid = || Phrase::new("invalid_duration").with("duration", value);
+
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let amount: i64 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        let part = match c.to_ascii_lowercase() {
+            'd' => Duration::try_days(amount),
+            'h' => Duration::try_hours(amount),
+            'm' => Duration::try_minutes(amount),
+            _ => None,
+        };
+        duration = part
+            .and_then(|part| duration.checked_add(&part))
+            .ok_or_else(invalid)?;
+    }
+    if !number.is_empty() || duration <= Duration::zero() {
+        return Err(invalid());
+    }
+    Ok(duration)
+}
+
+/// Parses `2024-11-01` or `2024-11-01..2024-11-14` into the first and last day
+fn parse_days(value: &str) -> Result<(NaiveDate, NaiveDate), Phrase> {
+    let parse_date = |date: &str| {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| Phrase::new("invalid_date").with("date", date))
+    };
+
+    let (first, last) = match value.split_once("..") {
+        Some((first, last)) => (parse_date(first)?, parse_date(last)?),
+        None => {
+            let date = parse_date(value)?;
+            (date, date)
+        }
+    };
+
+    if last < first {
+        return Err(Phrase::new("reversed_range").with("range", value));
+    }
+    if (last - first).num_days() >= MAX_RANGE_DAYS {
+        return Err(Phrase::new("range_too_long")
+            .with("range", value)
+            .with("days", MAX_RANGE_DAYS));
+    }
+    Ok((first, last))
+}
+
+/// The first instant of a day in `timezone`, which is not midnight when the day starts with a
+/// DST gap
+fn start_of_day(date: NaiveDate, timezone: &Tz) -> DateTime<Utc> {
+    (0..24)
+        .filter_map(|hour| date.and_hms_opt(hour, 0, 0))
+        .find_map(|time| timezone.from_local_datetime(&time).earliest())
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| date.and_time(Default::default()).and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_event(command: &str) -> NewEvent {
+        match Command::parse(command) {
+            Some(Command::Add(event)) => event,
+            other => panic!("{} parsed as {:?}", command, other),
+        }
+    }
+
+    fn url() -> Url {
+        Url::parse("https://dav.example.org/calendar/event.ics").unwrap()
+    }
+
+    #[test]
+    fn adds_timed_and_all_day_events() {
+        let event = new_event("!cal add \"Board meeting\" 2024-11-04 18:00 2h @ Community Hall")
+            .to_event("uid".to_string(), url(), &chrono_tz::Europe::Paris)
+            .unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 17, 0, 0).unwrap();
+        assert_eq!(event.dtstart(), &EventTime::DateTime(start));
+        assert_eq!(
+            event.dtend(),
+            &EventTime::DateTime(start + Duration::hours(2))
+        );
+        assert_eq!(event.location().map(String::as_str), Some("Community Hall"));
+
+        let event = new_event("!cal add Retreat 2024-11-04 3d")
+            .to_event("uid".to_string(), url(), &chrono_tz::UTC)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2024, 11, 7).unwrap();
+        assert_eq!(event.dtend(), &EventTime::Date(end));
+    }
+
+    #[test]
+    fn rejects_events_ending_out_of_range() {
+        for command in [
+            "!cal add x 2024-11-04 99999999d",
+            "!cal add x 2024-11-04 18:00 99999999d",
+            "!cal add x 9999-12-31 2d",
+        ] {
+            assert_eq!(
+                new_event(command)
+                    .to_event("uid".to_string(), url(), &chrono_tz::America::New_York)
+                    .err(),
+                Some(Phrase::new("too_late")),
+                "{}",
+                command
+            );
+        }
+        for command in ["!cal add x +262142-12-31 23:00", "!cal add x 0000-01-01"] {
+            assert!(
+                matches!(Command::parse(command), Some(Command::Invalid(_))),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_reschedules_ending_out_of_range() {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 17, 0, 0).unwrap();
+        let timed = Event::new_timed(
+            "x".to_string(),
+            "uid".to_string(),
+            start,
+            start + Duration::hours(1),
+            None,
+            None,
+            url(),
+            start,
+            None,
+        );
+        let date = NaiveDate::from_ymd_opt(2024, 11, 4).unwrap();
+        let all_day = Event::new_all_day(
+            "x".to_string(),
+            "uid".to_string(),
+            date,
+            date + Duration::days(1),
+            None,
+            None,
+            url(),
+            start,
+            None,
+        );
+
+        for (event, changes) in [
+            (&timed, "duration=99999999d"),
+            (&timed, "time=9999-12-31 23:30"),
+            (&all_day, "duration=99999999d"),
+            (&all_day, "time=9999-12-31"),
+            (&all_day, "time=18:00 duration=99999999d"),
+        ] {
+            let changes = EventChanges::parse(changes).unwrap();
+            assert_eq!(
+                changes.reschedule(event, &chrono_tz::UTC).err(),
+                Some(Phrase::new("too_late")),
+                "{:?}",
+                changes
+            );
+        }
+
+        let changes = EventChanges::parse("time=2024-11-05 duration=2h").unwrap();
+        let (new_start, new_end) = changes.reschedule(&timed, &chrono_tz::UTC).unwrap();
+        assert_eq!(new_start, EventTime::DateTime(start + Duration::days(1)));
+        assert_eq!(
+            new_end,
+            EventTime::DateTime(start + Duration::days(1) + Duration::hours(2))
+        );
+        assert!(EventChanges::parse("time=+262142-12-31").is_err());
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn invalid(command: &str) -> Phrase {
+        match Command::parse(command) {
+            Some(Command::Invalid(reason)) => reason,
+            other => panic!("{} parsed as {:?}", command, other),
+        }
+    }
+
+    #[test]
+    fn recognises_the_command_as_the_leading_word_only() {
+        for body in ["!cal", "!calendar", "  !cal", "!CAL", "!Calendar\n"] {
+            assert!(
+                matches!(
+                    Command::parse(body),
+                    Some(Command::Agenda(Period::Upcoming))
+                ),
+                "{}",
+                body
+            );
+        }
+        for body in [
+            "see !calendar later",
+            "hello !cal today",
+            "!calx",
+            "!cal-today",
+            "> <@alice:example.org> !cal today\n\nthanks",
+            "",
+        ] {
+            assert!(Command::parse(body).is_none(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let parse = |body| Command::parse(body).unwrap();
+        assert!(matches!(
+            parse("!cal today"),
+            Command::Agenda(Period::Today)
+        ));
+        assert!(matches!(
+            parse("!calendar TOMORROW"),
+            Command::Agenda(Period::Tomorrow)
+        ));
+        assert!(matches!(parse("!cal week"), Command::Agenda(Period::Week)));
+        assert!(matches!(
+            parse("!cal month "),
+            Command::Agenda(Period::Month)
+        ));
+        assert!(matches!(parse("!cal next"), Command::Next));
+        assert!(matches!(parse("!cal help"), Command::Help));
+        assert_eq!(
+            invalid("!cal today please"),
+            Phrase::new("unexpected_argument").with("argument", "please")
+        );
+    }
+
+    #[test]
+    fn parses_dates_and_ranges() {
+        assert!(matches!(
+            Command::parse("!cal 2024-11-04"),
+            Some(Command::Agenda(Period::Days(first, last)))
+                if first == date(2024, 11, 4) && last == date(2024, 11, 4)
+        ));
+        assert!(matches!(
+            Command::parse("!cal 2024-11-01..2024-11-14"),
+            Some(Command::Agenda(Period::Days(first, last)))
+                if first == date(2024, 11, 1) && last == date(2024, 11, 14)
+        ));
+        assert!(matches!(
+            Command::parse("!cal 2024-01-01..2024-12-31"),
+            Some(Command::Agenda(Period::Days(_, _)))
+        ));
+
+        assert_eq!(
+            invalid("!cal 2024-11-14..2024-11-01"),
+            Phrase::new("reversed_range").with("range", "2024-11-14..2024-11-01")
+        );
+        assert_eq!(
+            invalid("!cal 2024-01-01..2025-01-01"),
+            Phrase::new("range_too_long")
+                .with("range", "2024-01-01..2025-01-01")
+                .with("days", MAX_RANGE_DAYS)
+        );
+        assert_eq!(
+            invalid("!cal 2024-13-01"),
+            Phrase::new("invalid_date").with("date", "2024-13-01")
+        );
+        assert_eq!(
+            invalid("!cal 2024-11-01..soon"),
+            Phrase::new("invalid_date").with("date", "soon")
+        );
+    }
+
+    #[test]
+    fn answers_unknown_subcommands_with_the_usage() {
+        let reason = invalid("!cal tomorow");
+        assert_eq!(
+            reason,
+            Phrase::new("unknown_subcommand").with("subcommand", "tomorow")
+        );
+
+        let catalogs = crate::locale::Catalogs::load(None).unwrap();
+        let locale = Locale::new("en_US", &catalogs).unwrap();
+        let reply = Command::invalid_reply(&reason, &locale);
+        assert!(
+            reply.starts_with("Unknown subcommand tomorow\n\nUsage: !cal"),
+            "{}",
+            reply
+        );
+        assert!(reply.ends_with(&locale.message("usage", &[])));
+    }
+
+    #[test]
+    fn computes_windows_in_the_zone_of_the_room() {
+        // Half past midnight in Paris on the day DST starts, still the 30th in UTC
+        let now = Utc.with_ymd_and_hms(2024, 3, 30, 23, 30, 0).unwrap();
+        let utc = |month, day, hour| Utc.with_ymd_and_hms(2024, month, day, hour, 0, 0).unwrap();
+        let window = |period: Period| {
+            period.window(
+                now,
+                &chrono_tz::Europe::Paris,
+                Duration::days(7),
+                Weekday::Mon,
+            )
+        };
+
+        assert_eq!(window(Period::Upcoming), (now, now + Duration::days(7)));
+        assert_eq!(window(Period::Today), (utc(3, 30, 23), utc(3, 31, 22)));
+        assert_eq!(window(Period::Tomorrow), (utc(3, 31, 22), utc(4, 1, 22)));
+        assert_eq!(window(Period::Week), (utc(3, 24, 23), utc(3, 31, 22)));
+        assert_eq!(window(Period::Month), (utc(3, 30, 23), utc(4, 29, 22)));
+        assert_eq!(
+            window(Period::Days(date(2024, 3, 31), date(2024, 4, 1))),
+            (utc(3, 30, 23), utc(4, 1, 22))
+        );
+
+        let sunday_first = Period::Week.window(
+            now,
+            &chrono_tz::Europe::Paris,
+            Duration::days(7),
+            Weekday::Sun,
+        );
+        assert_eq!(sunday_first, (utc(3, 30, 23), utc(4, 6, 22)));
+    }
+}
@@ -0,0 +1,829 @@
+//! Configuration of the bot: the calendar sources, and the rooms they are posted to.
+//!
+//! The configuration is read from a TOML file such as:
+//!
+//! ```toml
+//! default_timezone = "Europe/Berlin"
+//! default_locale = "de_DE"
+//! locales_dir = "/etc/calendar-bot/locales"
+//!
+//! [matrix]
+//! homeserver = "https://matrix.example.org"
+//! username = "calendar-bot"
+//! password_file = "/run/secrets/matrix_password"
+//!
+//! [[calendars]]
+//! key = "team"
+//! server = "https://dav.example.org/"
+//! username = "alice"
+//! password = "secret"
+//! calendar_name = "Team"
+//! color = "#3366ff"
+//!
+//! [[rooms]]
+//! room_id = "!abcdef:example.org"
+//! calendars = ["team"]
+//! write_calendar = "team"
+//! window_days = 14
+//! timezone = "Europe/Berlin"
+//! locale = "en_GB"
+//! clock = "24h"
+//! first_weekday = "Mon"
+//! heading = "This fortnight"
+//! notify_changes = true
+//! reminders = ["15m", "1d"]
+//! alarms = true
+//! pinned_agenda = false
+//! direct = false
+//!
+//! [rooms.templates]
+//! event = "{{ event.day }} {{ event.start | date('%H:%M') }}: {{ event.name }}"
+//! event_html = "<p>{{ event.day }} {{ event.start | date('%H:%M') }}: <b>{{ event.name }}</b></p>"
+//! footer = "{{ count }} events"
+//!
+//! [[rooms.digests]]
+//! schedule = "0 8 * * *"
+//! window_days = 1
+//! heading = "Today"
+//!
+//! [[rooms.digests]]
+//! name = "weekly"
+//! schedule = "30 8 * * Mon"
+//! catch_up = false
+//! ```
+//!
+//! The `server` of a calendar is the URL of a calendar collection or of the server, or a user
+//! address such as `alice@example.org`, whose server is found in the `_caldavs._tcp` DNS records
+//! of its domain when it is inside that domain, or else at the domain itself. Credentials are only
+//! sent to that server.
+//!
+//! Every password can be given inline, or read from a file with the `_file` variant of the key.
+//!
+//! Digests are agendas posted on a cron schedule (`minute hour day-of-month month day-of-week`)
+//! evaluated in the zone of the room. As in standard cron, days of the week are numbered from 0
+//! (Sunday) to 7 (Sunday again) or named, and when both the day of the month and the day of the
+//! week are restricted, days matching either are included. A time skipped by a DST transition is
+//! moved an hour later, and a time repeated by one only counts once.
+//!
+//! The window and wording of digests default to those of the room. A room without digests gets
+//! the agenda every Sunday at 09:00, or at the time given by the older `schedule = "Mon 08:30"`
+//! form. A digest missed while the bot was down is posted once it is back, as long as its window
+//! has not passed, unless `catch_up` is false. The time of the last digest is kept under its
+//! `name`, or else its position among the digests of the room, so that digests which may be
+//! reordered should be named.
+//!
+//! Times are shown in the `timezone` of the room, which defaults to `default_timezone` and then
+//! to UTC. Users can set their own zone with `!cal tz Europe/Paris`, which the replies to their
+//! commands use in direct chats: the rooms marked as such by Matrix, and those with `direct`.
+//!
+//! Reminders are posted the given time before each event of a room, at most 31 days, or when the
+//! alarms of the event itself say so, unless `alarms` is false. A room without `reminders` only
+//! gets the reminders of alarms, and none at all with `alarms = false`.
+//!
+//! With `pinned_agenda`, the agenda of the room is kept in a single pinned message, edited as the
+//! events change, and the room gets no digests unless some are configured.
+//!
+//! The messages posted to a room, replies to commands included, are written in the language of
+//! its `locale`, such as "fr_FR", or else of `default_locale`, "en_US" by default. The locale also
+//! decides the names of days and months, the `clock` ("12h" or "24h"), the `date_order` ("dmy",
+//! "mdy" or "ymd") and the `first_weekday` of `!cal week`, which the room can each override. The
+//! wording comes from the message catalogs of the bot, which those in `locales_dir` add to or
+//! override. Errors in the configuration and the logs are in English.
+//!
+//! The agendas of a room are rendered with the [minijinja](https://docs.rs/minijinja) templates of
+//! its `templates` table: `header`, `event` (once per event), `empty` (when there are none) and
+//! `footer`, each with an `_html` variant in which calendar values are escaped. They see `heading`,
+//! `empty_message`, `count`, `events`, `locale` and the catalog entries in `messages`, and `event`
+//! has `number`, `name`, `location`, `description`, `link`, `status`, `calendar.name`,
+//! `calendar.color`, `start`, `end`, `all_day`, `times`, `day` (such as "tomorrow"), `uid`,
+//! `last_modified` and `created`. Besides the built-in filters, `date("%H:%M")` formats a time in
+//! the zone and language of the room, `truncate(n)` shortens a text and `linkify` turns web
+//! addresses into links. Templates left out keep the built-in format.
+
+use chrono::{Duration, NaiveTime, Timelike, Weekday};
+use chrono_tz::Tz;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::calendars::CalendarSource;
+use crate::command::parse_duration;
+use crate::discovery;
+use crate::locale::{Catalogs, Clock, DateOrder, Locale};
+use crate::matrix::MatrixCredentials;
+use crate::schedule::Schedule;
+use crate::template::AgendaTemplates;
+
+/// The schedule of the agenda of a room without digests
+const DEFAULT_SCHEDULE: &str = "0 9 * * Sun";
+
+/// How long before an event its reminders can be, so that the events are read that far ahead
+pub const MAX_REMINDER_DAYS: i64 = 31;
+
+/// Parses the older form of a weekly schedule, such as `Sun 09:00`
+fn parse_weekly_schedule(value: &str) -> Result<Schedule, String> {
+    let invalid = || format!("invalid schedule {}, expected e.g. \"Sun 09:00\"", value);
+    let (weekday, time) = value.trim().split_once(' ').ok_or_else(invalid)?;
+    let weekday: Weekday = weekday.parse().map_err(|_| invalid())?;
+    let time = NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid())?;
+    Schedule::parse(&format!(
+        "{} {} * * {}",
+        time.minute(),
+        time.hour(),
+        weekday
+    ))
+}
+
+/// An agenda posted to a room on a schedule
+#[derive(Clone, Debug)]
+pub struct Digest {
+    /// Identifies the digest in the store: its `name`, or else its position in the room
+    pub key: String,
+    pub schedule: Schedule,
+    /// How far ahead the agenda looks
+    pub window: Duration,
+    pub template: MessageTemplate,
+    /// Whether the last digest missed while the bot was down is posted when it starts
+    pub catch_up: bool,
+}
+
+/// The wording of the agenda messages posted to a room
+#[derive(Clone, Debug)]
+pub struct MessageTemplate {
+    pub heading: String,
+    /// Shown instead of the events when there are none
+    pub empty: String,
+    pub agenda: AgendaTemplates,
+}
+
+/// The configuration of a room the bot posts to
+#[derive(Clone, Debug)]
+pub struct RoomConfig {
+    pub room_id: OwnedRoomId,
+    /// Keys of the calendar sources shown in this room. All of them are shown when empty.
+    pub calendars: Vec<String>,
+    /// Key of the calendar source events added from this room are written to
+    pub write_calendar: Option<String>,
+    /// How far ahead the agenda looks
+    pub window: Duration,
+    pub digests: Vec<Digest>,
+    /// The zone the times of the messages are shown in, and the schedules evaluated in
+    pub timezone: Tz,
+    pub template: MessageTemplate,
+    /// Whether changes to upcoming events are posted as they happen
+    pub notify_changes: bool,
+    /// How long before each event a reminder is posted, unless the event has alarms of its own
+    pub reminders: Vec<Duration>,
+    /// Whether reminders are posted at the alarms of the events
+    pub alarms: bool,
+    /// Whether the agenda is kept up to date in a message pinned in the room
+    pub pinned_agenda: bool,
+    /// Whether the room is a direct chat with one user, even if Matrix does not mark it as one
+    pub direct: bool,
+    /// The language and the conventions of dates and times of the messages
+    pub locale: Locale,
+}
+
+impl RoomConfig {
+    /// Whether events of the calendar source `key` are shown in this room
+    pub fn shows_calendar(&self, key: &str) -> bool {
+        self.calendars.is_empty() || self.calendars.iter().any(|calendar| calendar == key)
+    }
+
+    /// Key of the calendar source events are added to: the configured one, or else the first
+    /// calendar shown in the room. `None` when any calendar can be written to.
+    pub fn write_calendar_key(&self) -> Option<&str> {
+        self.write_calendar
+            .as_deref()
+            .or(self.calendars.first().map(String::as_str))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub matrix: MatrixCredentials,
+    pub calendars: Vec<CalendarSource>,
+    /// The zone floating times of the calendars are interpreted in
+    pub default_timezone: Tz,
+    pub rooms: Vec<RoomConfig>,
+}
+
+/// The locale of rooms that do not set one
+const DEFAULT_LOCALE: &str = "en_US";
+
+impl Config {
+    /// Reads and validates the configuration file at `path`. All the problems found are reported
+    /// at once in the error.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+        let raw: RawConfig = toml::from_str(&content)
+            .map_err(|err| format!("Invalid configuration in {}: {}", path.display(), err))?;
+
+        raw.validate().map_err(|errors| {
+            format!(
+                "Invalid configuration in {}:\n  {}",
+                path.display(),
+                errors.join("\n  ")
+            )
+        })
+    }
+
+    pub fn room(&self, room_id: &RoomId) -> Option<&RoomConfig> {
+        self.rooms.iter().find(|room| room.room_id == room_id)
+    }
+}
+
+/// The configuration file, as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    default_timezone: Option<String>,
+    default_locale: Option<String>,
+    locales_dir: Option<PathBuf>,
+    matrix: RawMatrix,
+    calendars: Vec<RawCalendar>,
+    rooms: Vec<RawRoom>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawMatrix {
+    homeserver: String,
+    username: String,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCalendar {
+    key: String,
+    server: String,
+    username: String,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    calendar_name: Option<String>,
+    label: Option<String>,
+    color: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRoom {
+    room_id: String,
+    #[serde(default)]
+    calendars: Vec<String>,
+    write_calendar: Option<String>,
+    window_days: Option<i64>,
+    schedule: Option<String>,
+    timezone: Option<String>,
+    heading: Option<String>,
+    empty_message: Option<String>,
+    locale: Option<String>,
+    clock: Option<String>,
+    date_order: Option<String>,
+    first_weekday: Option<String>,
+    notify_changes: Option<bool>,
+    #[serde(default)]
+    reminders: Vec<String>,
+    alarms: Option<bool>,
+    #[serde(default)]
+    pinned_agenda: bool,
+    #[serde(default)]
+    direct: bool,
+    #[serde(default)]
+    templates: HashMap<String, String>,
+    #[serde(default)]
+    digests: Vec<RawDigest>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDigest {
+    name: Option<String>,
+    schedule: String,
+    window_days: Option<i64>,
+    heading: Option<String>,
+    empty_message: Option<String>,
+    catch_up: Option<bool>,
+}
+
+impl RawConfig {
+    fn validate(self) -> Result<Config, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let default_timezone = match &self.default_timezone {
+            Some(tz) => parse_timezone(tz)
+                .map_err(|err| errors.push(format!("default_timezone: {}", err)))
+                .unwrap_or(chrono_tz::UTC),
+            None => chrono_tz::UTC,
+        };
+
+        let catalogs = Catalogs::load(self.locales_dir.as_deref())
+            .map_err(|catalog_errors| {
+                errors.extend(
+                    catalog_errors
+                        .into_iter()
+                        .map(|err| format!("locales_dir: {}", err)),
+                )
+            })
+            .unwrap_or_else(|_| Catalogs::load(None).expect("valid built-in catalogs"));
+        let default_locale = self.default_locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+        let default_locale = Locale::new(default_locale, &catalogs)
+            .map_err(|err| errors.push(format!("default_locale: {}", err)))
+            .or_else(|_| Locale::new(DEFAULT_LOCALE, &catalogs))
+            .expect("valid default locale");
+
+        if let Err(err) = self.matrix.homeserver.parse::<url::Url>() {
+            errors.push(format!(
+                "matrix.homeserver: invalid URL {}: {}",
+                self.matrix.homeserver, err
+            ));
+        }
+        let matrix_password = read_secret(self.matrix.password, self.matrix.password_file)
+            .map_err(|err| errors.push(format!("matrix: {}", err)))
+            .unwrap_or_default();
+        let matrix = MatrixCredentials {
+            homeserver: self.matrix.homeserver,
+            username: self.matrix.username,
+            password: matrix_password,
+        };
+
+        let mut keys = HashSet::new();
+        let mut calendars = Vec::new();
+        for (index, calendar) in self.calendars.into_iter().enumerate() {
+            let context = format!("calendars[{}] ({})", index, calendar.key);
+            if !keys.insert(calendar.key.clone()) {
+                errors.push(format!("{}: duplicate key", context));
+            }
+            if let Err(err) = discovery::server_url(&calendar.server) {
+                errors.push(format!(
+                    "{}: invalid server {}: {}",
+                    context, calendar.server, err
+                ));
+            }
+            match read_secret(calendar.password, calendar.password_file) {
+                Ok(password) => calendars.push(CalendarSource {
+                    key: calendar.key,
+                    server: calendar.server,
+                    username: calendar.username,
+                    password,
+                    calendar_name: calendar.calendar_name,
+                    label: calendar.label,
+                    color: calendar.color,
+                }),
+                Err(err) => errors.push(format!("{}: {}", context, err)),
+            }
+        }
+        if calendars.is_empty() && errors.is_empty() {
+            errors.push("calendars: at least one calendar is required".to_string());
+        }
+
+        let mut room_ids = HashSet::new();
+        let mut rooms = Vec::new();
+        for (index, room) in self.rooms.into_iter().enumerate() {
+            let context = format!("rooms[{}] ({})", index, room.room_id);
+            if !room_ids.insert(room.room_id.clone()) {
+                errors.push(format!("{}: duplicate room", context));
+            }
+            match room.validate(&keys, &default_timezone, &default_locale, &catalogs) {
+                Ok(room) => rooms.push(room),
+                Err(room_errors) => errors.extend(
+                    room_errors
+                        .into_iter()
+                        .map(|err| format!("{}: {}", context, err)),
+                ),
+            }
+        }
+        if rooms.is_empty() && errors.is_empty() {
+            errors.push("rooms: at least one room is required".to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Config {
+            matrix,
+            calendars,
+            default_timezone,
+            rooms,
+        })
+    }
+}
+
+impl RawRoom {
+    fn validate(
+        self,
+        calendar_keys: &HashSet<String>,
+        default_timezone: &Tz,
+        default_locale: &Locale,
+        catalogs: &Catalogs,
+    ) -> Result<RoomConfig, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let room_id = RoomId::parse(&self.room_id)
+            .map_err(|err| errors.push(format!("invalid room ID: {}", err)))
+            .ok();
+        for key in self.calendars.iter().chain(&self.write_calendar) {
+            if !calendar_keys.contains(key) {
+                errors.push(format!("unknown calendar {}", key));
+            }
+        }
+        let window = parse_window(self.window_days.unwrap_or(7))
+            .map_err(|err| errors.push(err))
+            .unwrap_or(Duration::days(7));
+        let timezone = match &self.timezone {
+            Some(tz) => parse_timezone(tz)
+                .map_err(|err| errors.push(err))
+                .unwrap_or(*default_timezone),
+            None => *default_timezone,
+        };
+
+        let mut reminders = Vec::new();
+        for reminder in &self.reminders {
+            match parse_duration(reminder) {
+                Ok(lead_time) if lead_time > Duration::days(MAX_REMINDER_DAYS) => {
+                    errors.push(format!(
+                        "reminders: {} is longer than {} days",
+                        reminder, MAX_REMINDER_DAYS
+                    ))
+                }
+                Ok(lead_time) => reminders.push(lead_time),
+                Err(_) => errors.push(format!(
+                    "reminders: invalid duration {}, expected e.g. 15m, 2h or 1d",
+                    reminder
+                )),
+            }
+        }
+
+        let mut locale = match &self.locale {
+            Some(locale) => Locale::new(locale, catalogs)
+                .map_err(|err| errors.push(err))
+                .unwrap_or(default_locale.clone()),
+            None => default_locale.clone(),
+        };
+        match self.clock.as_deref() {
+            Some("12h") => locale.clock = Clock::TwelveHour,
+            Some("24h") => locale.clock = Clock::TwentyFourHour,
+            Some(clock) => errors.push(format!("clock must be 12h or 24h, not {}", clock)),
+            None => {}
+        }
+        match self.date_order.as_deref() {
+            Some("dmy") => locale.date_order = DateOrder::DayMonthYear,
+            Some("mdy") => locale.date_order = DateOrder::MonthDayYear,
+            Some("ymd") => locale.date_order = DateOrder::YearMonthDay,
+            Some(order) => {
+                errors.push(format!("date_order must be dmy, mdy or ymd, not {}", order))
+            }
+            None => {}
+        }
+        if let Some(weekday) = &self.first_weekday {
+            match weekday.parse::<Weekday>() {
+                Ok(weekday) => locale.first_weekday = weekday,
+                Err(_) => errors.push(format!("invalid first_weekday {}", weekday)),
+            }
+        }
+
+        let agenda = AgendaTemplates::new(&self.templates)
+            .map_err(|template_errors| errors.extend(template_errors))
+            .unwrap_or_default();
+        let template = MessageTemplate {
+            heading: self
+                .heading
+                .unwrap_or_else(|| locale.message("heading", &[])),
+            empty: self
+                .empty_message
+                .unwrap_or_else(|| locale.message("empty", &[])),
+            agenda,
+        };
+
+        let has_digests = !self.digests.is_empty();
+        let mut digests = Vec::new();
+        for (index, digest) in self.digests.into_iter().enumerate() {
+            match digest.validate(index, &window, &template) {
+                Ok(digest) if digests.iter().any(|other: &Digest| other.key == digest.key) => {
+                    errors.push(format!(
+                        "digests[{}]: {} is the name of another digest",
+                        index, digest.key
+                    ))
+                }
+                Ok(digest) => digests.push(digest),
+                Err(digest_errors) => errors.extend(
+                    digest_errors
+                        .into_iter()
+                        .map(|err| format!("digests[{}]: {}", index, err)),
+                ),
+            }
+        }
+        match &self.schedule {
+            Some(_) if has_digests => {
+                errors.push("schedule and digests are mutually exclusive".to_string())
+            }
+            Some(schedule) => match parse_weekly_schedule(schedule) {
+                Ok(schedule) => digests.push(Digest {
+                    key: digest_key(0),
+                    schedule,
+                    window,
+                    template: template.clone(),
+                    catch_up: true,
+                }),
+                Err(err) => errors.push(err),
+            },
+            None if !has_digests && !self.pinned_agenda => digests.push(Digest {
+                key: digest_key(0),
+                schedule: Schedule::parse(DEFAULT_SCHEDULE).expect("valid schedule"),
+                window,
+                template: template.clone(),
+                catch_up: true,
+            }),
+            None => {}
+        }
+
+        match room_id {
+            Some(room_id) if errors.is_empty() => Ok(RoomConfig {
+                room_id,
+                calendars: self.calendars,
+                write_calendar: self.write_calendar,
+                window,
+                digests,
+                timezone,
+                template,
+                notify_changes: self.notify_changes.unwrap_or(true),
+                reminders,
+                alarms: self.alarms.unwrap_or(true),
+                pinned_agenda: self.pinned_agenda,
+                direct: self.direct,
+                locale,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// The key of a digest without a name
+fn digest_key(index: usize) -> String {
+    format!("#{}", index)
+}
+
+impl RawDigest {
+    fn validate(
+        self,
+        index: usize,
+        room_window: &Duration,
+        room_template: &MessageTemplate,
+    ) -> Result<Digest, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let schedule = Schedule::parse(&self.schedule)
+            .map_err(|err| errors.push(err))
+            .ok();
+        if self
+            .name
+            .as_deref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            errors.push("name must not be empty".to_string());
+        }
+        let window = match self.window_days {
+            Some(window_days) => parse_window(window_days)
+                .map_err(|err| errors.push(err))
+                .unwrap_or(*room_window),
+            None => *room_window,
+        };
+
+        match schedule {
+            Some(schedule) if errors.is_empty() => Ok(Digest {
+                key: self.name.unwrap_or_else(|| digest_key(index)),
+                schedule,
+                window,
+                template: MessageTemplate {
+                    heading: self.heading.unwrap_or(room_template.heading.clone()),
+                    empty: self.empty_message.unwrap_or(room_template.empty.clone()),
+                    agenda: room_template.agenda.clone(),
+                },
+                catch_up: self.catch_up.unwrap_or(true),
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// How far ahead an agenda can look
+const MAX_WINDOW_DAYS: i64 = 366;
+
+fn parse_window(window_days: i64) -> Result<Duration, String> {
+    if (1..=MAX_WINDOW_DAYS).contains(&window_days) {
+        Ok(Duration::days(window_days))
+    } else {
+        Err(format!(
+            "window_days must be between 1 and {}, not {}",
+            MAX_WINDOW_DAYS, window_days
+        ))
+    }
+}
+
+fn parse_timezone(tz: &str) -> Result<Tz, String> {
+    tz.parse()
+        .map_err(|_| format!("{} is not an IANA time zone", tz))
+}
+
+/// Reads a secret given either inline or as the path of a file holding it
+fn read_secret(value: Option<String>, file: Option<PathBuf>) -> Result<String, String> {
+    match (value, file) {
+        (Some(value), None) => Ok(value),
+        (None, Some(file)) => fs::read_to_string(&file)
+            .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|err| format!("unable to read {}: {}", file.display(), err)),
+        (Some(_), Some(_)) => Err("password and password_file are mutually exclusive".to_string()),
+        (None, None) => Err("password or password_file is required".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRIX: &str = r#"
+        [matrix]
+        homeserver = "https://matrix.example.org"
+        username = "calendar-bot"
+        password = "secret"
+
+        [[calendars]]
+        key = "team"
+        server = "https://dav.example.org/"
+        username = "alice"
+        password = "secret"
+    "#;
+
+    fn validate(rooms: &str) -> Result<Config, Vec<String>> {
+        toml::from_str::<RawConfig>(&format!("{}\n{}", MATRIX, rooms))
+            .unwrap()
+            .validate()
+    }
+
+    fn errors(rooms: &str) -> Vec<String> {
+        validate(rooms).expect_err("an invalid configuration")
+    }
+
+    #[test]
+    fn accepts_a_minimal_configuration() {
+        let config = validate(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rooms.len(), 1);
+        let room = &config.rooms[0];
+        assert_eq!(room.window, Duration::days(7));
+        assert_eq!(room.digests.len(), 1);
+        assert_eq!(room.digests[0].key, "#0");
+        assert!(!room.direct);
+        assert_eq!(config.matrix.password, "secret");
+    }
+
+    #[test]
+    fn reports_all_errors_together() {
+        let errors = errors(
+            r#"
+            [[rooms]]
+            room_id = "not a room"
+            calendars = ["team", "unknown"]
+            timezone = "Mars/Olympus"
+            clock = "36h"
+            "#,
+        );
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .all(|err| err.starts_with("rooms[0] (not a room): ")));
+        assert!(errors.iter().any(|err| err.contains("invalid room ID")));
+        assert!(errors
+            .iter()
+            .any(|err| err.contains("unknown calendar unknown")));
+        assert!(errors.iter().any(|err| err.contains("Mars/Olympus")));
+        assert!(errors.iter().any(|err| err.contains("36h")));
+    }
+
+    #[test]
+    fn rejects_duplicate_rooms() {
+        let errors = errors(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            window_days = 14
+            "#,
+        );
+        assert_eq!(errors, ["rooms[1] (!abc:example.org): duplicate room"]);
+    }
+
+    #[test]
+    fn reads_secrets_from_files() {
+        let file = std::env::temp_dir().join(format!("calendar-bot-secret-{}", std::process::id()));
+        fs::write(&file, "from a file\n").unwrap();
+        let secret = read_secret(None, Some(file.clone()));
+        fs::remove_file(&file).unwrap();
+        assert_eq!(secret.unwrap(), "from a file");
+
+        assert_eq!(
+            read_secret(Some("inline".to_string()), None).unwrap(),
+            "inline"
+        );
+        assert!(read_secret(Some("inline".to_string()), Some(file.clone())).is_err());
+        assert!(read_secret(None, None).is_err());
+        assert!(read_secret(None, Some(file)).is_err());
+    }
+
+    #[test]
+    fn rejects_schedule_with_digests() {
+        let errors = errors(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            schedule = "Mon 08:30"
+
+            [[rooms.digests]]
+            schedule = "0 8 * * *"
+            "#,
+        );
+        assert_eq!(
+            errors,
+            ["rooms[0] (!abc:example.org): schedule and digests are mutually exclusive"]
+        );
+    }
+
+    #[test]
+    fn bounds_window_days() {
+        for window_days in [0, -1, MAX_WINDOW_DAYS + 1, i64::MAX] {
+            let errors = errors(&format!(
+                r#"
+                [[rooms]]
+                room_id = "!abc:example.org"
+                calendars = ["team"]
+                window_days = {}
+
+                [[rooms.digests]]
+                schedule = "0 8 * * *"
+                window_days = {}
+                "#,
+                window_days, window_days
+            ));
+            assert_eq!(errors.len(), 2, "{:?}", errors);
+            assert!(errors[1].starts_with("rooms[0] (!abc:example.org): digests[0]: window_days"));
+        }
+        let config = validate(&format!(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            window_days = {}
+            "#,
+            MAX_WINDOW_DAYS
+        ))
+        .unwrap();
+        assert_eq!(config.rooms[0].window, Duration::days(MAX_WINDOW_DAYS));
+    }
+
+    #[test]
+    fn bounds_reminders() {
+        let config = validate(&format!(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            reminders = ["15m", "{}d"]
+            "#,
+            MAX_REMINDER_DAYS
+        ))
+        .unwrap();
+        assert_eq!(
+            config.rooms[0].reminders,
+            [Duration::minutes(15), Duration::days(MAX_REMINDER_DAYS)]
+        );
+
+        let errors = errors(&format!(
+            r#"
+            [[rooms]]
+            room_id = "!abc:example.org"
+            calendars = ["team"]
+            reminders = ["{}d", "soon"]
+            "#,
+            MAX_REMINDER_DAYS + 1
+        ));
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("is longer than 31 days"));
+        assert!(errors[1].contains("invalid duration soon"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Agendas posted to rooms on the schedules of their digests
+
+use chrono::{DateTime, Utc};
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId},
+    Client,
+};
+use std::sync::Arc;
+
+use crate::config::{Digest, RoomConfig};
+use crate::{get_agenda_message, State};
+
+/// Posts the agenda of a digest of a room whenever its schedule says so
+pub async fn post_digest(
+    client: Arc<Client>,
+    state: Arc<State>,
+    room_id: OwnedRoomId,
+    index: usize,
+) {
+    let Some(room_config) = state.config.room(&room_id) else {
+        log::error!("No configuration for room {}", room_id);
+        return;
+    };
+    let Some(digest) = room_config.digests.get(index) else {
+        log::error!("No digest {} for room {}", index, room_id);
+        return;
+    };
+
+    let now = Utc::now();
+    let mut last_posted = match state.store.last_digest(room_id.as_str(), &digest.key) {
+        Ok(Some(last_posted)) => last_posted,
+        Ok(None) => {
+            // Digests are only caught up on from the first time the bot ran with them
+            record_digest(&state, &room_id, &digest.key, &now);
+            now
+        }
+        Err(err) => {
+            log::error!(
+                "Unable to read when the last digest of {} was sent: {}",
+                room_id,
+                err
+            );
+            now
+        }
+    };
+
+    if let Some(missed) = digest
+        .schedule
+        .last_between(&last_posted, &now, &room_config.timezone)
+    {
+        if digest.catch_up && missed + digest.window > now {
+            log::info!("Posting the digest of {} missed at {}", room_id, missed);
+            if send_digest(&client, &state, room_config, digest).await {
+                record_digest(&state, &room_id, &digest.key, &missed);
+            }
+        } else {
+            log::info!("Skipping the digest of {} missed at {}", room_id, missed);
+            record_digest(&state, &room_id, &digest.key, &missed);
+        }
+        last_posted = missed;
+    }
+
+    loop {
+        // The next time is computed from the last one as well, in case the clock is behind it
+        let after = last_posted.max(Utc::now());
+        let Some(next) = digest.schedule.next_after(&after, &room_config.timezone) else {
+            log::error!(
+                "The schedule {} of {} never comes",
+                digest.schedule,
+                room_id
+            );
+            return;
+        };
+        log::debug!("Next digest for {} at {}", room_id, next);
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        last_posted = next;
+
+        if send_digest(&client, &state, room_config, digest).await {
+            record_digest(&state, &room_id, &digest.key, &next);
+        }
+    }
+}
+
+/// Posts the agenda of a digest to its room, and tells whether it was sent
+async fn send_digest(client: &Client, state: &State, room: &RoomConfig, digest: &Digest) -> bool {
+    let Some(matrix_room) = client.get_room(&room.room_id) else {
+        log::error!("Failed to find room with ID {}", room.room_id);
+        return false;
+    };
+
+    let now = Utc::now();
+    let (body, html_body) =
+        get_agenda_message(state, room, &now, &(now + digest.window), &digest.template).await;
+    let content = RoomMessageEventContent::text_html(body, html_body);
+
+    match matrix_room.send(content).await {
+        Ok(_) => {
+            log::info!("Digest sent to {}", room.room_id);
+            true
+        }
+        Err(error) => {
+            log::error!("Error sending digest: {error}");
+            false
+        }
+    }
+}
+
+/// Records the scheduled time of the last digest posted to a room, so that it is neither posted
+/// again nor missed after a restart
+fn record_digest(state: &State, room_id: &OwnedRoomId, digest: &str, sent: &DateTime<Utc>) {
+    if let Err(err) = state.store.record_digest(room_id.as_str(), digest, sent) {
+        log::error!("Unable to record the digest sent to {}: {}", room_id, err);
+    }
+}
@@ -0,0 +1,464 @@
+//! CalDAV service discovery (RFC6764 and RFC4791 section 6), to find the calendar collections of
+//! a user from just a server URL or a user address
+
+use hickory_resolver::TokioAsyncResolver;
+use minidom::Element;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use std::cmp::Reverse;
+use std::error::Error;
+use url::Url;
+
+use crate::cal::{find_elems, sub_request, CalDavCredentials};
+
+/// A calendar collection found on the server
+#[derive(Clone, Debug)]
+pub struct CalendarInfo {
+    url: Url,
+    display_name: Option<String>,
+    color: Option<String>,
+}
+
+impl CalendarInfo {
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+    pub fn color(&self) -> Option<&str> {
+        self.color.as_deref()
+    }
+}
+
+/// Turns a configured server into a URL: either a URL itself, or a user address such as
+/// `alice@example.com`, whose domain hosts the server
+pub fn server_url(server: &str) -> Result<Url, Box<dyn Error>> {
+    match user_domain(server) {
+        Some(domain) => Ok(format!("https://{}/", domain).parse()?),
+        None => Ok(server.parse()?),
+    }
+}
+
+/// Finds the URL of a configured server. For a user address, the `_caldavs._tcp` SRV record of
+/// its domain and the context path of its TXT record are looked up first (RFC6764 section 3), and
+/// the domain is only assumed to host the server when it has none. As these records are not
+/// authenticated, a server outside of the domain is not used (RFC6764 section 8).
+pub async fn resolve_server(server: &str) -> Result<Url, Box<dyn Error>> {
+    let url = server_url(server)?;
+    let Some(domain) = user_domain(server) else {
+        return Ok(url);
+    };
+    match lookup_service(domain).await {
+        Ok(Some(service)) if is_in_domain(&service, domain) => {
+            log::info!("Found the CalDAV service of {} at {}", domain, service);
+            Ok(service)
+        }
+        Ok(Some(service)) => {
+            // The credentials are only sent to the configured server
+            log::warn!(
+                "Not using the CalDAV service of {} at {}, outside of its domain: configure that \
+                 URL to use it",
+                domain,
+                service
+            );
+            Ok(url)
+        }
+        Ok(None) => Ok(url),
+        Err(err) => {
+            log::debug!("No CalDAV service record for {}: {}", domain, err);
+            Ok(url)
+        }
+    }
+}
+
+/// The domain of a user address, such as `example.com` for `alice@example.com`
+fn user_domain(server: &str) -> Option<&str> {
+    if server.contains("://") {
+        return None;
+    }
+    server.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// Looks up the CalDAV service of a domain in its SRV and TXT records
+async fn lookup_service(domain: &str) -> Result<Option<Url>, Box<dyn Error>> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let name = format!("_caldavs._tcp.{}.", domain);
+
+    // The first record by priority is used, and the heaviest of those sharing it rather than one
+    // picked at random by weight, so that the same server is used on every start
+    let srv = resolver.srv_lookup(name.as_str()).await?;
+    let Some(record) = srv
+        .iter()
+        .min_by_key(|record| (record.priority(), Reverse(record.weight())))
+    else {
+        return Ok(None);
+    };
+
+    let path = match resolver.txt_lookup(name.as_str()).await {
+        Ok(txt) => txt.iter().find_map(|txt| context_path(&txt.to_string())),
+        Err(err) => {
+            log::debug!("No CalDAV context path for {}: {}", domain, err);
+            None
+        }
+    };
+    service_url(&record.target().to_utf8(), record.port(), path.as_deref())
+}
+
+/// Whether the host of `url` is `domain` itself or one of its subdomains
+fn is_in_domain(url: &Url, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    url.host_str().is_some_and(|host| {
+        let host = host.to_ascii_lowercase();
+        host == domain
+            || host
+                .strip_suffix(&domain)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// The path of a `path=/dav/` TXT record
+fn context_path(txt: &str) -> Option<String> {
+    txt.strip_prefix("path=")
+        .filter(|path| path.starts_with('/'))
+        .map(str::to_string)
+}
+
+/// The URL of the service found in an SRV record. A target of "." means that the domain has
+/// no such service.
+fn service_url(target: &str, port: u16, path: Option<&str>) -> Result<Option<Url>, Box<dyn Error>> {
+    let host = target.trim_end_matches('.');
+    if host.is_empty() {
+        return Ok(None);
+    }
+    let authority = match port {
+        443 => host.to_string(),
+        _ => format!("{}:{}", host, port),
+    };
+    Ok(Some(
+        format!("https://{}{}", authority, path.unwrap_or("/")).parse()?,
+    ))
+}
+
+/// Whether the URL of `credentials` is a calendar collection itself
+pub async fn is_calendar_collection(credentials: &CalDavCredentials) -> bool {
+    let body = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop>
+    <D:resourcetype/>
+  </D:prop>
+</D:propfind>
+"#;
+    match propfind(credentials, body, 0).await {
+        Ok(root) => find_elems(&root, "resourcetype")
+            .iter()
+            .any(|resourcetype| is_calendar(resourcetype)),
+        Err(err) => {
+            log::debug!(
+                "{} is not a calendar collection: {}",
+                credentials.url(),
+                err
+            );
+            false
+        }
+    }
+}
+
+/// Lists the calendar collections of the user, starting from the server root
+pub async fn discover_calendars(
+    credentials: &CalDavCredentials,
+) -> Result<Vec<CalendarInfo>, Box<dyn Error>> {
+    let context = well_known_context(credentials).await;
+    log::info!("Discovering calendars from {}", context);
+
+    let principal = find_href(
+        &credentials.with_url(context),
+        "current-user-principal",
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:">
+  <D:prop>
+    <D:current-user-principal/>
+  </D:prop>
+</D:propfind>
+"#,
+    )
+    .await?;
+    log::debug!("Principal: {}", principal);
+
+    let home = find_href(
+        &credentials.with_url(principal),
+        "calendar-home-set",
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <D:prop>
+    <C:calendar-home-set/>
+  </D:prop>
+</D:propfind>
+"#,
+    )
+    .await?;
+    log::debug!("Calendar home: {}", home);
+
+    let home_credentials = credentials.with_url(home);
+    let root = propfind(
+        &home_credentials,
+        r#"<?xml version="1.0" encoding="UTF-8" ?>
+<D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav" xmlns:A="http://apple.com/ns/ical/">
+  <D:prop>
+    <D:resourcetype/>
+    <D:displayname/>
+    <A:calendar-color/>
+    <C:supported-calendar-component-set/>
+  </D:prop>
+</D:propfind>
+"#,
+        1,
+    )
+    .await?;
+
+    let mut calendars = Vec::new();
+    for response in find_elems(&root, "response") {
+        let is_event_calendar = find_elems(response, "resourcetype")
+            .iter()
+            .any(|resourcetype| is_calendar(resourcetype))
+            && supports_events(response);
+        if !is_event_calendar {
+            continue;
+        }
+
+        let Some(href) = find_elems(response, "href").first().map(|href| href.text()) else {
+            continue;
+        };
+        let url = match resolve_href(home_credentials.url(), &href) {
+            Ok(url) => url,
+            Err(err) => {
+                log::warn!("Skipping a calendar: {}", err);
+                continue;
+            }
+        };
+        calendars.push(CalendarInfo {
+            url,
+            display_name: non_empty_text(response, "displayname"),
+            color: non_empty_text(response, "calendar-color"),
+        });
+    }
+
+    Ok(calendars)
+}
+
+/// Follows the `/.well-known/caldav` redirection of the server, if any, to find the URL to start
+/// the discovery from
+async fn well_known_context(credentials: &CalDavCredentials) -> Url {
+    let root = credentials.url().clone();
+    let Ok(well_known) = root.join("/.well-known/caldav") else {
+        return root;
+    };
+
+    // Redirections are followed by hand: reqwest would turn the PROPFIND into a GET
+    let client = match reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            log::warn!("Unable to build an HTTP client: {}", err);
+            return root;
+        }
+    };
+
+    let mut url = well_known;
+    for _ in 0..5 {
+        let response = client
+            .request(
+                "PROPFIND".parse().expect("invalid method name"),
+                url.clone(),
+            )
+            .header("Depth", 0)
+            .header(CONTENT_TYPE, "application/xml")
+            .basic_auth(credentials.username(), Some(credentials.password()))
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                log::debug!("No well-known CalDAV URL: {}", err);
+                return root;
+            }
+        };
+
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok());
+            match location {
+                Some(location) if is_same_origin(&location, &root) => url = location,
+                Some(location) => {
+                    // The credentials are only sent to the configured server
+                    log::warn!(
+                        "Not following the redirection of {} to {}, on another server: configure \
+                         that URL to use it",
+                        root,
+                        location
+                    );
+                    return root;
+                }
+                None => return root,
+            }
+        } else if status.is_success() {
+            return url;
+        } else {
+            log::debug!("No well-known CalDAV URL: {}", status);
+            return root;
+        }
+    }
+
+    root
+}
+
+fn is_same_origin(url: &Url, other: &Url) -> bool {
+    url.origin() == other.origin()
+}
+
+/// Resolves a `DAV:href` against the URL it was found at. Hrefs on another server are rejected,
+/// as the credentials are only sent to the configured server.
+fn resolve_href(base: &Url, href: &str) -> Result<Url, Box<dyn Error>> {
+    let url = base.join(href.trim())?;
+    if !is_same_origin(&url, base) {
+        return Err(format!(
+            "{} refers to {}, on another server: configure that URL to use it",
+            base, url
+        )
+        .into());
+    }
+    Ok(url)
+}
+
+async fn propfind(
+    credentials: &CalDavCredentials,
+    body: &str,
+    depth: u32,
+) -> Result<Element, Box<dyn Error>> {
+    let text = sub_request(credentials, "PROPFIND", body.to_string(), depth).await?;
+    Ok(text.parse()?)
+}
+
+/// Requests a property whose value is a `DAV:href`, and resolves it against the request URL
+async fn find_href(
+    credentials: &CalDavCredentials,
+    property: &str,
+    body: &str,
+) -> Result<Url, Box<dyn Error>> {
+    let root = propfind(credentials, body, 0).await?;
+    let href = find_elems(&root, property)
+        .iter()
+        .flat_map(|prop| find_elems(prop, "href"))
+        .map(|href| href.text())
+        .next()
+        .ok_or_else(|| format!("No {} found at {}", property, credentials.url()))?;
+
+    resolve_href(credentials.url(), &href)
+}
+
+fn is_calendar(resourcetype: &Element) -> bool {
+    resourcetype
+        .children()
+        .any(|child| child.name() == "calendar" && child.ns() == "urn:ietf:params:xml:ns:caldav")
+}
+
+/// Whether a collection accepts events. Servers that do not advertise the supported components
+/// accept all of them.
+fn supports_events(response: &Element) -> bool {
+    let components: Vec<String> = find_elems(response, "supported-calendar-component-set")
+        .iter()
+        .flat_map(|set| find_elems(set, "comp"))
+        .filter_map(|comp| comp.attr("name").map(str::to_string))
+        .collect();
+    components.is_empty() || components.iter().any(|name| name == "VEVENT")
+}
+
+fn non_empty_text(root: &Element, name: &str) -> Option<String> {
+    find_elems(root, name)
+        .first()
+        .map(|elem| elem.text().trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_follows_redirections_on_the_same_origin() {
+        let root: Url = "https://example.org/".parse().unwrap();
+        let same = |url: &str| is_same_origin(&url.parse().unwrap(), &root);
+        assert!(same("https://example.org/dav/"));
+        assert!(same("https://example.org:443/remote.php/dav"));
+        assert!(!same("http://example.org/dav/"));
+        assert!(!same("https://example.org:8443/dav/"));
+        assert!(!same("https://dav.example.org/"));
+        assert!(!same("https://example.org.evil.test/"));
+    }
+
+    #[test]
+    fn rejects_hrefs_on_other_servers() {
+        let base: Url = "https://example.org/dav/principals/alice/".parse().unwrap();
+        let resolve = |href| resolve_href(&base, href).map(String::from).ok();
+        assert_eq!(
+            resolve(" /dav/calendars/alice/ "),
+            Some("https://example.org/dav/calendars/alice/".to_string())
+        );
+        assert_eq!(
+            resolve("https://example.org/dav/calendars/alice/"),
+            Some("https://example.org/dav/calendars/alice/".to_string())
+        );
+        assert_eq!(
+            resolve("../../calendars/alice/work/"),
+            Some("https://example.org/dav/calendars/alice/work/".to_string())
+        );
+        assert_eq!(resolve("https://evil.test/dav/calendars/alice/"), None);
+        assert_eq!(resolve("//evil.test/dav/"), None);
+        assert_eq!(resolve("http://example.org/dav/"), None);
+    }
+
+    #[test]
+    fn reads_service_records() {
+        assert_eq!(user_domain("alice@example.com"), Some("example.com"));
+        assert_eq!(user_domain("https://alice@example.com/dav/"), None);
+        assert_eq!(user_domain("https://example.com/"), None);
+
+        let url = |target, port, path| service_url(target, port, path).unwrap().map(String::from);
+        assert_eq!(
+            url("dav.example.com.", 443, None),
+            Some("https://dav.example.com/".to_string())
+        );
+        assert_eq!(
+            url("dav.example.com.", 8443, Some("/caldav/")),
+            Some("https://dav.example.com:8443/caldav/".to_string())
+        );
+        assert_eq!(url(".", 0, None), None);
+
+        assert_eq!(context_path("path=/caldav/"), Some("/caldav/".to_string()));
+        assert_eq!(context_path("path=caldav"), None);
+        assert_eq!(context_path("v=spf1"), None);
+    }
+
+    #[test]
+    fn only_uses_services_inside_the_domain() {
+        let inside = |url: &str, domain| is_in_domain(&url.parse().unwrap(), domain);
+        assert!(inside("https://example.com/", "example.com"));
+        assert!(inside(
+            "https://dav.example.com:8443/caldav/",
+            "example.com"
+        ));
+        assert!(inside("https://DAV.Example.com/", "example.COM."));
+        assert!(!inside("https://dav.example.org/", "example.com"));
+        assert!(!inside("https://evilexample.com/", "example.com"));
+        assert!(!inside("https://example.com.evil.test/", "example.com"));
+        assert!(!inside(
+            "https://dav.example.com/",
+            "dav.example.com.evil.test"
+        ));
+    }
+}
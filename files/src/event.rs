@@ -1,10 +1,13 @@
 //! Calendar events (iCal `VEVENT` items)
 
-use chrono::{DateTime, NaiveDate, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use url::Url;
 
+use crate::recurrence::Recurrence;
+use crate::timezone::EventTimeZone;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum EventTime {
     Date(NaiveDate),
@@ -25,6 +28,14 @@ impl EventTime {
     //         _ => None,
     //     }
     // }
+
+    /// The naive UTC value of this time, dates being considered as starting at midnight
+    pub fn naive_utc(&self) -> NaiveDateTime {
+        match self {
+            EventTime::Date(date) => date.and_time(Default::default()),
+            EventTime::DateTime(datetime) => datetime.naive_utc(),
+        }
+    }
 }
 
 impl Ord for EventTime {
@@ -60,6 +71,101 @@ impl PartialEq for EventTime {
 
 impl Eq for EventTime {}
 
+/// The `STATUS` of an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventStatus {
+    Tentative,
+    Confirmed,
+    Cancelled,
+}
+
+impl EventStatus {
+    pub fn from_ical(value: &str) -> Option<Self> {
+        match value {
+            "TENTATIVE" => Some(EventStatus::Tentative),
+            "CONFIRMED" => Some(EventStatus::Confirmed),
+            "CANCELLED" => Some(EventStatus::Cancelled),
+            _ => None,
+        }
+    }
+
+    pub fn to_ical(self) -> &'static str {
+        match self {
+            EventStatus::Tentative => "TENTATIVE",
+            EventStatus::Confirmed => "CONFIRMED",
+            EventStatus::Cancelled => "CANCELLED",
+        }
+    }
+}
+
+/// When a `VALARM` of an event triggers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Alarm {
+    /// An offset in seconds from the start of the event, or from its end, negative when before
+    Relative {
+        seconds: i64,
+        from_end: bool,
+    },
+    At(DateTime<Utc>),
+}
+
+impl Alarm {
+    /// The time this alarm triggers for an occurrence from `start` to `end`, or `None` when
+    /// that is out of range
+    pub fn trigger(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Alarm::Relative { seconds, from_end } => {
+                let base = if *from_end { end } else { start };
+                base.checked_add_signed(Duration::try_seconds(*seconds)?)
+            }
+            Alarm::At(time) => Some(*time),
+        }
+    }
+}
+
+/// The calendar an event comes from, as shown next to it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalendarLabel {
+    name: String,
+    /// A `#rrggbb` color
+    color: Option<String>,
+}
+
+impl CalendarLabel {
+    /// Builds a label. Colors are normalized to `#rrggbb`, as CalDAV servers commonly report
+    /// them with an alpha channel (`#rrggbbaa`), and invalid ones are dropped.
+    pub fn new(name: String, color: Option<&str>) -> Self {
+        let color = color
+            .map(str::trim)
+            .filter(|color| {
+                color.starts_with('#')
+                    && (color.len() == 7 || color.len() == 9)
+                    && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .map(|color| color[..7].to_ascii_lowercase());
+        Self { name, color }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn color(&self) -> Option<&str> {
+        self.color.as_deref()
+    }
+}
+
+/// The property that [`Event::last_modified`] was read from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LastModifiedSource {
+    #[default]
+    LastModified,
+    DtStamp,
+    Created,
+    /// The event carries no timestamp, so the time it was fetched is used instead
+    FetchTime,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Event {
     uid: String,
@@ -69,11 +175,28 @@ pub struct Event {
     location: Option<String>,
     description: Option<String>,
     last_modified: DateTime<Utc>,
+    last_modified_source: LastModifiedSource,
     creation_date: Option<DateTime<Utc>>,
+    status: Option<EventStatus>,
+    /// The URL of the calendar object resource holding this event
     url: Url,
+    /// The ETag of that resource, when known
+    etag: Option<String>,
+    calendar: Option<CalendarLabel>,
+    /// The zone of `dtstart`, in which recurrences are expanded
+    timezone: Option<EventTimeZone>,
+    recurrence: Option<Recurrence>,
+    /// The original start of this occurrence, for events expanded from a recurring series
+    recurrence_id: Option<EventTime>,
+    #[serde(default)]
+    alarms: Vec<Alarm>,
+    /// The `URL` of the event, such as its page or the link to join it
+    #[serde(default)]
+    link: Option<Url>,
 }
 
 impl Event {
+    #[allow(clippy::too_many_arguments)]
     pub fn new_timed(
         name: String,
         uid: String,
@@ -93,11 +216,21 @@ impl Event {
             location,
             description,
             last_modified,
+            last_modified_source: LastModifiedSource::default(),
             creation_date,
+            status: None,
             url,
+            etag: None,
+            calendar: None,
+            timezone: None,
+            recurrence: None,
+            recurrence_id: None,
+            alarms: Vec::new(),
+            link: None,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_all_day(
         name: String,
         uid: String,
@@ -117,18 +250,35 @@ impl Event {
             location,
             description,
             last_modified,
+            last_modified_source: LastModifiedSource::default(),
             creation_date,
+            status: None,
             url,
+            etag: None,
+            calendar: None,
+            timezone: None,
+            recurrence: None,
+            recurrence_id: None,
+            alarms: Vec::new(),
+            link: None,
         }
     }
 
-    // pub fn url(&self) -> &Url {
-    //     &self.url
-    // }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
 
-    // pub fn uid(&self) -> &str {
-    //     &self.uid
-    // }
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_deref()
+    }
+
+    pub fn set_etag(&mut self, etag: Option<String>) {
+        self.etag = etag;
+    }
+
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
 
     pub fn name(&self) -> &str {
         &self.name
@@ -142,21 +292,152 @@ impl Event {
         &self.dtend
     }
 
-    // pub fn location(&self) -> Option<&String> {
-    //     self.location.as_ref()
-    // }
+    pub fn calendar(&self) -> Option<&CalendarLabel> {
+        self.calendar.as_ref()
+    }
 
-    // pub fn description(&self) -> Option<&String> {
-    //     self.description.as_ref()
-    // }
+    pub fn set_calendar(&mut self, calendar: CalendarLabel) {
+        self.calendar = Some(calendar);
+    }
 
-    // pub fn last_modified(&self) -> &DateTime<Utc> {
-    //     &self.last_modified
-    // }
+    pub fn set_last_modified_source(&mut self, source: LastModifiedSource) {
+        self.last_modified_source = source;
+    }
 
-    // pub fn creation_date(&self) -> Option<&DateTime<Utc>> {
-    //     self.creation_date.as_ref()
-    // }
+    pub fn set_status(&mut self, status: EventStatus) {
+        self.status = Some(status);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.status == Some(EventStatus::Cancelled)
+    }
+
+    /// Marks this event as an override of the instance of a series that started at
+    /// `recurrence_id`
+    pub fn set_recurrence_id(&mut self, recurrence_id: EventTime) {
+        self.recurrence_id = Some(recurrence_id);
+    }
+
+    /// The original start of this occurrence, if it belongs to a series
+    pub fn recurrence_id(&self) -> Option<&EventTime> {
+        self.recurrence_id.as_ref()
+    }
+
+    pub fn alarms(&self) -> &[Alarm] {
+        &self.alarms
+    }
+
+    pub fn set_alarms(&mut self, alarms: Vec<Alarm>) {
+        self.alarms = alarms;
+    }
+
+    pub fn set_timezone(&mut self, timezone: EventTimeZone) {
+        self.timezone = Some(timezone);
+    }
+
+    pub fn set_recurrence(&mut self, recurrence: Recurrence) {
+        self.recurrence = Some(recurrence).filter(|recurrence| !recurrence.is_empty());
+    }
+
+    /// Returns the occurrences of this event that overlap the `start..end` window.
+    ///
+    /// A recurring event is expanded into one event per occurrence, each keeping the series UID
+    /// and recording its original start as its recurrence ID. Recurrences are computed in the
+    /// zone of the event, so that they keep the same wall clock time across DST transitions.
+    pub fn occurrences(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Vec<Event> {
+        let start = start.naive_utc();
+        let end = end.naive_utc();
+
+        let Some(recurrence) = &self.recurrence else {
+            return if self.overlaps(start, end) {
+                vec![self.clone()]
+            } else {
+                Vec::new()
+            };
+        };
+
+        let dtstart = self.to_local(&self.dtstart);
+        let duration = self.to_local(&self.dtend) - dtstart;
+        // Local times may be up to a day ahead of UTC
+        let local_end = end + Duration::days(1);
+
+        recurrence
+            .occurrence_starts(dtstart, local_end, |time| self.to_local(time))
+            .into_iter()
+            .map(|occurrence_start| self.occurrence_at(occurrence_start, duration))
+            .filter(|occurrence| occurrence.overlaps(start, end))
+            .collect()
+    }
+
+    /// The wall clock value of a time in the zone of this event
+    fn to_local(&self, time: &EventTime) -> NaiveDateTime {
+        match (time, &self.timezone) {
+            (EventTime::DateTime(datetime), Some(timezone)) => timezone.to_local(datetime),
+            _ => time.naive_utc(),
+        }
+    }
+
+    fn to_utc(&self, local: &NaiveDateTime) -> DateTime<Utc> {
+        match &self.timezone {
+            Some(timezone) => timezone.to_utc(local),
+            None => local.and_utc(),
+        }
+    }
+
+    fn occurrence_at(&self, start: NaiveDateTime, duration: Duration) -> Event {
+        let (dtstart, dtend) = match self.dtstart {
+            EventTime::Date(_) => (
+                EventTime::Date(start.date()),
+                EventTime::Date((start + duration).date()),
+            ),
+            EventTime::DateTime(_) => (
+                EventTime::DateTime(self.to_utc(&start)),
+                EventTime::DateTime(self.to_utc(&(start + duration))),
+            ),
+        };
+
+        Event {
+            recurrence_id: Some(dtstart.clone()),
+            dtstart,
+            dtend,
+            recurrence: None,
+            ..self.clone()
+        }
+    }
+
+    fn overlaps(&self, start: NaiveDateTime, end: NaiveDateTime) -> bool {
+        let dtstart = self.dtstart.naive_utc();
+        let dtend = self.dtend.naive_utc();
+        dtstart < end && (dtend > start || (dtstart == dtend && dtstart >= start))
+    }
+
+    pub fn location(&self) -> Option<&String> {
+        self.location.as_ref()
+    }
+
+    pub fn description(&self) -> Option<&String> {
+        self.description.as_ref()
+    }
+
+    pub fn link(&self) -> Option<&Url> {
+        self.link.as_ref()
+    }
+
+    pub fn set_link(&mut self, link: Url) {
+        self.link = Some(link);
+    }
+
+    pub fn last_modified(&self) -> &DateTime<Utc> {
+        &self.last_modified
+    }
+
+    pub fn creation_date(&self) -> Option<&DateTime<Utc>> {
+        self.creation_date.as_ref()
+    }
+
+    pub fn status(&self) -> Option<EventStatus> {
+        self.status
+    }
 
     // #[cfg(any(test, feature = "integration_tests"))]
     // pub fn has_same_observable_content_as(&self, other: &Event) -> bool {
@@ -170,9 +451,83 @@ impl Event {
     // }
 }
 
+/// A calendar object resource: a (possibly recurring) master event, and the instances of it that
+/// were moved or cancelled (`VEVENT`s with a `RECURRENCE-ID`)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Series {
+    master: Option<Event>,
+    overrides: Vec<Event>,
+}
+
+impl Series {
+    pub fn new(master: Option<Event>, overrides: Vec<Event>) -> Self {
+        Self { master, overrides }
+    }
+
+    pub fn uid(&self) -> Option<&str> {
+        self.master
+            .iter()
+            .chain(self.overrides.iter())
+            .map(|event| event.uid.as_str())
+            .next()
+    }
+
+    pub fn master(&self) -> Option<&Event> {
+        self.master.as_ref()
+    }
+
+    /// Whether this series has several instances, rather than being a single event
+    pub fn is_recurring(&self) -> bool {
+        self.master
+            .as_ref()
+            .is_some_and(|master| master.recurrence.is_some())
+            || !self.overrides.is_empty()
+    }
+
+    /// Returns a UID of this series that differs from the others, if any
+    pub fn inconsistent_uid(&self) -> Option<&str> {
+        let mut events = self.master.iter().chain(self.overrides.iter());
+        let uid = &events.next()?.uid;
+        events
+            .map(|event| event.uid.as_str())
+            .find(|other| other != uid)
+    }
+
+    /// Returns the occurrences of this series that overlap the `start..end` window, with
+    /// overridden instances replaced by their override and cancelled instances left out
+    pub fn occurrences(&self, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Vec<Event> {
+        let mut occurrences = match &self.master {
+            Some(master) => master.occurrences(start, end),
+            None => Vec::new(),
+        };
+
+        occurrences.retain(|occurrence| {
+            !self
+                .overrides
+                .iter()
+                .any(|overridden| overridden.recurrence_id == occurrence.recurrence_id)
+        });
+
+        occurrences.extend(
+            self.overrides
+                .iter()
+                .filter(|overridden| overridden.overlaps(start.naive_utc(), end.naive_utc()))
+                .cloned(),
+        );
+
+        occurrences.retain(|occurrence| !occurrence.is_cancelled());
+        occurrences
+    }
+}
+
+/// Events are ordered by start, then by identity, so that occurrences of a series that start
+/// together still differ
 impl Ord for Event {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.dtstart.cmp(&other.dtstart)
+        self.dtstart
+            .cmp(&other.dtstart)
+            .then_with(|| self.uid.cmp(&other.uid))
+            .then_with(|| self.recurrence_id.cmp(&other.recurrence_id))
     }
 }
 
@@ -184,8 +539,70 @@ impl PartialOrd for Event {
 
 impl PartialEq for Event {
     fn eq(&self, other: &Self) -> bool {
-        self.uid == other.uid
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for Event {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recurrence::RecurrenceRule;
+    use chrono::TimeZone;
+
+    fn event(uid: &str) -> Event {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        Event::new_timed(
+            "Meeting".to_string(),
+            uid.to_string(),
+            start,
+            start + Duration::hours(1),
+            None,
+            None,
+            Url::parse("https://example.org/cal/event.ics").unwrap(),
+            start,
+            None,
+        )
+    }
+
+    #[test]
+    fn orders_events_consistently_with_equality() {
+        let master = event("abc");
+        let mut occurrence = event("abc");
+        occurrence.set_recurrence_id(occurrence.dtstart().clone());
+        let mut moved = event("abc");
+        moved.dtstart = EventTime::DateTime(Utc.with_ymd_and_hms(2024, 11, 5, 9, 0, 0).unwrap());
+
+        assert_eq!(master, event("abc"));
+        assert_eq!(master.cmp(&event("abc")), Ordering::Equal);
+        for other in [&occurrence, &moved, &event("def")] {
+            assert_ne!(&master, other);
+            assert_ne!(master.cmp(other), Ordering::Equal);
+        }
+        assert!(master < occurrence && master < event("def") && event("def") < moved);
+    }
+
+    #[test]
+    fn tells_series_from_single_events() {
+        assert!(!Series::new(Some(event("abc")), Vec::new()).is_recurring());
+
+        let mut weekly = event("abc");
+        let mut recurrence = Recurrence::default();
+        recurrence.add_rule(RecurrenceRule::parse("FREQ=WEEKLY", None).unwrap());
+        weekly.set_recurrence(recurrence);
+        assert!(Series::new(Some(weekly), Vec::new()).is_recurring());
+
+        let mut rdate = event("abc");
+        let mut recurrence = Recurrence::default();
+        recurrence.add_rdate(EventTime::DateTime(
+            Utc.with_ymd_and_hms(2024, 11, 11, 18, 0, 0).unwrap(),
+        ));
+        rdate.set_recurrence(recurrence);
+        assert!(Series::new(Some(rdate), Vec::new()).is_recurring());
+
+        let mut occurrence = event("abc");
+        occurrence.set_recurrence_id(occurrence.dtstart().clone());
+        assert!(Series::new(None, vec![occurrence]).is_recurring());
+    }
+}
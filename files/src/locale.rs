@@ -0,0 +1,459 @@
+//! Languages of the messages posted to rooms: how dates and times are written, and the wording
+//! of the bot, read from message catalogs.
+//!
+//! The built-in catalogs are the TOML files of `locales/`, one per language. More languages can
+//! be added, and the wording of the built-in ones changed, with files of the same form in the
+//! configured `locales_dir`. Entries missing from a catalog fall back to English.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Weekday};
+use chrono_tz::Tz;
+use pure_rust_locales::locale_match;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::event::EventTime;
+
+/// The language whose catalog holds every message
+const FALLBACK_LANGUAGE: &str = "en";
+
+const BUILT_IN_CATALOGS: [(&str, &str); 3] = [
+    ("en", include_str!("../locales/en.toml")),
+    ("de", include_str!("../locales/de.toml")),
+    ("fr", include_str!("../locales/fr.toml")),
+];
+
+/// The messages of a language, by key
+type Catalog = HashMap<String, String>;
+
+/// The message catalogs of every language, each completed with the English messages
+#[derive(Clone, Debug)]
+pub struct Catalogs {
+    languages: HashMap<String, Arc<Catalog>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    TwelveHour,
+    TwentyFourHour,
+}
+
+/// The order of the day, month and year in dates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DateOrder {
+    DayMonthYear,
+    MonthDayYear,
+    YearMonthDay,
+}
+
+/// A message of the catalogs along with its values, written in the language of a room once it
+/// is known, such as the reason a command is invalid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Phrase {
+    key: &'static str,
+    values: Vec<(&'static str, String)>,
+}
+
+/// The language and conventions used in the messages posted to a room
+#[derive(Clone, Debug)]
+pub struct Locale {
+    name: String,
+    locale: chrono::Locale,
+    messages: Arc<Catalog>,
+    pub clock: Clock,
+    pub date_order: DateOrder,
+    pub first_weekday: Weekday,
+}
+
+impl Catalogs {
+    /// Reads the built-in catalogs, and those of `dir` over them
+    pub fn load(dir: Option<&Path>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut catalogs: HashMap<String, Catalog> = BUILT_IN_CATALOGS
+            .iter()
+            .map(|(language, catalog)| {
+                let catalog = toml::from_str(catalog).expect("valid built-in catalog");
+                (language.to_string(), catalog)
+            })
+            .collect();
+
+        if let Some(dir) = dir {
+            let files = fs::read_dir(dir)
+                .map_err(|err| vec![format!("unable to read {}: {}", dir.display(), err)])?;
+            for path in files.filter_map(|file| Some(file.ok()?.path())) {
+                if path.extension().is_none_or(|extension| extension != "toml") {
+                    continue;
+                }
+                let Some(language) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let catalog: Catalog = match fs::read_to_string(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|data| toml::from_str(&data).map_err(|err| err.to_string()))
+                {
+                    Ok(catalog) => catalog,
+                    Err(err) => {
+                        errors.push(format!("unable to read {}: {}", path.display(), err));
+                        continue;
+                    }
+                };
+                catalogs
+                    .entry(language.to_string())
+                    .or_default()
+                    .extend(catalog);
+            }
+        }
+
+        let fallback = catalogs[FALLBACK_LANGUAGE].clone();
+        let mut languages = HashMap::new();
+        for (language, catalog) in catalogs {
+            for key in catalog.keys() {
+                if !fallback.contains_key(key) {
+                    errors.push(format!("unknown message {} in catalog {}", key, language));
+                }
+            }
+            let mut messages = fallback.clone();
+            messages.extend(catalog);
+            languages.insert(language, Arc::new(messages));
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self { languages })
+    }
+}
+
+impl Phrase {
+    pub fn new(key: &'static str) -> Self {
+        Self {
+            key,
+            values: Vec::new(),
+        }
+    }
+
+    /// Sets the value replacing `{name}` in the message
+    pub fn with(mut self, name: &'static str, value: impl Display) -> Self {
+        self.values.push((name, value.to_string()));
+        self
+    }
+}
+
+impl Locale {
+    /// The locale named like `de_DE`, with the conventions of its region and the messages of its
+    /// language
+    pub fn new(name: &str, catalogs: &Catalogs) -> Result<Self, String> {
+        let locale = chrono::Locale::try_from(name)
+            .map_err(|_| format!("unknown locale {}, expected e.g. \"de_DE\"", name))?;
+        let language = name.split(['_', '.', '@']).next().unwrap_or(name);
+        let messages = catalogs
+            .languages
+            .get(language)
+            .ok_or_else(|| format!("no message catalog for the language of {}", name))?
+            .clone();
+
+        let time_format = locale_match!(locale => LC_TIME::T_FMT);
+        let clock = if ["%r", "%I", "%l"]
+            .iter()
+            .any(|item| time_format.contains(item))
+        {
+            Clock::TwelveHour
+        } else {
+            Clock::TwentyFourHour
+        };
+
+        let date_format = locale_match!(locale => LC_TIME::D_FMT);
+        let position = |items: &[&str]| {
+            items
+                .iter()
+                .filter_map(|item| date_format.find(item))
+                .min()
+                .unwrap_or(usize::MAX)
+        };
+        let day = position(&["%d", "%e"]);
+        let month = position(&["%m", "%b", "%B", "%h"]);
+        let year = position(&["%Y", "%y", "%C"]);
+        let date_order = if year < month && month < day {
+            DateOrder::YearMonthDay
+        } else if month < day {
+            DateOrder::MonthDayYear
+        } else {
+            DateOrder::DayMonthYear
+        };
+
+        // Days are numbered from 1, starting on the day of the week of the date in WEEK
+        let week_start = locale_match!(locale => LC_TIME::WEEK)
+            .and_then(|week| NaiveDate::parse_from_str(&week.get(1)?.to_string(), "%Y%m%d").ok())
+            .map_or(Weekday::Sun, |date| date.weekday());
+        let first_day = locale_match!(locale => LC_TIME::FIRST_WEEKDAY).unwrap_or(1);
+        let first_weekday = (1..first_day).fold(week_start, |weekday, _| weekday.succ());
+
+        Ok(Self {
+            name: name.to_string(),
+            locale,
+            messages,
+            clock,
+            date_order,
+            first_weekday,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The messages of the catalog, for templates
+    pub fn messages(&self) -> &HashMap<String, String> {
+        &self.messages
+    }
+
+    /// The message `key` of the catalog, with the names in braces replaced by `values`
+    pub fn message(&self, key: &str, values: &[(&str, &dyn Display)]) -> String {
+        let Some(message) = self.messages.get(key) else {
+            log::error!("No message {} in the catalog of {}", key, self.name);
+            return key.to_string();
+        };
+        // A single pass, so that braces in the values are left as they are
+        let mut text = String::with_capacity(message.len());
+        let mut rest = message.as_str();
+        while let Some(open) = rest.find('{') {
+            text.push_str(&rest[..open]);
+            let placeholder = &rest[open..];
+            let value = placeholder.find('}').and_then(|close| {
+                let name = &placeholder[1..close];
+                let (_, value) = values.iter().find(|(key, _)| *key == name)?;
+                Some((value, close))
+            });
+            match value {
+                Some((value, close)) => {
+                    text.push_str(&value.to_string());
+                    rest = &placeholder[close + 1..];
+                }
+                None => {
+                    text.push('{');
+                    rest = &placeholder[1..];
+                }
+            }
+        }
+        text.push_str(rest);
+        text
+    }
+
+    /// Writes a phrase in the language of this locale
+    pub fn phrase(&self, phrase: &Phrase) -> String {
+        let values: Vec<(&str, &dyn Display)> = phrase
+            .values
+            .iter()
+            .map(|(name, value)| (*name, value as &dyn Display))
+            .collect();
+        self.message(phrase.key, &values)
+    }
+
+    /// A date with the name of its day, such as "Tuesday, 1 January 2030"
+    pub fn format_date(&self, date: &NaiveDate) -> String {
+        self.message(
+            "weekday_date",
+            &[
+                ("weekday", &self.format_weekday(date)),
+                ("date", &self.format_short_date(date)),
+            ],
+        )
+    }
+
+    /// A date without the name of its day, such as "1 January 2030"
+    pub fn format_short_date(&self, date: &NaiveDate) -> String {
+        date.format_localized(&self.short_date_format(), self.locale)
+            .to_string()
+    }
+
+    /// The chrono format of dates without the name of their day, with the day of the month
+    /// written as the catalog says, such as "1." in German
+    fn short_date_format(&self) -> String {
+        let day = self.message("day_number", &[("day", &"%-d")]);
+        match self.date_order {
+            DateOrder::DayMonthYear => format!("{} %B %Y", day),
+            DateOrder::MonthDayYear => format!("%B {}, %Y", day),
+            DateOrder::YearMonthDay => "%Y-%m-%d".to_string(),
+        }
+    }
+
+    /// The name of the day of the week of a date
+    pub fn format_weekday(&self, date: &NaiveDate) -> String {
+        date.format_localized("%A", self.locale).to_string()
+    }
+
+    /// The time of day on the clock of this locale, such as "6:30 PM" or "18:30"
+    pub fn format_time<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> String
+    where
+        Tz::Offset: Display,
+    {
+        let format = match self.clock {
+            Clock::TwelveHour => "%-I:%M %p",
+            Clock::TwentyFourHour => "%H:%M",
+        };
+        time.format_localized(format, self.locale).to_string()
+    }
+
+    /// A time of day along with its date, such as "6:30 PM Tuesday, 1 January 2030"
+    pub fn format_datetime<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> String
+    where
+        Tz::Offset: Display,
+    {
+        format!(
+            "{} {}",
+            self.format_time(time),
+            self.format_date(&time.date_naive())
+        )
+    }
+
+    /// The start and end of an event as seen in `timezone`, such as
+    /// "6:30 PM – 8:00 PM Tuesday, 1 January 2030"
+    pub fn format_event_times(&self, start: &EventTime, end: &EventTime, timezone: &Tz) -> String {
+        match (start, end) {
+            (EventTime::Date(start_date), EventTime::Date(end_date)) => {
+                if *start_date == *end_date - Duration::days(1) {
+                    format!(
+                        "{} – {}",
+                        self.format_date(start_date),
+                        self.message("all_day", &[])
+                    )
+                } else {
+                    format!(
+                        "{} – {}",
+                        self.format_date(start_date),
+                        self.format_date(end_date)
+                    )
+                }
+            }
+            (EventTime::DateTime(start), EventTime::DateTime(end)) => {
+                let start = start.with_timezone(timezone);
+                let end = end.with_timezone(timezone);
+                if start.date_naive() == end.date_naive() {
+                    format!(
+                        "{} – {}",
+                        self.format_time(&start),
+                        self.format_datetime(&end)
+                    )
+                } else {
+                    format!(
+                        "{} – {}",
+                        self.format_datetime(&start),
+                        self.format_datetime(&end)
+                    )
+                }
+            }
+            (EventTime::Date(_), EventTime::DateTime(_))
+            | (EventTime::DateTime(_), EventTime::Date(_)) => self.message("invalid_times", &[]),
+        }
+    }
+
+    /// Describes a day relative to `today`: today, tomorrow or yesterday, the name of the day
+    /// within the coming week, or else the date
+    pub fn relative_day(&self, date: &NaiveDate, today: &NaiveDate) -> String {
+        match (*date - *today).num_days() {
+            -1 => self.message("yesterday", &[]),
+            0 => self.message("today", &[]),
+            1 => self.message("tomorrow", &[]),
+            2..=6 => self.format_weekday(date),
+            _ => self.format_date(date),
+        }
+    }
+
+    /// A duration such as "1 day", "2 hours" or "1 hour 30 minutes"
+    pub fn format_duration(&self, duration: Duration) -> String {
+        let units = [
+            (duration.num_days(), "days"),
+            (duration.num_hours() % 24, "hours"),
+            (duration.num_minutes() % 60, "minutes"),
+        ];
+        let parts: Vec<String> = units
+            .iter()
+            .filter(|(amount, _)| *amount > 0)
+            .map(|(amount, unit)| match amount {
+                1 => self.message(&format!("{}_one", unit), &[]),
+                _ => self.message(&format!("{}_other", unit), &[("count", amount)]),
+            })
+            .collect();
+        if parts.is_empty() {
+            self.message("less_than_a_minute", &[])
+        } else {
+            parts.join(" ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names in braces of a message
+    fn placeholders(message: &str) -> Vec<&str> {
+        let mut names: Vec<&str> = message
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn built_in_catalogs_are_complete() {
+        let english: Catalog = toml::from_str(BUILT_IN_CATALOGS[0].1).unwrap();
+        for (language, catalog) in BUILT_IN_CATALOGS {
+            let catalog: Catalog = toml::from_str(catalog).unwrap();
+            for (key, message) in &english {
+                let translation = catalog
+                    .get(key)
+                    .unwrap_or_else(|| panic!("{} is missing from catalog {}", key, language));
+                assert_eq!(
+                    placeholders(message),
+                    placeholders(translation),
+                    "{} in catalog {}",
+                    key,
+                    language
+                );
+            }
+        }
+        assert!(Catalogs::load(None).is_ok());
+    }
+
+    #[test]
+    fn writes_phrases_in_the_language_of_the_room() {
+        let catalogs = Catalogs::load(None).unwrap();
+        let phrase = Phrase::new("invalid_date").with("date", "2024-13-01");
+        let english = Locale::new("en_US", &catalogs).unwrap();
+        let german = Locale::new("de_DE", &catalogs).unwrap();
+        assert_eq!(
+            english.phrase(&phrase),
+            "Invalid date 2024-13-01, expected e.g. 2024-11-04"
+        );
+        assert_eq!(
+            german.phrase(&phrase),
+            "Ungültiges Datum 2024-13-01, erwartet z. B. 2024-11-04"
+        );
+        assert!(german.message("usage", &[]).starts_with("Verwendung: !cal"));
+    }
+
+    #[test]
+    fn leaves_placeholders_in_values_as_they_are() {
+        let catalogs = Catalogs::load(None).unwrap();
+        let english = Locale::new("en_US", &catalogs).unwrap();
+        let message = english.message(
+            "added",
+            &[
+                ("name", &"{uid}"),
+                ("calendar", &"{name} {missing"),
+                ("times", &"today"),
+                ("uid", &"abc"),
+                ("url", &"https://dav.example/abc.ics"),
+            ],
+        );
+        assert_eq!(
+            message,
+            "Added {uid} to {name} {missing: today\nUID: abc\nhttps://dav.example/abc.ics"
+        );
+    }
+}
@@ -1,25 +1,59 @@
-use chrono::{Datelike, Duration, Utc, Weekday};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
 use dotenv::dotenv;
 use matrix_sdk::{
+    event_handler::Ctx,
     ruma::{
         events::room::message::{
             MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
         },
-        RoomId,
+        OwnedRoomId, UserId,
     },
     Client, Room, RoomState,
 };
-use std::{env, sync::Arc};
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::RwLock,
+    task::JoinHandle,
+};
+use url::Url;
 
+mod cache;
 mod cal;
-use cal::{get_calendar_events, CalDavCredentials};
+use cal::{PreconditionFailed, Resource};
+mod calendars;
+use calendars::{get_agenda, Calendar, Calendars, SourceCalendars};
+mod changes;
+mod command;
+use command::{Command, EventChanges, EventRef, NewEvent, Period};
+mod config;
+use config::{Config, MessageTemplate, RoomConfig};
+mod digests;
+mod discovery;
 mod event;
-use event::EventTime;
+use event::{Event, Series};
+mod locale;
+use locale::Locale;
 mod matrix;
 mod parser;
-use matrix::{login, restore_session, sync, MatrixCredentials};
-use std::time::Duration as StdDuration;
-use tokio::time::{interval_at, Instant};
+mod pinned_agenda;
+mod recurrence;
+mod reminders;
+mod render;
+use render::Message;
+mod schedule;
+mod serializer;
+mod store;
+use store::EventStore;
+mod template;
+mod timezone;
+use matrix::{login, restore_session, sync};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -27,19 +61,27 @@ async fn main() -> anyhow::Result<()> {
 
     // Load the environment variables from a .env file
     dotenv().ok();
-    let matrix_credentials = MatrixCredentials {
-        homeserver: env::var("MATRIX_SERVER_URL").expect("MATRIX_SERVER_URL must be set"),
-        username: env::var("MATRIX_BOT_USERNAME").expect("MATRIX_BOT_USERNAME must be set"),
-        password: env::var("MATRIX_BOT_PASSWORD").expect("MATRIX_BOT_PASSWORD must be set"),
+    let config_path =
+        PathBuf::from(env::var("CONFIG_FILE").unwrap_or_else(|_| "config.toml".to_string()));
+    let config = Config::load(&config_path).map_err(anyhow::Error::msg)?;
+    let matrix_credentials = config.matrix.clone();
+
+    // The folder containing persisted Matrix data and the event store
+    let data_dir = match env::var_os("DATA_DIR") {
+        Some(data_dir) => PathBuf::from(data_dir),
+        None => dirs::data_dir()
+            .expect("no data_dir directory found")
+            .join("persist_session"),
     };
+    // The events of the calendars, kept across restarts
+    let store = Arc::new(
+        EventStore::open(&data_dir.join("calendars.sqlite3"))
+            .map_err(|err| anyhow::anyhow!("Unable to open the event store: {}", err))?,
+    );
+    let state = Arc::new(State::new(config, store));
 
-    // dry run to make sure env variables are set correctly
-    get_events_message().await;
-
-    // The folder containing persisted Matrix data
-    let data_dir = dirs::data_dir()
-        .expect("no data_dir directory found")
-        .join("persist_session");
+    // dry run to make sure the calendars are set correctly
+    state.calendars.get(|_| true).await.log_errors();
     // The file where the session is persisted
     let session_file = data_dir.join("session");
 
@@ -54,71 +96,76 @@ async fn main() -> anyhow::Result<()> {
 
     let client = &Arc::new(client);
 
-    let room_ids = get_room_ids();
-    for id in room_ids {
-        let client_clone = Arc::clone(&client);
-        RoomId::parse(&id).expect("MATRIX_ROOM_IDS must be a valid room ID");
-        tokio::spawn(post_weekly_message(client_clone, id.clone()));
-    }
-
-    sync(client.clone(), sync_token, &session_file, on_room_message)
-        .await
-        .map_err(Into::into)
-}
-
-fn format_datetime(datetime: &EventTime) -> String {
-    match datetime {
-        EventTime::Date(date) => date.format("%A, %-d %B, %C%y").to_string(),
-        EventTime::DateTime(datetime) => datetime.format("%-I:%M %p %A, %-d %B, %C%y").to_string(),
-    }
-}
-
-fn format_event_times(start: &EventTime, end: &EventTime) -> String {
-    match (start, end) {
-        (EventTime::Date(start_date), EventTime::Date(end_date)) => {
-            if *start_date == *end_date - Duration::days(1) {
-                format!("{} – All Day", format_datetime(start))
-            } else {
-                format!("{} – {}\n", format_datetime(start), format_datetime(end))
-            }
-        }
-        (EventTime::DateTime(start_datetime), EventTime::DateTime(end_datetime)) => {
-            if start_datetime.date_naive() == end_datetime.date_naive() {
-                format!(
-                    "{} – {}\n",
-                    start_datetime.format("%-I:%M %p").to_string(),
-                    format_datetime(end)
-                )
-            } else {
-                format!("{} – {}\n", format_datetime(start), format_datetime(end))
-            }
-        }
-        (EventTime::Date(_), EventTime::DateTime(_))
-        | (EventTime::DateTime(_), EventTime::Date(_)) => {
-            "Invalid Date: Check Calendar".to_string()
-        }
-    }
+    let tasks = spawn_room_tasks(client, &state);
+    let shared_state = Arc::new(RwLock::new(state));
+    tokio::spawn(reload_on_hangup(
+        Arc::clone(client),
+        config_path,
+        shared_state.clone(),
+        tasks,
+    ));
+
+    client.add_event_handler_context(shared_state);
+    sync(client.clone(), sync_token, &session_file, on_room_message).await
 }
 
 /// Handle room messages.
-async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
+async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    Ctx(shared_state): Ctx<SharedState>,
+) {
     // We only want to log text messages in joined rooms.
-    if room.state() != RoomState::Joined
-        || !get_room_ids()
-            .iter()
-            .any(|id| id == room.room_id().as_str())
-    {
+    if room.state() != RoomState::Joined {
         return;
     }
+    let state = shared_state.read().await.clone();
+    let Some(room_config) = state.config.room(room.room_id()) else {
+        return;
+    };
 
     let MessageType::Text(text_content) = &event.content.msgtype else {
         return;
     };
 
-    let (body, html_body) = get_events_message().await;
-
-    if text_content.body.contains("!calendar") || text_content.body.contains("!cal") {
-        let content = RoomMessageEventContent::text_html(body, html_body);
+    if let Some(command) = Command::parse(&text_content.body) {
+        // Replies show times in the zone of their reader when the room is a direct chat
+        let room_config = &RoomConfig {
+            timezone: reply_timezone(&state, &room, &event.sender, room_config).await,
+            ..room_config.clone()
+        };
+        let content = match command {
+            Command::Agenda(period) => {
+                let (body, html_body) = get_events_message(&state, room_config, &period).await;
+                RoomMessageEventContent::text_html(body, html_body)
+            }
+            Command::Next => {
+                let (body, html_body) = get_next_event_message(&state, room_config).await;
+                RoomMessageEventContent::text_html(body, html_body)
+            }
+            Command::Add(new_event) => RoomMessageEventContent::text_plain(
+                add_event(&state, room_config, &new_event).await,
+            ),
+            Command::Edit(reference, changes) => RoomMessageEventContent::text_plain(
+                edit_event(&state, room_config, &reference, &changes).await,
+            ),
+            Command::Delete(reference) => RoomMessageEventContent::text_plain(
+                delete_event(&state, room_config, &reference).await,
+            ),
+            Command::ShowTimezone => RoomMessageEventContent::text_plain(
+                describe_timezone(&state, &room, &event.sender, room_config).await,
+            ),
+            Command::SetTimezone(timezone) => RoomMessageEventContent::text_plain(
+                set_timezone(&state, &room, &event.sender, timezone.as_ref(), room_config).await,
+            ),
+            Command::Help => {
+                RoomMessageEventContent::text_plain(room_config.locale.message("usage", &[]))
+            }
+            Command::Invalid(err) => RoomMessageEventContent::text_plain(Command::invalid_reply(
+                &err,
+                &room_config.locale,
+            )),
+        };
 
         log::info!("sending");
 
@@ -143,99 +190,577 @@ async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
     log::info!("[{room_name}] {}: {}", event.sender, text_content.body)
 }
 
-pub async fn post_weekly_message(client: Arc<Client>, room_id: String) {
-    // Calculate the next instance of the specific time
-    let now = Utc::now();
-    let days_until_sunday =
-        (Weekday::Sun.num_days_from_monday() + 7 - now.weekday().num_days_from_monday()) % 7;
-    let next_sunday = now.date() + Duration::days(days_until_sunday.into());
-    let target_time = match next_sunday.and_hms_opt(9, 0, 0) {
-        Some(time) => time,
-        None => {
-            log::error!("Failed to calculate the next Sunday at 9:00 AM");
+/// Whether a room is a direct chat between the bot and one user, either marked as such or
+/// configured as one
+async fn is_direct_chat(room: &Room, room_config: &RoomConfig) -> bool {
+    room_config.direct || room.is_direct().await.unwrap_or(false)
+}
+
+/// The zone to show times in when replying to `sender`: theirs in a direct chat, if they set
+/// one, and otherwise that of the room
+async fn reply_timezone(
+    state: &State,
+    room: &Room,
+    sender: &UserId,
+    room_config: &RoomConfig,
+) -> Tz {
+    if !is_direct_chat(room, room_config).await {
+        return room_config.timezone;
+    }
+    match state.store.user_timezone(sender.as_str()) {
+        Ok(timezone) => timezone.unwrap_or(room_config.timezone),
+        Err(err) => {
+            log::error!("Unable to read the time zone of {}: {}", sender, err);
+            room_config.timezone
+        }
+    }
+}
+
+/// Tells a user which zone the times of the replies to them are shown in
+async fn describe_timezone(
+    state: &State,
+    room: &Room,
+    sender: &UserId,
+    room_config: &RoomConfig,
+) -> String {
+    let locale = &room_config.locale;
+    let timezone = match state.store.user_timezone(sender.as_str()) {
+        Ok(timezone) => timezone,
+        Err(err) => {
+            log::error!("Unable to read the time zone of {}: {}", sender, err);
+            return locale.message("timezone_read_failed", &[]);
+        }
+    };
+    match timezone {
+        Some(timezone) if is_direct_chat(room, room_config).await => {
+            locale.message("timezone_shown", &[("timezone", &timezone)])
+        }
+        Some(timezone) => locale.message(
+            "timezone_elsewhere",
+            &[
+                ("timezone", &timezone),
+                ("room_timezone", &room_config.timezone),
+            ],
+        ),
+        None => locale.message(
+            "timezone_unset",
+            &[("room_timezone", &room_config.timezone)],
+        ),
+    }
+}
+
+/// Records the zone of a user, or forgets it with `None`, and confirms it
+async fn set_timezone(
+    state: &State,
+    room: &Room,
+    sender: &UserId,
+    timezone: Option<&Tz>,
+    room_config: &RoomConfig,
+) -> String {
+    let locale = &room_config.locale;
+    if let Err(err) = state.store.record_user_timezone(sender.as_str(), timezone) {
+        log::error!("Unable to record the time zone of {}: {}", sender, err);
+        return locale.message("timezone_save_failed", &[]);
+    }
+    match timezone {
+        Some(timezone) if is_direct_chat(room, room_config).await => {
+            locale.message("timezone_set", &[("timezone", timezone)])
+        }
+        Some(timezone) => locale.message("timezone_set_elsewhere", &[("timezone", timezone)]),
+        None => locale.message("timezone_reset", &[]),
+    }
+}
+
+/// Starts posting the digests, the changes to the events and the reminders to every configured
+/// room, and keeping their pinned agendas up to date
+fn spawn_room_tasks(client: &Arc<Client>, state: &Arc<State>) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+    for room in &state.config.rooms {
+        for index in 0..room.digests.len() {
+            tasks.push(tokio::spawn(digests::post_digest(
+                Arc::clone(client),
+                state.clone(),
+                room.room_id.clone(),
+                index,
+            )));
+        }
+        if room.notify_changes {
+            tasks.push(tokio::spawn(changes::post_change_notices(
+                Arc::clone(client),
+                state.clone(),
+                room.room_id.clone(),
+            )));
+        }
+        if !room.reminders.is_empty() || room.alarms {
+            tasks.push(tokio::spawn(reminders::post_reminders(
+                Arc::clone(client),
+                state.clone(),
+                room.room_id.clone(),
+            )));
+        }
+        if room.pinned_agenda {
+            tasks.push(tokio::spawn(pinned_agenda::maintain_pinned_agenda(
+                Arc::clone(client),
+                state.clone(),
+                room.room_id.clone(),
+            )));
+        }
+    }
+    tasks
+}
+
+/// Reloads the configuration file on SIGHUP, and restarts the tasks of the rooms with it.
+///
+/// The Matrix client is kept as is, so changes to the `matrix` section only apply after a
+/// restart. An invalid file is reported and leaves the current configuration in place.
+async fn reload_on_hangup(
+    client: Arc<Client>,
+    config_path: PathBuf,
+    shared_state: SharedState,
+    mut tasks: Vec<JoinHandle<()>>,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::error!("Unable to listen for SIGHUP: {}", err);
             return;
         }
     };
 
-    let duration_until_target = (target_time - now)
-        .to_std()
-        .unwrap_or(StdDuration::from_secs(0));
-    let start = Instant::now() + duration_until_target;
+    while hangup.recv().await.is_some() {
+        log::info!("Reloading configuration from {}", config_path.display());
+        let config = match Config::load(&config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("{}", err);
+                log::error!("Keeping the previous configuration");
+                continue;
+            }
+        };
+        let previous = shared_state.read().await.config.matrix.clone();
+        if config.matrix.homeserver != previous.homeserver
+            || config.matrix.username != previous.username
+        {
+            log::warn!("Changes to the Matrix account only apply after a restart");
+        }
 
-    let mut interval = interval_at(start, StdDuration::from_secs(7 * 24 * 60 * 60)); // 1 week interval
+        let store = shared_state.read().await.store.clone();
+        let state = Arc::new(State::new(config, store));
+        state.calendars.get(|_| true).await.log_errors();
 
-    loop {
-        interval.tick().await;
+        for task in tasks.drain(..) {
+            task.abort();
+        }
+        tasks = spawn_room_tasks(&client, &state);
+        *shared_state.write().await = state;
+        log::info!("Configuration reloaded");
+    }
+}
 
-        let room_id = RoomId::parse(&room_id).expect("Invalid room ID");
+/// The configuration in effect, and the calendar collections resolved from it
+pub struct State {
+    config: Config,
+    store: Arc<EventStore>,
+    calendars: SourceCalendars,
+    /// The events of the last listing posted to each room, which commands refer to by number
+    listings: Mutex<HashMap<OwnedRoomId, Vec<ListedEvent>>>,
+}
 
-        // Post message to the room
-        if let Some(room) = client.get_room(&room_id) {
-            let (body, html_body) = get_events_message().await;
-            let content = RoomMessageEventContent::text_html(body, html_body);
+/// An event shown in a listing
+#[derive(Clone, Debug)]
+struct ListedEvent {
+    uid: String,
+    /// The URL of its resource
+    url: Url,
+    /// The ETag of its resource when it was listed
+    etag: Option<String>,
+}
 
-            match room.send(content).await {
-                Ok(_) => log::info!("Weekly message sent"),
-                Err(error) => {
-                    log::error!("Error sending weekly message: {error}");
-                }
-            }
-        } else {
-            log::error!("Failed to find room with ID {}", room_id);
+/// The state shared with the event handlers, replaced when the configuration is reloaded
+type SharedState = Arc<RwLock<Arc<State>>>;
+
+impl State {
+    fn new(config: Config, store: Arc<EventStore>) -> Self {
+        Self {
+            calendars: SourceCalendars::new(&config.calendars, store.clone()),
+            config,
+            store,
+            listings: Mutex::new(HashMap::new()),
         }
     }
+
+    /// Records the events listed in a room, in the order they were numbered
+    fn remember_listing(&self, room_id: &OwnedRoomId, events: &[Event]) {
+        let listing = events
+            .iter()
+            .map(|event| ListedEvent {
+                uid: event.uid().to_string(),
+                url: event.url().clone(),
+                etag: event.etag().map(str::to_string),
+            })
+            .collect();
+        if let Ok(mut listings) = self.listings.lock() {
+            listings.insert(room_id.clone(), listing);
+        }
+    }
+
+    /// The event listed with the number `index` (starting from 1) in the last listing of a room
+    fn listed_event(&self, room_id: &OwnedRoomId, index: usize) -> Option<ListedEvent> {
+        let listings = self.listings.lock().ok()?;
+        listings.get(room_id)?.get(index.checked_sub(1)?).cloned()
+    }
 }
 
-async fn get_events_message() -> (String, String) {
-    let caldav_credentials = CalDavCredentials::new(
-        env::var("CALDAV_SERVER_URL")
-            .expect("CALDAV_SERVER_URL must be set")
-            .parse()
-            .expect("CALDAV_SERVER_URL must be a valid URL"),
-        env::var("CALDAV_USERNAME").expect("CALDAV_USERNAME must be set"),
-        env::var("CALDAV_PASSWORD").expect("CALDAV_PASSWORD must be set"),
+/// How far ahead `!cal next` looks for an event
+const NEXT_EVENT_HORIZON_DAYS: i64 = 365;
+
+/// The calendars shown in a room
+async fn room_calendars<'a>(state: &'a State, room: &RoomConfig) -> Calendars<'a> {
+    state
+        .calendars
+        .get(|source| room.shows_calendar(source))
+        .await
+}
+
+fn failure_message(locale: &Locale) -> (String, String) {
+    let mut message = Message::new();
+    message.paragraph(|message| {
+        message.text(&locale.message("failure", &[]));
+    });
+    message.into_parts()
+}
+
+async fn get_events_message(state: &State, room: &RoomConfig, period: &Period) -> (String, String) {
+    let (start, end) = period.window(
+        Utc::now(),
+        &room.timezone,
+        room.window,
+        room.locale.first_weekday,
     );
-    // let start = "20240617T000000Z";
-    // let end = "20240619T235959Z";
+    let template = match period.description(&room.locale) {
+        Some(description) => MessageTemplate {
+            heading: room
+                .locale
+                .message("period_heading", &[("period", &description)]),
+            empty: room
+                .locale
+                .message("period_empty", &[("period", &description)]),
+            agenda: room.template.agenda.clone(),
+        },
+        None => room.template.clone(),
+    };
+    get_agenda_message(state, room, &start, &end, &template).await
+}
+
+/// Lists the events of a room in the `start..end` window
+async fn get_agenda_message(
+    state: &State,
+    room: &RoomConfig,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+    template: &MessageTemplate,
+) -> (String, String) {
+    let calendars = room_calendars(state, room).await;
+    if calendars.failed() {
+        calendars.log_errors();
+        return failure_message(&room.locale);
+    }
+
+    // get the calendar events from the caldav calendars
+    let events = get_agenda(calendars, start, end, &state.config.default_timezone)
+        .await
+        .available_events();
+
+    state.remember_listing(&room.room_id, &events);
+    format_events_message(&events, room, template)
+}
+
+async fn get_next_event_message(state: &State, room: &RoomConfig) -> (String, String) {
+    let calendars = room_calendars(state, room).await;
+    if calendars.failed() {
+        calendars.log_errors();
+        return failure_message(&room.locale);
+    }
 
     let start = Utc::now();
-    let window = Duration::days(7);
-    let end = start + window;
+    let end = start + Duration::days(NEXT_EVENT_HORIZON_DAYS);
+    let events = get_agenda(calendars, &start, &end, &state.config.default_timezone)
+        .await
+        .available_events();
+
+    // The agenda includes the events in progress, which have already started
+    let next: Vec<Event> = events
+        .into_iter()
+        .filter(|event| event.dtstart().naive_utc() >= start.naive_utc())
+        .take(1)
+        .collect();
+    state.remember_listing(&room.room_id, &next);
+    let template = MessageTemplate {
+        heading: room.locale.message("next_heading", &[]),
+        empty: room.locale.message("next_empty", &[]),
+        agenda: room.template.agenda.clone(),
+    };
+    format_events_message(&next, room, &template)
+}
 
-    // get the calendar events from caldav calendar
-    if let Ok(events) = get_calendar_events(caldav_credentials, &start, &end).await {
-        let mut body = String::from("Upcoming Events");
-        let mut html_body = String::from("<h3>Upcoming Events</h3><br />");
+/// Adds an event to the calendar written to from the room, and describes the outcome
+async fn add_event(state: &State, room: &RoomConfig, new_event: &NewEvent) -> String {
+    let locale = &room.locale;
+    let calendars = state
+        .calendars
+        .get(|source| {
+            room.shows_calendar(source) && room.write_calendar_key().is_none_or(|key| source == key)
+        })
+        .await;
+    if calendars.failed() {
+        calendars.log_errors();
+        return locale.message("no_write_calendar", &[]);
+    }
+    let Some(calendar) = calendars.available().into_iter().next() else {
+        return locale.message("no_calendar_to_add", &[]);
+    };
 
-        if events.len() == 0 {
-            body += "No events in the calendar this week";
-            html_body += "<p>No events in the calendar this week</p>";
-        };
+    let (uid, url) = match calendar.new_resource() {
+        Ok(resource) => resource,
+        Err(err) => {
+            log::error!("Error creating a resource URL: {}", err);
+            return locale.message("add_failed", &[]);
+        }
+    };
+    let event = match new_event.to_event(uid, url, &room.timezone) {
+        Ok(event) => event,
+        Err(err) => return locale.phrase(&err),
+    };
+
+    match calendar.create_event(&event).await {
+        Ok(()) => locale.message(
+            "added",
+            &[
+                ("name", &event.name()),
+                ("calendar", &calendar.label().name()),
+                (
+                    "times",
+                    &locale.format_event_times(event.dtstart(), event.dtend(), &room.timezone),
+                ),
+                ("uid", &event.uid()),
+                ("url", event.url()),
+            ],
+        ),
+        Err(err) if err.is::<PreconditionFailed>() => locale.message("uid_taken", &[]),
+        Err(err) => {
+            log::error!("Error adding event {}: {}", event.uid(), err);
+            locale.message("add_failed_because", &[("error", &err)])
+        }
+    }
+}
+
+/// Finds the event designated by a reference among the calendars shown in the room, along with
+/// its calendar and resource
+async fn find_event<'a>(
+    state: &'a State,
+    room: &RoomConfig,
+    reference: &EventRef,
+) -> Result<(&'a Calendar, Resource, Series), String> {
+    let locale = &room.locale;
+    let message = |key: &str| locale.message(key, &[("reference", reference)]);
+    let calendars = room_calendars(state, room).await;
+    if calendars.failed() {
+        calendars.log_errors();
+        return Err(locale.message("no_calendars", &[]));
+    }
+    let calendars = calendars.available();
+
+    let prefix = match reference {
+        EventRef::Index(index) => {
+            let listed = state
+                .listed_event(&room.room_id, *index)
+                .ok_or_else(|| message("not_listed"))?;
+            let calendar = calendars
+                .into_iter()
+                .find(|calendar| calendar.contains(&listed.url))
+                .ok_or_else(|| message("calendar_gone"))?;
+            return match calendar
+                .get_resource(&listed.url, listed.etag, &state.config.default_timezone)
+                .await
+            {
+                Ok((resource, series)) if series.uid() == Some(listed.uid.as_str()) => {
+                    Ok((calendar, resource, series))
+                }
+                Ok(_) => Err(message("event_replaced")),
+                Err(err) => {
+                    log::error!("Error reading {}: {}", listed.url, err);
+                    Err(message("read_failed"))
+                }
+            };
+        }
+        EventRef::UidPrefix(prefix) => prefix,
+    };
+
+    let mut found = Vec::new();
+    for calendar in calendars {
+        match calendar
+            .find_resources(prefix, &state.config.default_timezone)
+            .await
+        {
+            Ok(resources) => found.extend(
+                resources
+                    .into_iter()
+                    .map(|(resource, series)| (calendar, resource, series)),
+            ),
+            Err(err) => log::error!("Error searching calendar {}: {}", calendar.url(), err),
+        }
+    }
+
+    match found.len() {
+        0 => Err(message("not_found")),
+        1 => Ok(found.remove(0)),
+        _ => Err(message("ambiguous_reference")),
+    }
+}
+
+/// The name of the event of a resource, to describe it in replies
+fn series_name(series: &Series, locale: &Locale) -> String {
+    series
+        .master()
+        .map(|master| master.name().to_string())
+        .unwrap_or_else(|| locale.message("unnamed_event", &[]))
+}
+
+async fn edit_event(
+    state: &State,
+    room: &RoomConfig,
+    reference: &EventRef,
+    changes: &EventChanges,
+) -> String {
+    let (calendar, resource, series) = match find_event(state, room, reference).await {
+        Ok(found) => found,
+        Err(err) => return err,
+    };
+    let locale = &room.locale;
+    let name = series_name(&series, locale);
 
-        for event in events {
-            body += &format!(
-                "{}: \n{}\n\n",
-                event.name(),
-                format_event_times(event.dtstart(), event.dtend())
-            );
+    let times = match (changes.reschedules(), series.master()) {
+        (false, _) => None,
+        (true, _) if series.is_recurring() => {
+            return locale.message("recurring_time", &[("name", &name)])
+        }
+        (true, None) => return locale.message("no_main_event", &[("name", &name)]),
+        (true, Some(master)) => match changes.reschedule(master, &room.timezone) {
+            Ok(times) => Some(times),
+            Err(err) => return locale.phrase(&err),
+        },
+    };
+
+    let data = match serializer::update(
+        &resource.data,
+        changes.title.as_deref(),
+        changes.location.as_deref(),
+        times,
+    ) {
+        Ok(data) => data,
+        Err(err) => return locale.message("edit_failed", &[("name", &name), ("error", &err)]),
+    };
 
-            html_body += &format!(
-                "<p><strong>{}</strong><br />{}</p>",
-                event.name(),
-                format_event_times(event.dtstart(), event.dtend())
-            );
+    match calendar.update_resource(&resource, data).await {
+        Ok(()) => edited_message(&series, changes.title.as_deref().unwrap_or(&name), locale),
+        Err(err) if err.is::<PreconditionFailed>() => {
+            locale.message("edit_conflict", &[("name", &name)])
         }
+        Err(err) => {
+            log::error!("Error updating {}: {}", resource.url, err);
+            locale.message("edit_failed", &[("name", &name), ("error", &err)])
+        }
+    }
+}
 
-        (body, html_body)
+/// The reply to an edit. The title and location of a recurring series are changed in every
+/// occurrence, while the listing the edit refers to only shows one of them.
+fn edited_message(series: &Series, name: &str, locale: &Locale) -> String {
+    let key = if series.is_recurring() {
+        "edited_series"
     } else {
-        (
-            "Failed to get calendar events".to_string(),
-            "<p>Failed to get calendar events</p>".to_string(),
-        )
+        "edited"
+    };
+    locale.message(key, &[("name", &name)])
+}
+
+async fn delete_event(state: &State, room: &RoomConfig, reference: &EventRef) -> String {
+    let (calendar, resource, series) = match find_event(state, room, reference).await {
+        Ok(found) => found,
+        Err(err) => return err,
+    };
+    let locale = &room.locale;
+    let name = series_name(&series, locale);
+    // The resource holds every occurrence of a series, which a reference to one of them must
+    // not delete
+    if series.is_recurring() {
+        return locale.message("recurring_delete", &[("name", &name)]);
+    }
+
+    match calendar.delete_resource(&resource).await {
+        Ok(()) => locale.message("deleted", &[("name", &name)]),
+        Err(err) if err.is::<PreconditionFailed>() => {
+            locale.message("delete_conflict", &[("name", &name)])
+        }
+        Err(err) => {
+            log::error!("Error deleting {}: {}", resource.url, err);
+            locale.message("delete_failed", &[("name", &name), ("error", &err)])
+        }
     }
 }
 
-fn get_room_ids() -> Vec<String> {
-    let room_ids = env::var("MATRIX_ROOM_IDS").expect("MATRIX_ROOM_IDS must be set");
-    room_ids.split(',').map(|s| s.to_string()).collect()
+/// Renders an agenda with the templates of a room, or in the built-in format when they fail
+fn format_events_message(
+    events: &[Event],
+    room: &RoomConfig,
+    template: &MessageTemplate,
+) -> (String, String) {
+    template.agenda.render_or_default(
+        events,
+        &template.heading,
+        &template.empty,
+        &room.timezone,
+        &room.locale,
+        &Utc::now(),
+    )
+}
+
+/// Adds the label of the calendar of an event, in the color of the calendar
+fn format_calendar_label(message: &mut Message, event: &Event) {
+    if let Some(calendar) = event.calendar() {
+        message
+            .colored(&format!("[{}]", calendar.name()), calendar.color())
+            .text(" ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use locale::Catalogs;
+
+    fn series(rrule: &str) -> Series {
+        let data = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:abc\r\n\
+             DTSTAMP:20241101T000000Z\r\nDTSTART:20241104T180000Z\r\n\
+             DTEND:20241104T190000Z\r\nSUMMARY:Meeting\r\n{}END:VEVENT\r\nEND:VCALENDAR\r\n",
+            rrule
+        );
+        let url = Url::parse("https://dav.example/cal/abc.ics").unwrap();
+        parser::parse(&data, url, &chrono_tz::UTC).unwrap()
+    }
+
+    #[test]
+    fn tells_that_edits_apply_to_every_occurrence_of_a_series() {
+        let catalogs = Catalogs::load(None).unwrap();
+        let locale = Locale::new("en_US", &catalogs).unwrap();
+        assert_eq!(
+            edited_message(&series(""), "Standup", &locale),
+            "Updated Standup"
+        );
+        assert_eq!(
+            edited_message(&series("RRULE:FREQ=WEEKLY\r\n"), "Standup", &locale),
+            "Updated every occurrence of Standup"
+        );
+    }
 }
@@ -44,6 +44,7 @@ struct FullSession {
     sync_token: Option<String>,
 }
 
+#[derive(Clone, Debug)]
 pub struct MatrixCredentials {
     pub username: String,
     pub password: String,
@@ -163,16 +164,14 @@ async fn build_client(
         .build()
         .await
     {
-        Ok(client) => {
-            return Ok((
-                client,
-                ClientSession {
-                    homeserver,
-                    db_path,
-                    passphrase,
-                },
-            ))
-        }
+        Ok(client) => Ok((
+            client,
+            ClientSession {
+                homeserver,
+                db_path,
+                passphrase,
+            },
+        )),
         Err(error) => match &error {
             matrix_sdk::ClientBuildError::AutoDiscovery(_)
             | matrix_sdk::ClientBuildError::Url(_)
@@ -1,13 +1,24 @@
 //! A module to parse ICal files
 
-use crate::event::{Event, EventTime};
-use chrono::{DateTime, NaiveDate, TimeZone, Utc};
-use ical::parser::ical::component::{IcalCalendar, IcalEvent};
+use crate::event::{Alarm, Event, EventStatus, EventTime, LastModifiedSource, Series};
+use crate::recurrence::{Recurrence, RecurrenceRule};
+use crate::timezone::{CustomTimeZone, EventTimeZone};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use ical::parser::ical::component::{IcalAlarm, IcalCalendar, IcalEvent};
+use ical::property::Property;
 use std::error::Error;
 use url::Url;
 
-/// Parse an iCal file into the internal representation [`crate::Event`]
-pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
+/// Parse an iCal file into the internal representation [`crate::Series`]: the master event and
+/// the instances of it that were overridden with a `RECURRENCE-ID`
+///
+/// Floating times (without a `TZID` nor a UTC designator) are interpreted in `default_timezone`.
+pub fn parse(
+    content: &str,
+    item_url: Url,
+    default_timezone: &Tz,
+) -> Result<Series, Box<dyn Error>> {
     let mut reader = ical::IcalParser::new(content.as_bytes());
     let parsed_item = match reader.next() {
         None => return Err(format!("Invalid iCal data to parse for item {}", item_url).into()),
@@ -21,28 +32,105 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
         },
     };
 
-    let event = assert_single_type(parsed_item)?;
+    let timezones = TimeZones::new(&parsed_item, default_timezone);
+    let vevents = assert_events_only(parsed_item)?;
 
+    let mut master = None;
+    let mut overrides = Vec::new();
+    for vevent in &vevents {
+        let is_override = vevent
+            .properties
+            .iter()
+            .any(|prop| prop.name == "RECURRENCE-ID");
+        if !is_override {
+            if master.is_some() {
+                return Err(format!("Multiple master VEVENTs for item {}", item_url).into());
+            }
+            master = Some(parse_event(vevent, &item_url, &timezones)?);
+            continue;
+        }
+
+        // A broken override should not hide the rest of the series
+        match parse_event(vevent, &item_url, &timezones) {
+            Ok(event) => overrides.push(event),
+            Err(err) => log::warn!("Ignoring recurrence override: {}", err),
+        }
+    }
+
+    let series = Series::new(master, overrides);
+    if let Some(uid) = series.inconsistent_uid() {
+        return Err(format!("Mismatched UID {} in item {}", uid, item_url).into());
+    }
+
+    // What to do with multiple items?
+    if reader.next().map(|r| r.is_ok()) == Some(true) {
+        return Err("Parsing multiple items are not supported".into());
+    }
+
+    Ok(series)
+}
+
+/// Parse a single `VEVENT` component
+fn parse_event(
+    event: &IcalEvent,
+    item_url: &Url,
+    timezones: &TimeZones,
+) -> Result<Event, Box<dyn Error>> {
     let mut name = None;
     let mut uid = None;
     let mut dtstart = None;
+    let mut timezone = None;
     let mut dtend = None;
+    let mut duration = None;
     let mut location = None;
     let mut description = None;
+    let mut link = None;
     let mut last_modified = None;
     let mut creation_date = None;
+    let mut dtstamp = None;
+    let mut status = None;
+    let mut recurrence_id = None;
+    let mut recurrence = Recurrence::default();
+    let mut rrules = Vec::new();
     let mut extra_parameters = Vec::new();
 
     for prop in &event.properties {
         match prop.name.as_str() {
-            "SUMMARY" => name = prop.value.clone(),
+            "SUMMARY" => name = prop.value.as_deref().map(unescape_text),
             "UID" => uid = prop.value.clone(),
-            "DTSTART" => dtstart = parse_event_time_from_property(&prop.value),
-            "DTEND" => dtend = parse_event_time_from_property(&prop.value),
-            "LOCATION" => location = prop.value.clone(),
-            "DESCRIPTION" => description = prop.value.clone(),
+            "DTSTART" => {
+                dtstart = parse_event_time_from_property(prop, timezones);
+                timezone = timezones.property_timezone(prop);
+            }
+            "DTEND" => dtend = parse_event_time_from_property(prop, timezones),
+            "DURATION" => {
+                duration = prop.value.as_deref().and_then(|value| {
+                    EventDuration::parse(value)
+                        .inspect_err(|err| log::warn!("{} for item {}", err, item_url))
+                        .ok()
+                })
+            }
+            "RECURRENCE-ID" => recurrence_id = parse_event_time_from_property(prop, timezones),
+            "STATUS" => status = prop.value.as_deref().and_then(EventStatus::from_ical),
+            "LOCATION" => location = prop.value.as_deref().map(unescape_text),
+            "DESCRIPTION" => description = prop.value.as_deref().map(unescape_text),
+            "URL" => {
+                link = prop.value.as_deref().and_then(|value| {
+                    Url::parse(value.trim())
+                        .inspect_err(|err| log::warn!("Invalid URL for item {}: {}", item_url, err))
+                        .ok()
+                })
+            }
             "LAST-MODIFIED" => last_modified = parse_date_time_from_property(&prop.value),
             "CREATED" => creation_date = parse_date_time_from_property(&prop.value),
+            "DTSTAMP" => dtstamp = parse_date_time_from_property(&prop.value),
+            "RRULE" => rrules.extend(prop.value.clone()),
+            "RDATE" => parse_event_time_list_from_property(prop, timezones)
+                .into_iter()
+                .for_each(|rdate| recurrence.add_rdate(rdate)),
+            "EXDATE" => parse_event_time_list_from_property(prop, timezones)
+                .into_iter()
+                .for_each(|exdate| recurrence.add_exdate(exdate)),
             _ => {
                 // This field is not supported. Let's store it anyway, so that we are able to re-create an identical iCal file
                 extra_parameters.push(prop.clone());
@@ -50,18 +138,49 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
         }
     }
 
+    let alarms = parse_alarms(&event.alarms, item_url);
     let name = name.ok_or_else(|| format!("Missing name for item {}", item_url))?;
     let uid = uid.ok_or_else(|| format!("Missing UID for item {}", item_url))?;
     let dtstart = dtstart.ok_or_else(|| format!("Missing DTSTART for item {}", item_url))?;
-    let dtend = dtend.ok_or_else(|| format!("Missing DTEND for item {}", item_url))?;
-    let last_modified = last_modified.ok_or_else(|| {
-        format!(
-            "Missing LAST-MODIFIED for item {}, but this is required by RFC5545",
-            item_url
-        )
-    })?;
-
-    let event = match dtstart {
+    // RFC5545 3.6.1: without DTEND, the end is given by DURATION, or else the event lasts one
+    // day for a date DTSTART, and takes no time for a date-time DTSTART
+    let dtend = match (dtend, duration) {
+        (Some(dtend), _) => dtend,
+        (None, Some(duration)) if duration.is_negative() => {
+            return Err(format!("Negative DURATION for item {}", item_url).into())
+        }
+        (None, Some(duration)) => duration
+            .end_from(&dtstart, timezone.as_ref())
+            .ok_or_else(|| format!("DURATION out of range for item {}", item_url))?,
+        (None, None) => match &dtstart {
+            EventTime::Date(date) => EventTime::Date(*date + Duration::days(1)),
+            EventTime::DateTime(_) => dtstart.clone(),
+        },
+    };
+    // Such an event would be missed by the queries of the windows it overlaps
+    if dtend.naive_utc() < dtstart.naive_utc() {
+        return Err(format!("DTSTART for item {} is after DTEND", item_url).into());
+    }
+    // LAST-MODIFIED is optional for a VEVENT, so fall back to the best timestamp available
+    let (last_modified, last_modified_source) = match (last_modified, dtstamp, creation_date) {
+        (Some(last_modified), _, _) => (last_modified, LastModifiedSource::LastModified),
+        (None, Some(dtstamp), _) => (dtstamp, LastModifiedSource::DtStamp),
+        (None, None, Some(created)) => (created, LastModifiedSource::Created),
+        (None, None, None) => {
+            log::debug!("No modification time for item {}", item_url);
+            (Utc::now(), LastModifiedSource::FetchTime)
+        }
+    };
+
+    // A floating UNTIL is in the zone of DTSTART, so rules are parsed once it is known
+    for rrule in rrules {
+        match RecurrenceRule::parse(&rrule, timezone.as_ref()) {
+            Ok(rule) => recurrence.add_rule(rule),
+            Err(err) => log::warn!("Ignoring RRULE for item {}: {}", item_url, err),
+        }
+    }
+
+    let mut event = match dtstart {
         EventTime::DateTime(dtstart) => match dtend {
             EventTime::DateTime(dtend) => Event::new_timed(
                 name,
@@ -70,7 +189,7 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
                 dtend,
                 location,
                 description,
-                item_url,
+                item_url.clone(),
                 last_modified,
                 creation_date,
             ),
@@ -92,9 +211,6 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
             }
             EventTime::Date(dtend) => match dtstart.as_date() {
                 Some(dtstart) => {
-                    if dtstart > &dtend {
-                        return Err(format!("DTSTART for item {} is after DTEND", item_url).into());
-                    }
                     let dtstart = dtstart.to_owned();
 
                     Event::new_all_day(
@@ -104,7 +220,7 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
                         dtend,
                         location,
                         description,
-                        item_url,
+                        item_url.clone(),
                         last_modified,
                         creation_date,
                     )
@@ -120,39 +236,84 @@ pub fn parse(content: &str, item_url: Url) -> Result<Event, Box<dyn Error>> {
         },
     };
 
-    // What to do with multiple items?
-    if reader.next().map(|r| r.is_ok()) == Some(true) {
-        return Err("Parsing multiple items are not supported".into());
+    event.set_last_modified_source(last_modified_source);
+    event.set_recurrence(recurrence);
+    if let Some(timezone) = timezone {
+        event.set_timezone(timezone);
     }
+    if let Some(recurrence_id) = recurrence_id {
+        event.set_recurrence_id(recurrence_id);
+    }
+    if let Some(status) = status {
+        event.set_status(status);
+    }
+    if let Some(link) = link {
+        event.set_link(link);
+    }
+    event.set_alarms(alarms);
 
     Ok(event)
 }
 
+/// Reads the triggers of the `VALARM`s of an event
+fn parse_alarms(alarms: &[IcalAlarm], item_url: &Url) -> Vec<Alarm> {
+    let mut triggers = Vec::new();
+    for prop in alarms
+        .iter()
+        .flat_map(|alarm| &alarm.properties)
+        .filter(|prop| prop.name == "TRIGGER")
+    {
+        let Some(value) = prop.value.as_deref() else {
+            continue;
+        };
+        let alarm = if property_param(prop, "VALUE") == Some("DATE-TIME") {
+            parse_date_time(value).ok().map(Alarm::At)
+        } else {
+            EventDuration::parse(value)
+                .inspect_err(|err| log::warn!("{} in an alarm of item {}", err, item_url))
+                .ok()
+                .and_then(|duration| duration.exact_seconds())
+                .map(|seconds| Alarm::Relative {
+                    seconds,
+                    from_end: property_param(prop, "RELATED") == Some("END"),
+                })
+        };
+        match alarm {
+            Some(alarm) if !triggers.contains(&alarm) => triggers.push(alarm),
+            Some(_) => {}
+            None => log::warn!("Ignoring alarm of item {}: invalid TRIGGER", item_url),
+        }
+    }
+    triggers
+}
+
 // Function to parse both datetime and date formats
 
 fn parse_date_time(dt: &str) -> Result<DateTime<Utc>, chrono::format::ParseError> {
-    match Utc.datetime_from_str(dt, "%Y%m%dT%H%M%SZ") {
-        Ok(datetime) => Ok(datetime),
-        Err(err) => Err(err),
-    }
+    NaiveDateTime::parse_from_str(dt, "%Y%m%dT%H%M%SZ").map(|datetime| datetime.and_utc())
 }
 
 fn parse_date_time_from_property(value: &Option<String>) -> Option<DateTime<Utc>> {
     value.as_ref().and_then(|s| {
         parse_date_time(s)
-            .map_err(|err| {
-                log::warn!("Invalid timestamp: {}", s);
-                err
-            })
+            .inspect_err(|_| log::warn!("Invalid timestamp: {}", s))
             .ok()
     })
 }
 
-fn parse_event_time(dt: &str) -> Result<EventTime, chrono::format::ParseError> {
-    match Utc.datetime_from_str(dt, "%Y%m%dT%H%M%SZ") {
-        Ok(datetime) => Ok(EventTime::DateTime(datetime)),
-        Err(_) => match Utc.datetime_from_str(dt, "%Y%m%dT%H%M%S") {
-            Ok(datetime) => Ok(EventTime::DateTime(datetime)),
+/// Parses a DATE or DATE-TIME value. Local times are interpreted in `timezone`, or as UTC if
+/// there is none.
+pub(crate) fn parse_event_time(
+    dt: &str,
+    timezone: Option<&EventTimeZone>,
+) -> Result<EventTime, chrono::format::ParseError> {
+    match NaiveDateTime::parse_from_str(dt, "%Y%m%dT%H%M%SZ") {
+        Ok(datetime) => Ok(EventTime::DateTime(datetime.and_utc())),
+        Err(_) => match NaiveDateTime::parse_from_str(dt, "%Y%m%dT%H%M%S") {
+            Ok(datetime) => Ok(EventTime::DateTime(match timezone {
+                Some(timezone) => timezone.to_utc(&datetime),
+                None => datetime.and_utc(),
+            })),
             Err(_) => match NaiveDate::parse_from_str(dt, "%Y%m%d") {
                 Ok(date) => Ok(EventTime::Date(date)),
                 Err(err) => Err(err),
@@ -161,29 +322,522 @@ fn parse_event_time(dt: &str) -> Result<EventTime, chrono::format::ParseError> {
     }
 }
 
-fn parse_event_time_from_property(value: &Option<String>) -> Option<EventTime> {
-    value.as_ref().and_then(|s| {
-        parse_event_time(s)
-            .map_err(|err| {
-                log::warn!("Invalid timestamp: {}", s);
-                err
-            })
+fn parse_event_time_from_property(prop: &Property, timezones: &TimeZones) -> Option<EventTime> {
+    let timezone = timezones.property_timezone(prop);
+    prop.value.as_ref().and_then(|s| {
+        parse_event_time(s, timezone.as_ref())
+            .inspect_err(|_| log::warn!("Invalid timestamp: {}", s))
             .ok()
     })
 }
 
-fn assert_single_type(item: IcalCalendar) -> Result<IcalEvent, Box<dyn Error>> {
+/// Parses a comma-separated list of times, as used by `RDATE` and `EXDATE`.
+/// `RDATE` periods are reduced to their start.
+fn parse_event_time_list_from_property(prop: &Property, timezones: &TimeZones) -> Vec<EventTime> {
+    let timezone = timezones.property_timezone(prop);
+    prop.value
+        .iter()
+        .flat_map(|s| s.split(','))
+        .filter_map(|s| {
+            let start = s.split('/').next().unwrap_or(s);
+            parse_event_time(start, timezone.as_ref())
+                .inspect_err(|_| log::warn!("Invalid timestamp: {}", s))
+                .ok()
+        })
+        .collect()
+}
+
+/// The first value of a parameter of a property
+fn property_param<'a>(prop: &'a Property, name: &str) -> Option<&'a str> {
+    prop.params
+        .iter()
+        .flatten()
+        .find(|(param, _)| param == name)
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// An iCal `DURATION` value (RFC5545 3.3.6, a subset of ISO-8601 durations)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EventDuration {
+    /// Nominal days (and weeks), which follow the wall clock across DST transitions
+    days: i64,
+    /// Exact hours, minutes and seconds
+    seconds: i64,
+}
+
+impl EventDuration {
+    /// Parses values such as `PT1H30M`, `P1D`, `P2W` or `-PT15M`
+    fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid DURATION {}", value);
+
+        let (sign, rest) = match value.as_bytes().first() {
+            Some(b'-') => (-1, &value[1..]),
+            Some(b'+') => (1, &value[1..]),
+            _ => (1, value),
+        };
+        let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+        let (date_part, time_part) = match rest.split_once('T') {
+            Some((date_part, time_part)) if !time_part.is_empty() => (date_part, Some(time_part)),
+            Some(_) => return Err(invalid()),
+            None => (rest, None),
+        };
+
+        let mut days: i64 = 0;
+        for (amount, unit) in duration_components(date_part).ok_or_else(invalid)? {
+            let factor = match unit {
+                'W' => 7,
+                'D' => 1,
+                _ => return Err(invalid()),
+            };
+            days = amount
+                .checked_mul(factor)
+                .and_then(|amount| days.checked_add(amount))
+                .ok_or_else(invalid)?;
+        }
+
+        let mut seconds: i64 = 0;
+        for (amount, unit) in
+            duration_components(time_part.unwrap_or_default()).ok_or_else(invalid)?
+        {
+            let factor = match unit {
+                'H' => 3600,
+                'M' => 60,
+                'S' => 1,
+                _ => return Err(invalid()),
+            };
+            seconds = amount
+                .checked_mul(factor)
+                .and_then(|amount| seconds.checked_add(amount))
+                .ok_or_else(invalid)?;
+        }
+
+        if date_part.is_empty() && time_part.is_none() {
+            return Err(invalid());
+        }
+        // Both parts must fit in a chrono duration for the end of the event to be computed
+        if Duration::try_days(days).is_none() || Duration::try_seconds(seconds).is_none() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            days: sign * days,
+            seconds: sign * seconds,
+        })
+    }
+
+    fn is_negative(&self) -> bool {
+        self.days < 0 || self.seconds < 0
+    }
+
+    /// The length in seconds, counting nominal days as 24 hours, or `None` when it overflows
+    fn exact_seconds(&self) -> Option<i64> {
+        self.days
+            .checked_mul(24 * 3600)
+            .and_then(|seconds| seconds.checked_add(self.seconds))
+            .filter(|seconds| Duration::try_seconds(*seconds).is_some())
+    }
+
+    /// The end of an event that starts at `dtstart` and lasts this long. Nominal days are added
+    /// in `timezone`, the zone of `dtstart`. `None` when the end is past the years iCal can
+    /// represent.
+    fn end_from(&self, dtstart: &EventTime, timezone: Option<&EventTimeZone>) -> Option<EventTime> {
+        let days = Duration::days(self.days);
+        match dtstart {
+            EventTime::Date(date) => {
+                if self.seconds != 0 {
+                    log::warn!("Ignoring the time part of the DURATION of an all-day event");
+                }
+                date.checked_add_signed(days)
+                    .filter(|date| is_ical_year(date.year()))
+                    .map(EventTime::Date)
+            }
+            EventTime::DateTime(datetime) => {
+                let datetime = match timezone {
+                    Some(timezone) => {
+                        let local = timezone
+                            .to_local(datetime)
+                            .checked_add_signed(days)
+                            .filter(|local| is_ical_year(local.year()))?;
+                        timezone.to_utc(&local)
+                    }
+                    None => datetime.checked_add_signed(days)?,
+                };
+                datetime
+                    .checked_add_signed(Duration::seconds(self.seconds))
+                    .filter(|datetime| is_ical_year(datetime.year()))
+                    .map(EventTime::DateTime)
+            }
+        }
+    }
+}
+
+/// Whether `year` can be written in an iCal date, which has four digits (RFC5545 3.3.4)
+fn is_ical_year(year: i32) -> bool {
+    (0..=9999).contains(&year)
+}
+
+/// Splits a duration part such as `1H30M` into its `(amount, unit)` components
+fn duration_components(part: &str) -> Option<Vec<(i64, char)>> {
+    let mut components = Vec::new();
+    let mut amount = String::new();
+    for c in part.chars() {
+        if c.is_ascii_digit() {
+            amount.push(c);
+        } else {
+            components.push((amount.parse().ok()?, c));
+            amount.clear();
+        }
+    }
+    amount.is_empty().then_some(components)
+}
+
+/// The zones that the times of a calendar object can refer to
+struct TimeZones {
+    vtimezones: Vec<CustomTimeZone>,
+    default: EventTimeZone,
+}
+
+impl TimeZones {
+    fn new(calendar: &IcalCalendar, default: &Tz) -> Self {
+        let vtimezones = calendar
+            .timezones
+            .iter()
+            .filter_map(|vtimezone| {
+                CustomTimeZone::from_vtimezone(vtimezone)
+                    .inspect_err(|err| log::warn!("Ignoring VTIMEZONE: {}", err))
+                    .ok()
+            })
+            .collect();
+
+        Self {
+            vtimezones,
+            default: EventTimeZone::Iana(*default),
+        }
+    }
+
+    /// Resolves the zone of a time property: the one named by its `TZID` parameter, the default
+    /// zone for floating times, or `None` for UTC times and dates
+    fn property_timezone(&self, prop: &Property) -> Option<EventTimeZone> {
+        let tzid = prop
+            .params
+            .iter()
+            .flatten()
+            .find(|(name, _)| name == "TZID")
+            .and_then(|(_, values)| values.first());
+
+        let Some(tzid) = tzid else {
+            let value = prop.value.as_deref().unwrap_or_default();
+            return if value.ends_with('Z') || !value.contains('T') {
+                None
+            } else {
+                Some(self.default.clone())
+            };
+        };
+
+        EventTimeZone::from_iana_name(tzid)
+            .or_else(|| {
+                self.vtimezones
+                    .iter()
+                    .find(|vtimezone| vtimezone.tzid() == tzid)
+                    .cloned()
+                    .map(EventTimeZone::Custom)
+            })
+            .or_else(|| {
+                log::warn!("Unknown TZID {}, using the default time zone", tzid);
+                Some(self.default.clone())
+            })
+    }
+}
+
+fn assert_events_only(item: IcalCalendar) -> Result<Vec<IcalEvent>, Box<dyn Error>> {
     let n_events = item.events.len();
     let n_todos = item.todos.len();
     let n_journals = item.journals.len();
 
-    if n_events == 1 {
-        if n_todos != 0 || n_journals != 0 {
-            return Err("Only a single TODO or a single EVENT is supported".into());
-        } else {
-            return Ok(item.events[0].clone());
+    if n_todos != 0 || n_journals != 0 {
+        return Err("Only EVENT items are supported".into());
+    }
+    if n_events == 0 {
+        return Err("Missing EVENT in item".into());
+    }
+
+    Ok(item.events)
+}
+
+/// Unescapes a `TEXT` value (RFC5545 section 3.3.11)
+fn unescape_text(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
         }
     }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn item_url() -> Url {
+        Url::parse("https://example.org/cal/event.ics").unwrap()
+    }
+
+    fn calendar(event_lines: &[&str]) -> String {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR",
+            "VERSION:2.0",
+            "BEGIN:VEVENT",
+            "UID:abc",
+            "SUMMARY:Meeting",
+            "DTSTAMP:20240101T000000Z",
+        ];
+        lines.extend_from_slice(event_lines);
+        lines.extend_from_slice(&["END:VEVENT", "END:VCALENDAR", ""]);
+        lines.join("\r\n")
+    }
 
-    return Err("Only a single EVENT is supported".into());
+    fn parse_master(event_lines: &[&str]) -> Result<Event, Box<dyn Error>> {
+        let series = parse(
+            &calendar(event_lines),
+            item_url(),
+            &chrono_tz::Europe::Paris,
+        )?;
+        Ok(series.master().unwrap().clone())
+    }
+
+    #[test]
+    fn parses_durations() {
+        let duration = |days, seconds| EventDuration { days, seconds };
+        assert_eq!(EventDuration::parse("PT1H30M"), Ok(duration(0, 5400)));
+        assert_eq!(EventDuration::parse("P2W"), Ok(duration(14, 0)));
+        assert_eq!(EventDuration::parse("P1DT12H"), Ok(duration(1, 43200)));
+        assert_eq!(EventDuration::parse("-PT15M"), Ok(duration(0, -900)));
+        assert!(EventDuration::parse("P").is_err());
+        assert!(EventDuration::parse("PT").is_err());
+        assert!(EventDuration::parse("P1H").is_err());
+        assert!(EventDuration::parse("1D").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert!(EventDuration::parse("P9223372036854775807W").is_err());
+        assert!(EventDuration::parse("PT9223372036854775807H").is_err());
+        assert!(EventDuration::parse("P9223372036854775807D").is_err());
+        assert!(EventDuration::parse("P99999999999999999999D").is_err());
+    }
+
+    #[test]
+    fn ends_timed_events_after_their_duration_in_their_zone() {
+        // Nominal days follow the wall clock across the end of DST, exact hours do not
+        let event = parse_master(&[
+            "DTSTART;TZID=Europe/Paris:20241026T180000",
+            "DURATION:P1DT1H",
+        ])
+        .unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 10, 27, 18, 0, 0).unwrap();
+        assert_eq!(event.dtend(), &EventTime::DateTime(end));
+    }
+
+    #[test]
+    fn ends_all_day_events_without_a_duration_after_a_day() {
+        let event = parse_master(&["DTSTART;VALUE=DATE:20241104"]).unwrap();
+        let end = NaiveDate::from_ymd_opt(2024, 11, 5).unwrap();
+        assert_eq!(event.dtend(), &EventTime::Date(end));
+    }
+
+    #[test]
+    fn rejects_events_ending_before_they_start() {
+        assert!(parse_master(&["DTSTART:20241104T180000Z", "DURATION:-PT1H"]).is_err());
+        assert!(parse_master(&["DTSTART;VALUE=DATE:20241104", "DURATION:-P1D"]).is_err());
+        assert!(parse_master(&["DTSTART:20241104T180000Z", "DTEND:20241104T170000Z"]).is_err());
+        assert!(
+            parse_master(&["DTSTART;VALUE=DATE:20241104", "DTEND;VALUE=DATE:20241103",]).is_err()
+        );
+
+        // Events may take no time
+        let event = parse_master(&["DTSTART:20241104T180000Z", "DURATION:PT0S"]).unwrap();
+        assert_eq!(event.dtend(), event.dtstart());
+        let event = parse_master(&["DTSTART:20241104T180000Z", "DTEND:20241104T180000Z"]).unwrap();
+        assert_eq!(event.dtend(), event.dtstart());
+    }
+
+    #[test]
+    fn skips_events_whose_duration_ends_out_of_range() {
+        assert!(parse_master(&["DTSTART;VALUE=DATE:20241104", "DURATION:P100000000D"]).is_err());
+        assert!(parse_master(&["DTSTART:20241104T180000Z", "DURATION:P100000000D"]).is_err());
+        assert!(parse_master(&[
+            "DTSTART;TZID=Europe/Paris:20241104T180000",
+            "DURATION:P3000000D",
+        ])
+        .is_err());
+        assert!(parse_master(&["DTSTART:99991231T180000Z", "DURATION:PT9999999999S"]).is_err());
+    }
+
+    #[test]
+    fn reads_alarm_triggers() {
+        let event = parse_master(&[
+            "DTSTART:20241104T180000Z",
+            "DURATION:PT1H",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "TRIGGER:-P1DT30M",
+            "END:VALARM",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "TRIGGER;RELATED=END:PT0S",
+            "END:VALARM",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "TRIGGER;VALUE=DATE-TIME:20241103T090000Z",
+            "END:VALARM",
+        ])
+        .unwrap();
+        let at = Utc.with_ymd_and_hms(2024, 11, 3, 9, 0, 0).unwrap();
+        assert_eq!(
+            event.alarms(),
+            &[
+                Alarm::Relative {
+                    seconds: -(24 * 3600 + 1800),
+                    from_end: false,
+                },
+                Alarm::Relative {
+                    seconds: 0,
+                    from_end: true,
+                },
+                Alarm::At(at),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_alarms_that_overflow() {
+        let event = parse_master(&[
+            "DTSTART:20241104T180000Z",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "TRIGGER:-P100000000000000D",
+            "END:VALARM",
+        ])
+        .unwrap();
+        assert!(event.alarms().is_empty());
+
+        let far = Alarm::Relative {
+            seconds: i64::MAX / 1000,
+            from_end: false,
+        };
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        assert_eq!(far.trigger(&start, &start), None);
+    }
+
+    /// The occurrences between 2024-11-10 and 2024-11-24 of a meeting held on Mondays at 10:00
+    /// from 2024-11-04 to 2024-12-09, with the given overrides
+    fn occurrences_with(overrides: &[&[&str]]) -> Vec<(EventTime, String)> {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR",
+            "VERSION:2.0",
+            "BEGIN:VEVENT",
+            "UID:abc",
+            "SUMMARY:Meeting",
+            "DTSTAMP:20240101T000000Z",
+            "DTSTART:20241104T100000Z",
+            "DURATION:PT1H",
+            "RRULE:FREQ=WEEKLY;COUNT=6",
+            "END:VEVENT",
+        ];
+        for override_lines in overrides {
+            lines.extend_from_slice(&["BEGIN:VEVENT", "UID:abc", "DTSTAMP:20240101T000000Z"]);
+            lines.extend_from_slice(override_lines);
+            lines.push("END:VEVENT");
+        }
+        lines.extend_from_slice(&["END:VCALENDAR", ""]);
+
+        let series = parse(&lines.join("\r\n"), item_url(), &chrono_tz::Europe::Paris).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 11, 10, 0, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 11, 24, 0, 0, 0).unwrap();
+        let mut occurrences: Vec<_> = series
+            .occurrences(&start, &end)
+            .into_iter()
+            .map(|event| (event.dtstart().clone(), event.name().to_string()))
+            .collect();
+        occurrences.sort();
+        occurrences
+    }
+
+    fn at(day: u32, hour: u32, name: &str) -> (EventTime, String) {
+        let time = Utc.with_ymd_and_hms(2024, 11, day, hour, 0, 0).unwrap();
+        (EventTime::DateTime(time), name.to_string())
+    }
+
+    #[test]
+    fn expands_series_without_overrides() {
+        assert_eq!(
+            occurrences_with(&[]),
+            [at(11, 10, "Meeting"), at(18, 10, "Meeting")]
+        );
+    }
+
+    #[test]
+    fn replaces_occurrences_by_their_override() {
+        let moved: &[&str] = &[
+            "RECURRENCE-ID:20241111T100000Z",
+            "SUMMARY:Moved meeting",
+            "DTSTART:20241112T150000Z",
+            "DURATION:PT1H",
+        ];
+        assert_eq!(
+            occurrences_with(&[moved]),
+            [at(12, 15, "Moved meeting"), at(18, 10, "Meeting")]
+        );
+    }
+
+    #[test]
+    fn includes_overrides_moved_into_the_window() {
+        let moved: &[&str] = &[
+            "RECURRENCE-ID:20241202T100000Z",
+            "SUMMARY:Early meeting",
+            "DTSTART:20241120T100000Z",
+            "DURATION:PT1H",
+        ];
+        assert_eq!(
+            occurrences_with(&[moved]),
+            [
+                at(11, 10, "Meeting"),
+                at(18, 10, "Meeting"),
+                at(20, 10, "Early meeting")
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_occurrences_moved_out_of_the_window() {
+        let moved: &[&str] = &[
+            "RECURRENCE-ID:20241118T100000Z",
+            "SUMMARY:Late meeting",
+            "DTSTART:20241130T100000Z",
+            "DURATION:PT1H",
+        ];
+        assert_eq!(occurrences_with(&[moved]), [at(11, 10, "Meeting")]);
+    }
+
+    #[test]
+    fn drops_cancelled_occurrences() {
+        let cancelled: &[&str] = &[
+            "RECURRENCE-ID:20241111T100000Z",
+            "SUMMARY:Meeting",
+            "DTSTART:20241111T100000Z",
+            "DURATION:PT1H",
+            "STATUS:CANCELLED",
+        ];
+        assert_eq!(occurrences_with(&[cancelled]), [at(18, 10, "Meeting")]);
+    }
 }
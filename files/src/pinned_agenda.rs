@@ -0,0 +1,137 @@
+//! The agenda of a room kept in a single pinned message, edited as the events change
+
+use chrono::Utc;
+use matrix_sdk::{
+    deserialized_responses::SyncOrStrippedState,
+    ruma::{
+        events::{
+            room::{
+                message::{ReplacementMetadata, RoomMessageEventContent},
+                pinned_events::RoomPinnedEventsEventContent,
+            },
+            SyncStateEvent,
+        },
+        OwnedEventId, OwnedRoomId,
+    },
+    Client, Room,
+};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use crate::calendars::get_agenda;
+use crate::config::RoomConfig;
+use crate::{format_events_message, room_calendars, State};
+
+/// How often the pinned agenda of a room is checked against the calendars
+const PINNED_AGENDA_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// Keeps the agenda of a room in a single pinned message, edited whenever the events change. The
+/// message is recorded in the store, so that the same one is edited across restarts.
+pub async fn maintain_pinned_agenda(client: Arc<Client>, state: Arc<State>, room_id: OwnedRoomId) {
+    let Some(room_config) = state.config.room(&room_id) else {
+        log::error!("No configuration for room {}", room_id);
+        return;
+    };
+
+    let mut interval = tokio::time::interval(PINNED_AGENDA_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let (body, html_body) = match pinned_agenda_message(&state, room_config).await {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!("Unable to update the pinned agenda of {}: {}", room_id, err);
+                continue;
+            }
+        };
+        let pinned = match state.store.pinned_agenda(room_id.as_str()) {
+            Ok(pinned) => pinned,
+            Err(err) => {
+                log::error!("Unable to read the pinned agenda of {}: {}", room_id, err);
+                continue;
+            }
+        };
+        if pinned
+            .as_ref()
+            .is_some_and(|(_, previous_body)| *previous_body == body)
+        {
+            continue;
+        }
+        let Some(room) = client.get_room(&room_id) else {
+            log::error!("Failed to find room with ID {}", room_id);
+            continue;
+        };
+
+        let pinned = pinned.and_then(|(event_id, _)| event_id.parse::<OwnedEventId>().ok());
+        let content = RoomMessageEventContent::text_html(body.clone(), html_body);
+        let event_id = match pinned {
+            Some(event_id) => {
+                let replacement = ReplacementMetadata::new(event_id.clone(), None);
+                match room.send(content.make_replacement(replacement, None)).await {
+                    Ok(_) => event_id,
+                    Err(error) => {
+                        log::error!("Error editing the pinned agenda: {error}");
+                        continue;
+                    }
+                }
+            }
+            None => match room.send(content).await {
+                Ok(response) => response.event_id,
+                Err(error) => {
+                    log::error!("Error sending the pinned agenda: {error}");
+                    continue;
+                }
+            },
+        };
+        log::info!("Pinned agenda of {} updated", room_id);
+
+        if let Err(err) =
+            state
+                .store
+                .record_pinned_agenda(room_id.as_str(), event_id.as_str(), &body)
+        {
+            log::error!("Unable to record the pinned agenda of {}: {}", room_id, err);
+        }
+        // Pinned again on every update, in case it was unpinned
+        if let Err(error) = pin_event(&room, &event_id).await {
+            log::error!("Unable to pin the agenda of {}: {error}", room_id);
+        }
+    }
+}
+
+/// The agenda of a room over its window, failing rather than leaving out the events of a
+/// calendar that cannot be read
+async fn pinned_agenda_message(
+    state: &State,
+    room: &RoomConfig,
+) -> Result<(String, String), String> {
+    let calendars = room_calendars(state, room).await;
+    let start = Utc::now();
+    let end = start + room.window;
+    let events = get_agenda(calendars, &start, &end, &state.config.default_timezone)
+        .await
+        .complete_events()?;
+    Ok(format_events_message(&events, room, &room.template))
+}
+
+/// Adds an event to the pinned events of a room, unless it already is
+async fn pin_event(room: &Room, event_id: &OwnedEventId) -> matrix_sdk::Result<()> {
+    let mut pinned = match room
+        .get_state_event_static::<RoomPinnedEventsEventContent>()
+        .await?
+    {
+        Some(raw) => match raw.deserialize()? {
+            SyncOrStrippedState::Sync(SyncStateEvent::Original(event)) => event.content.pinned,
+            SyncOrStrippedState::Sync(SyncStateEvent::Redacted(_)) => Vec::new(),
+            SyncOrStrippedState::Stripped(event) => event.content.pinned.unwrap_or_default(),
+        },
+        None => Vec::new(),
+    };
+    if pinned.contains(event_id) {
+        return Ok(());
+    }
+    pinned.push(event_id.clone());
+    room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+        .await?;
+    Ok(())
+}
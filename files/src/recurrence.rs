@@ -0,0 +1,757 @@
+//! Expansion of recurring events (iCal `RRULE`, `RDATE` and `EXDATE` properties)
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::event::EventTime;
+use crate::parser;
+use crate::timezone::EventTimeZone;
+
+/// Upper bound on the number of recurrence periods walked through for a single rule, so that
+/// rules that never produce an occurrence (e.g. `BYMONTH=2;BYMONTHDAY=30`) cannot loop forever
+const MAX_PERIODS: u32 = 50_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl FromStr for Frequency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "DAILY" => Ok(Frequency::Daily),
+            "WEEKLY" => Ok(Frequency::Weekly),
+            "MONTHLY" => Ok(Frequency::Monthly),
+            "YEARLY" => Ok(Frequency::Yearly),
+            other => Err(format!("Unsupported recurrence frequency {}", other)),
+        }
+    }
+}
+
+/// A single `RRULE` value
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecurrenceRule {
+    freq: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<EventTime>,
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+    by_set_pos: Vec<i32>,
+    week_start: Weekday,
+}
+
+impl RecurrenceRule {
+    /// Parses an `RRULE` value.
+    ///
+    /// A floating `UNTIL` is interpreted in `timezone`, which should be the zone of `DTSTART`.
+    ///
+    /// Unsupported parts are ignored so that the rule is at least expanded at its base
+    /// frequency: a rule repeating more often than daily is expanded once on each day it covers,
+    /// `BYHOUR`, `BYMINUTE` and `BYSECOND` are left out, and a rule with `BYWEEKNO` or
+    /// `BYYEARDAY` repeats on the anniversary of `DTSTART`.
+    pub fn parse(s: &str, timezone: Option<&EventTimeZone>) -> Result<Self, String> {
+        let mut freq = None;
+        let mut sub_daily_period = None;
+        let mut selects_days_of_year = false;
+        let mut rule = RecurrenceRule {
+            freq: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+
+        for part in s.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid RRULE part {}", part))?;
+            match key {
+                "FREQ" => match sub_daily_seconds(value) {
+                    Some(seconds) => {
+                        log::warn!("Expanding {} RRULE {} once a day", value, s);
+                        sub_daily_period = Some(seconds);
+                        freq = Some(Frequency::Daily);
+                    }
+                    None => freq = Some(value.parse()?),
+                },
+                "INTERVAL" => rule.interval = parse_number(key, value)?,
+                "COUNT" => rule.count = Some(parse_number(key, value)?),
+                "UNTIL" => {
+                    rule.until = Some(
+                        parser::parse_event_time(value, timezone)
+                            .map_err(|err| format!("Invalid UNTIL {}: {}", value, err))?,
+                    )
+                }
+                "BYDAY" => {
+                    rule.by_day = value
+                        .split(',')
+                        .map(parse_by_day)
+                        .collect::<Result<_, _>>()?
+                }
+                "BYMONTHDAY" => rule.by_month_day = parse_list(key, value)?,
+                "BYMONTH" => rule.by_month = parse_list(key, value)?,
+                "BYSETPOS" => rule.by_set_pos = parse_list(key, value)?,
+                "WKST" => rule.week_start = parse_weekday(value)?,
+                "BYHOUR" | "BYMINUTE" | "BYSECOND" => {
+                    log::warn!("Ignoring unsupported part {} of RRULE {}", part, s);
+                }
+                "BYWEEKNO" | "BYYEARDAY" => {
+                    log::warn!("Ignoring unsupported part {} of RRULE {}", part, s);
+                    selects_days_of_year = true;
+                }
+                _ => {
+                    return Err(format!("Unsupported RRULE part {}", part));
+                }
+            }
+        }
+
+        rule.freq = freq.ok_or_else(|| format!("Missing FREQ in RRULE {}", s))?;
+        if rule.interval == 0 {
+            return Err(format!("Invalid INTERVAL in RRULE {}", s));
+        }
+        if selects_days_of_year {
+            // The other parts only narrow down the days of the year that are left out
+            rule.by_day.clear();
+            rule.by_month_day.clear();
+            rule.by_month.clear();
+            rule.by_set_pos.clear();
+        }
+        if let Some(seconds) = sub_daily_period {
+            let span = u64::from(rule.interval) * u64::from(seconds);
+            if span % (24 * 3600) == 0 {
+                // Occurrences a whole number of days apart are those of a daily rule
+                rule.interval = u32::try_from(span / (24 * 3600)).unwrap_or(u32::MAX);
+            } else {
+                // Keep the rule to the days its occurrences span
+                rule.count = rule.count.map(|count| {
+                    let days = u64::from(count.saturating_sub(1)) * span / (24 * 3600) + 1;
+                    u32::try_from(days).unwrap_or(u32::MAX)
+                });
+                rule.interval = 1;
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+/// The length in seconds of the periods of a `FREQ` shorter than a day
+fn sub_daily_seconds(freq: &str) -> Option<u32> {
+    match freq {
+        "HOURLY" => Some(3600),
+        "MINUTELY" => Some(60),
+        "SECONDLY" => Some(1),
+        _ => None,
+    }
+}
+
+fn parse_number<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} value {}", key, value))
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Result<Vec<T>, String> {
+    value.split(',').map(|v| parse_number(key, v)).collect()
+}
+
+fn parse_weekday(value: &str) -> Result<Weekday, String> {
+    match value {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        other => Err(format!("Invalid weekday {}", other)),
+    }
+}
+
+/// Parses a `BYDAY` entry such as `MO`, `2TU` or `-1FR`
+fn parse_by_day(value: &str) -> Result<(Option<i32>, Weekday), String> {
+    let split = value.len().saturating_sub(2);
+    let (ordinal, weekday) = value.split_at(split);
+    let weekday = parse_weekday(weekday)?;
+    if ordinal.is_empty() {
+        return Ok((None, weekday));
+    }
+    let ordinal = parse_number("BYDAY", ordinal.trim_start_matches('+'))?;
+    Ok((Some(ordinal), weekday))
+}
+
+impl RecurrenceRule {
+    /// Returns the start of every occurrence generated by this rule that begins before `end`.
+    ///
+    /// `dtstart` is always the first occurrence, as required by RFC5545.
+    fn occurrence_starts(
+        &self,
+        dtstart: NaiveDateTime,
+        end: NaiveDateTime,
+        to_naive: &impl Fn(&EventTime) -> NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        let until = self.until.as_ref().map(|until| match until {
+            // A date UNTIL includes the whole day
+            EventTime::Date(date) => date.and_time(dtstart.time()),
+            EventTime::DateTime(_) => to_naive(until),
+        });
+
+        let mut starts = vec![dtstart];
+        let time = dtstart.time();
+
+        for period in 0..MAX_PERIODS {
+            if self
+                .period_start(dtstart.date(), period)
+                .is_none_or(|period_start| period_start > end.date())
+            {
+                return starts;
+            }
+
+            let mut candidates = self.period_dates(dtstart.date(), period);
+            if candidates.is_empty() {
+                continue;
+            }
+            candidates.sort();
+            candidates.dedup();
+            let candidates = self.apply_set_pos(candidates);
+
+            for date in candidates {
+                let start = date.and_time(time);
+                if start <= dtstart {
+                    continue;
+                }
+                if start >= end
+                    || until.is_some_and(|until| start > until)
+                    || self
+                        .count
+                        .is_some_and(|count| starts.len() >= count as usize)
+                {
+                    return starts;
+                }
+                starts.push(start);
+            }
+        }
+
+        log::warn!("Recurrence expansion stopped after {} periods", MAX_PERIODS);
+        starts
+    }
+
+    /// Returns the first day of the `period`-th period (counted in `INTERVAL`s) after the one
+    /// containing `dtstart`
+    fn period_start(&self, dtstart: NaiveDate, period: u32) -> Option<NaiveDate> {
+        let step = i64::from(period) * i64::from(self.interval);
+        match self.freq {
+            Frequency::Daily => dtstart.checked_add_signed(Duration::days(step)),
+            Frequency::Weekly => {
+                let offset = days_from(self.week_start, dtstart.weekday());
+                (dtstart - Duration::days(offset)).checked_add_signed(Duration::weeks(step))
+            }
+            Frequency::Monthly => {
+                let months = i64::from(dtstart.year()) * 12 + i64::from(dtstart.month0()) + step;
+                let year = i32::try_from(months.div_euclid(12)).ok()?;
+                NaiveDate::from_ymd_opt(year, months.rem_euclid(12) as u32 + 1, 1)
+            }
+            Frequency::Yearly => {
+                let year = i32::try_from(i64::from(dtstart.year()) + step).ok()?;
+                NaiveDate::from_ymd_opt(year, 1, 1)
+            }
+        }
+    }
+
+    /// Returns the candidate dates of the `period`-th period (counted in `INTERVAL`s) after the
+    /// one containing `dtstart`
+    fn period_dates(&self, dtstart: NaiveDate, period: u32) -> Vec<NaiveDate> {
+        let Some(period_start) = self.period_start(dtstart, period) else {
+            return Vec::new();
+        };
+        match self.freq {
+            Frequency::Daily => {
+                if self.matches_month(period_start)
+                    && self.matches_month_day(period_start)
+                    && self.matches_weekday(period_start)
+                {
+                    vec![period_start]
+                } else {
+                    Vec::new()
+                }
+            }
+            Frequency::Weekly => {
+                let weekdays: Vec<Weekday> = if self.by_day.is_empty() {
+                    vec![dtstart.weekday()]
+                } else {
+                    self.by_day.iter().map(|(_, weekday)| *weekday).collect()
+                };
+                weekdays
+                    .into_iter()
+                    .map(|weekday| {
+                        period_start + Duration::days(days_from(self.week_start, weekday))
+                    })
+                    .filter(|date| self.matches_month(*date))
+                    .collect()
+            }
+            Frequency::Monthly => {
+                if !self.matches_month(period_start) {
+                    return Vec::new();
+                }
+                self.month_dates(period_start.year(), period_start.month(), dtstart)
+            }
+            Frequency::Yearly => self.year_dates(period_start.year(), dtstart),
+        }
+    }
+
+    fn month_dates(&self, year: i32, month: u32, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        let days_in_month = days_in_month(year, month);
+        let mut dates: Vec<NaiveDate> = if !self.by_month_day.is_empty() {
+            self.by_month_day
+                .iter()
+                .filter_map(|day| resolve_ordinal(*day, days_in_month))
+                .filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+                .collect()
+        } else if self.by_day.is_empty() {
+            NaiveDate::from_ymd_opt(year, month, dtstart.day())
+                .into_iter()
+                .collect()
+        } else {
+            (1..=days_in_month)
+                .filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+                .collect()
+        };
+
+        if !self.by_day.is_empty() {
+            dates.retain(|date| {
+                self.by_day.iter().any(|(ordinal, weekday)| {
+                    date.weekday() == *weekday
+                        && ordinal.is_none_or(|ordinal| {
+                            matches_ordinal(ordinal, date.day(), days_in_month)
+                        })
+                })
+            });
+        }
+        dates
+    }
+
+    fn year_dates(&self, year: i32, dtstart: NaiveDate) -> Vec<NaiveDate> {
+        if self.by_month.is_empty() && self.by_month_day.is_empty() && !self.by_day.is_empty() {
+            // Without BYMONTH, BYDAY ordinals are relative to the whole year
+            let Some(first) = NaiveDate::from_ymd_opt(year, 1, 1) else {
+                return Vec::new();
+            };
+            let days_in_year = first.iter_days().take_while(|d| d.year() == year).count() as u32;
+            return first
+                .iter_days()
+                .take(days_in_year as usize)
+                .filter(|date| {
+                    self.by_day.iter().any(|(ordinal, weekday)| {
+                        date.weekday() == *weekday
+                            && ordinal.is_none_or(|ordinal| {
+                                matches_ordinal(ordinal, date.ordinal(), days_in_year)
+                            })
+                    })
+                })
+                .collect();
+        }
+
+        let months: Vec<u32> = if !self.by_month.is_empty() {
+            self.by_month.clone()
+        } else if !self.by_month_day.is_empty() {
+            (1..=12).collect()
+        } else {
+            vec![dtstart.month()]
+        };
+
+        months
+            .into_iter()
+            .flat_map(|month| self.month_dates(year, month, dtstart))
+            .collect()
+    }
+
+    fn apply_set_pos(&self, candidates: Vec<NaiveDate>) -> Vec<NaiveDate> {
+        if self.by_set_pos.is_empty() {
+            return candidates;
+        }
+        let len = candidates.len() as u32;
+        let mut selected: Vec<NaiveDate> = self
+            .by_set_pos
+            .iter()
+            .filter_map(|pos| resolve_ordinal(*pos, len))
+            .map(|pos| candidates[pos as usize - 1])
+            .collect();
+        selected.sort();
+        selected.dedup();
+        selected
+    }
+
+    fn matches_month(&self, date: NaiveDate) -> bool {
+        self.by_month.is_empty() || self.by_month.contains(&date.month())
+    }
+
+    fn matches_month_day(&self, date: NaiveDate) -> bool {
+        let days_in_month = days_in_month(date.year(), date.month());
+        self.by_month_day.is_empty()
+            || self
+                .by_month_day
+                .iter()
+                .any(|day| resolve_ordinal(*day, days_in_month) == Some(date.day()))
+    }
+
+    fn matches_weekday(&self, date: NaiveDate) -> bool {
+        self.by_day.is_empty()
+            || self
+                .by_day
+                .iter()
+                .any(|(_, weekday)| *weekday == date.weekday())
+    }
+}
+
+/// The recurrence set of an event: its rules, plus extra and excluded dates
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recurrence {
+    rules: Vec<RecurrenceRule>,
+    rdates: Vec<EventTime>,
+    exdates: Vec<EventTime>,
+}
+
+impl Recurrence {
+    pub fn add_rule(&mut self, rule: RecurrenceRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn add_rdate(&mut self, rdate: EventTime) {
+        self.rdates.push(rdate);
+    }
+
+    pub fn add_exdate(&mut self, exdate: EventTime) {
+        self.exdates.push(exdate);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.rdates.is_empty()
+    }
+
+    /// Returns the sorted start of every occurrence that begins before `end`.
+    ///
+    /// Times are handled as naive values: `to_naive` converts the `UNTIL`, `RDATE` and `EXDATE`
+    /// values into the same time scale as `dtstart`.
+    pub fn occurrence_starts(
+        &self,
+        dtstart: NaiveDateTime,
+        end: NaiveDateTime,
+        to_naive: impl Fn(&EventTime) -> NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        let mut starts = vec![dtstart];
+        for rule in &self.rules {
+            starts.extend(rule.occurrence_starts(dtstart, end, &to_naive));
+        }
+        starts.extend(
+            self.rdates
+                .iter()
+                .map(&to_naive)
+                .filter(|rdate| *rdate < end),
+        );
+
+        let exdates: Vec<NaiveDateTime> = self.exdates.iter().map(&to_naive).collect();
+        starts.retain(|start| !exdates.contains(start));
+
+        starts.sort();
+        starts.dedup();
+        starts
+    }
+}
+
+/// Number of days from `from` to the next (or same) `to`
+fn days_from(from: Weekday, to: Weekday) -> i64 {
+    i64::from((7 + to.num_days_from_monday() - from.num_days_from_monday()) % 7)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    match (
+        NaiveDate::from_ymd_opt(year, month, 1),
+        NaiveDate::from_ymd_opt(next_year, next_month, 1),
+    ) {
+        (Some(first), Some(next)) => (next - first).num_days() as u32,
+        _ => 0,
+    }
+}
+
+/// Resolves a 1-based ordinal that may count from the end (negative) into a 1-based position
+fn resolve_ordinal(ordinal: i32, len: u32) -> Option<u32> {
+    let position = if ordinal < 0 {
+        i64::from(len) + i64::from(ordinal) + 1
+    } else {
+        i64::from(ordinal)
+    };
+    if position >= 1 && position <= i64::from(len) {
+        Some(position as u32)
+    } else {
+        None
+    }
+}
+
+/// Whether the `position`-th day is the `ordinal`-th occurrence of its weekday in its period
+fn matches_ordinal(ordinal: i32, position: u32, len: u32) -> bool {
+    if ordinal > 0 {
+        (position - 1) / 7 + 1 == ordinal as u32
+    } else {
+        (len - position) / 7 + 1 == ordinal.unsigned_abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap()
+    }
+
+    fn recurrence(rrule: &str) -> Recurrence {
+        let mut recurrence = Recurrence::default();
+        recurrence.add_rule(RecurrenceRule::parse(rrule, None).unwrap());
+        recurrence
+    }
+
+    fn expand(recurrence: &Recurrence, dtstart: NaiveDateTime, end: NaiveDateTime) -> Vec<String> {
+        recurrence
+            .occurrence_starts(dtstart, end, |time| time.naive_utc())
+            .iter()
+            .map(|start| start.date().to_string())
+            .collect()
+    }
+
+    // The examples below come from RFC5545 3.8.5.3
+
+    #[test]
+    fn repeats_daily_for_a_count() {
+        let starts = expand(
+            &recurrence("FREQ=DAILY;COUNT=10"),
+            at(1997, 9, 2),
+            at(1998, 1, 1),
+        );
+        assert_eq!(starts.len(), 10);
+        assert_eq!(starts.last().unwrap(), "1997-09-11");
+    }
+
+    #[test]
+    fn includes_the_day_of_a_date_until() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=DAILY;UNTIL=19970905"),
+                at(1997, 9, 2),
+                at(1998, 1, 1)
+            ),
+            ["1997-09-02", "1997-09-03", "1997-09-04", "1997-09-05"]
+        );
+    }
+
+    #[test]
+    fn starts_weeks_on_the_week_start() {
+        let dtstart = at(1997, 8, 5);
+        let end = at(1998, 1, 1);
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=MO"),
+                dtstart,
+                end
+            ),
+            ["1997-08-05", "1997-08-10", "1997-08-19", "1997-08-24"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=SU"),
+                dtstart,
+                end
+            ),
+            ["1997-08-05", "1997-08-17", "1997-08-19", "1997-08-31"]
+        );
+    }
+
+    #[test]
+    fn repeats_on_the_last_friday_of_the_month() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=MONTHLY;BYDAY=-1FR"),
+                at(2024, 1, 26),
+                at(2024, 6, 1)
+            ),
+            [
+                "2024-01-26",
+                "2024-02-23",
+                "2024-03-29",
+                "2024-04-26",
+                "2024-05-31"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_on_the_last_weekday_of_the_month() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-1"),
+                at(1997, 9, 30),
+                at(1998, 4, 1)
+            ),
+            [
+                "1997-09-30",
+                "1997-10-31",
+                "1997-11-28",
+                "1997-12-31",
+                "1998-01-30",
+                "1998-02-27",
+                "1998-03-31"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_on_days_counted_from_the_end_of_the_month() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=MONTHLY;BYMONTHDAY=-3;COUNT=4"),
+                at(1997, 9, 28),
+                at(1998, 4, 1)
+            ),
+            ["1997-09-28", "1997-10-29", "1997-11-28", "1997-12-29"]
+        );
+    }
+
+    #[test]
+    fn skips_months_without_the_day_of_the_start() {
+        assert_eq!(
+            expand(&recurrence("FREQ=MONTHLY"), at(2024, 1, 31), at(2024, 6, 1)),
+            ["2024-01-31", "2024-03-31", "2024-05-31"]
+        );
+    }
+
+    #[test]
+    fn counts_weekdays_of_the_year() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=YEARLY;BYDAY=20MO"),
+                at(1997, 5, 19),
+                at(2000, 1, 1)
+            ),
+            ["1997-05-19", "1998-05-18", "1999-05-17"]
+        );
+    }
+
+    #[test]
+    fn removes_excluded_dates_from_the_count() {
+        let mut recurrence = recurrence("FREQ=DAILY;COUNT=5");
+        recurrence.add_exdate(EventTime::DateTime(at(2024, 3, 3).and_utc()));
+        recurrence.add_rdate(EventTime::DateTime(at(2024, 3, 10).and_utc()));
+        assert_eq!(
+            expand(&recurrence, at(2024, 3, 1), at(2024, 4, 1)),
+            [
+                "2024-03-01",
+                "2024-03-02",
+                "2024-03-04",
+                "2024-03-05",
+                "2024-03-10"
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_rules_that_never_match() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=30"),
+                at(2024, 1, 30),
+                at(9999, 1, 1)
+            ),
+            ["2024-01-30"]
+        );
+    }
+
+    #[test]
+    fn ignores_unsupported_parts() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=WEEKLY;BYDAY=MO;BYHOUR=9,17;COUNT=3"),
+                at(2024, 3, 4),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-04", "2024-03-11", "2024-03-18"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=YEARLY;BYWEEKNO=20;BYDAY=MO"),
+                at(2024, 5, 13),
+                at(2026, 1, 1)
+            ),
+            ["2024-05-13", "2025-05-13"]
+        );
+    }
+
+    #[test]
+    fn expands_rules_shorter_than_a_day_once_a_day() {
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=HOURLY;INTERVAL=6;COUNT=9"),
+                at(2024, 3, 1),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-01", "2024-03-02", "2024-03-03"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=MINUTELY;INTERVAL=30;UNTIL=20240302T120000Z"),
+                at(2024, 3, 1),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-01", "2024-03-02"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=HOURLY;INTERVAL=48;COUNT=3"),
+                at(2024, 3, 1),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-01", "2024-03-03", "2024-03-05"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=MINUTELY;INTERVAL=2880;UNTIL=20240306T120000Z"),
+                at(2024, 3, 1),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-01", "2024-03-03", "2024-03-05"]
+        );
+        assert_eq!(
+            expand(
+                &recurrence("FREQ=SECONDLY;INTERVAL=86400;COUNT=2"),
+                at(2024, 3, 1),
+                at(2024, 4, 1)
+            ),
+            ["2024-03-01", "2024-03-02"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!(RecurrenceRule::parse("INTERVAL=2", None).is_err());
+        assert!(RecurrenceRule::parse("FREQ=DAILY;INTERVAL=0", None).is_err());
+        assert!(RecurrenceRule::parse("FREQ=WEEKLY;BYDAY=XX", None).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+//! Reminders posted to a room ahead of its events
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId},
+    Client,
+};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use crate::calendars::get_agenda;
+use crate::config::{RoomConfig, MAX_REMINDER_DAYS};
+use crate::event::{Event, EventTime};
+use crate::locale::Locale;
+use crate::render::Message;
+use crate::{format_calendar_label, room_calendars, State};
+
+/// A reminder of an occurrence of an event
+#[derive(Debug)]
+pub struct Reminder {
+    pub event: Event,
+    /// The start of the occurrence
+    pub start: DateTime<Utc>,
+    pub due_at: DateTime<Utc>,
+}
+
+impl Reminder {
+    /// How long before the start of the event the reminder is due, negative when after
+    pub fn lead_time(&self) -> Duration {
+        self.start - self.due_at
+    }
+}
+
+/// The reminders of `events`, in the order they are due: at the alarms of an event when it has
+/// any and `alarms` is set, or else `lead_times` before it starts. All-day events start at
+/// midnight in `timezone`.
+pub fn reminders(
+    events: &[Event],
+    lead_times: &[Duration],
+    alarms: bool,
+    timezone: &Tz,
+) -> Vec<Reminder> {
+    let mut reminders = Vec::new();
+    for event in events {
+        let start = to_utc(event.dtstart(), timezone);
+        let end = to_utc(event.dtend(), timezone);
+        let due_times: Vec<DateTime<Utc>> = if !alarms || event.alarms().is_empty() {
+            lead_times
+                .iter()
+                .map(|lead_time| start - *lead_time)
+                .collect()
+        } else {
+            event
+                .alarms()
+                .iter()
+                .filter_map(|alarm| alarm.trigger(&start, &end))
+                .collect()
+        };
+        reminders.extend(due_times.into_iter().map(|due_at| Reminder {
+            event: event.clone(),
+            start,
+            due_at,
+        }));
+    }
+    reminders.sort_by(|a, b| a.due_at.cmp(&b.due_at).then_with(|| a.event.cmp(&b.event)));
+    reminders
+}
+
+fn to_utc(time: &EventTime, timezone: &Tz) -> DateTime<Utc> {
+    match time {
+        EventTime::DateTime(datetime) => *datetime,
+        EventTime::Date(date) => date
+            .and_time(Default::default())
+            .and_local_timezone(*timezone)
+            .earliest()
+            .map(|datetime| datetime.to_utc())
+            .unwrap_or_else(|| time.naive_utc().and_utc()),
+    }
+}
+
+/// How long after it was due a reminder is still posted, such as after a restart
+const REMINDER_GRACE_MINUTES: i64 = 10;
+
+/// The reminders of `reminders`, sorted by due time, to post at `now`: those due since
+/// the grace period. Also returns when the next one is due.
+fn due_reminders(
+    reminders: Vec<Reminder>,
+    now: &DateTime<Utc>,
+) -> (Vec<Reminder>, Option<DateTime<Utc>>) {
+    let expired = *now - Duration::minutes(REMINDER_GRACE_MINUTES);
+    let mut due = Vec::new();
+    for reminder in reminders {
+        if reminder.due_at > *now {
+            return (due, Some(reminder.due_at));
+        }
+        if reminder.due_at >= expired {
+            due.push(reminder);
+        }
+    }
+    (due, None)
+}
+
+/// How often the events are read again while no reminder is due, so that the reminders follow the
+/// changes to the events
+const REMINDER_POLL_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// Posts reminders of the events of a room as they fall due. The reminders posted are recorded
+/// in the store, so that none is posted twice across restarts.
+pub async fn post_reminders(client: Arc<Client>, state: Arc<State>, room_id: OwnedRoomId) {
+    let Some(room_config) = state.config.room(&room_id) else {
+        log::error!("No configuration for room {}", room_id);
+        return;
+    };
+
+    loop {
+        let now = Utc::now();
+        let expired = now - Duration::minutes(REMINDER_GRACE_MINUTES);
+        // Far enough for the reminders furthest ahead of their events to be due now
+        let end = now + Duration::days(MAX_REMINDER_DAYS + 1);
+        let events = get_agenda(
+            room_calendars(&state, room_config).await,
+            &now,
+            &end,
+            &state.config.default_timezone,
+        )
+        .await
+        .available_events();
+        let (due, next_due) = due_reminders(
+            reminders(
+                &events,
+                &room_config.reminders,
+                room_config.alarms,
+                &room_config.timezone,
+            ),
+            &now,
+        );
+        for reminder in &due {
+            post_reminder(&client, &state, room_config, reminder, &expired).await;
+        }
+
+        let wait = next_due
+            .and_then(|due_at| (due_at - Utc::now()).to_std().ok())
+            .map_or(REMINDER_POLL_INTERVAL, |wait| {
+                wait.min(REMINDER_POLL_INTERVAL)
+            });
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Posts a reminder to a room, unless it already was
+async fn post_reminder(
+    client: &Client,
+    state: &State,
+    room_config: &RoomConfig,
+    reminder: &Reminder,
+    expired: &DateTime<Utc>,
+) {
+    let room_id = &room_config.room_id;
+    let uid = reminder.event.uid();
+    match state
+        .store
+        .reminder_sent(room_id.as_str(), uid, &reminder.start, &reminder.due_at)
+    {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(err) => {
+            log::error!("Unable to check the reminders sent for {}: {}", uid, err);
+            return;
+        }
+    }
+
+    let Some(room) = client.get_room(room_id) else {
+        log::error!("Failed to find room with ID {}", room_id);
+        return;
+    };
+    let (body, html_body) = format_reminder(reminder, &room_config.locale);
+    if let Err(error) = room
+        .send(RoomMessageEventContent::text_html(body, html_body))
+        .await
+    {
+        log::error!("Error sending reminder: {error}");
+        return;
+    }
+    log::info!("Reminder of {} sent to {}", uid, room_id);
+
+    if let Err(err) = state.store.record_reminder(
+        room_id.as_str(),
+        uid,
+        &reminder.start,
+        &reminder.due_at,
+        expired,
+    ) {
+        log::error!("Unable to record the reminder of {}: {}", uid, err);
+    }
+}
+
+/// Describes a reminder, such as "Starting in 15 minutes: Standup (Room 1) https://meet.example"
+fn format_reminder(reminder: &Reminder, locale: &Locale) -> (String, String) {
+    let event = &reminder.event;
+    let lead_time = reminder.lead_time();
+    let notice = if lead_time > Duration::zero() {
+        locale.message(
+            "starting_in",
+            &[("lead_time", &locale.format_duration(lead_time))],
+        )
+    } else if lead_time < Duration::zero() {
+        locale.message(
+            "started_ago",
+            &[("lead_time", &locale.format_duration(-lead_time))],
+        )
+    } else {
+        locale.message("starting_now", &[])
+    };
+    let mut message = Message::new();
+    message.paragraph(|message| {
+        message.text(&format!("{}: ", notice));
+        format_calendar_label(message, event);
+        message.strong(event.name());
+        if let Some(location) = event.location() {
+            message.text(" (").linkified(location).text(")");
+        }
+        if let Some(link) = event.link() {
+            message.text(" ").link(link);
+        }
+    });
+    message.into_parts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Alarm;
+    use chrono::{NaiveDate, TimeZone};
+    use url::Url;
+
+    fn url() -> Url {
+        Url::parse("https://example.org/cal/event.ics").unwrap()
+    }
+
+    fn event(uid: &str, start: DateTime<Utc>, alarms: Vec<Alarm>) -> Event {
+        let mut event = Event::new_timed(
+            "Meeting".to_string(),
+            uid.to_string(),
+            start,
+            start + Duration::hours(2),
+            None,
+            None,
+            url(),
+            start,
+            None,
+        );
+        event.set_alarms(alarms);
+        event
+    }
+
+    fn all_day(date: NaiveDate) -> Event {
+        Event::new_all_day(
+            "Holiday".to_string(),
+            "holiday".to_string(),
+            date,
+            date.succ_opt().unwrap(),
+            None,
+            None,
+            url(),
+            Utc::now(),
+            None,
+        )
+    }
+
+    fn due_times(reminders: &[Reminder]) -> Vec<DateTime<Utc>> {
+        reminders.iter().map(|reminder| reminder.due_at).collect()
+    }
+
+    #[test]
+    fn follows_alarms_rather_than_lead_times() {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        let alarm = Alarm::Relative {
+            seconds: -15 * 60,
+            from_end: false,
+        };
+        let events = [event("abc", start, vec![alarm])];
+        let lead_times = [Duration::hours(1)];
+        let paris = chrono_tz::Europe::Paris;
+
+        let with_alarms = reminders(&events, &lead_times, true, &paris);
+        assert_eq!(due_times(&with_alarms), vec![start - Duration::minutes(15)]);
+        assert_eq!(with_alarms[0].lead_time(), Duration::minutes(15));
+
+        let without_alarms = reminders(&events, &lead_times, false, &paris);
+        assert_eq!(due_times(&without_alarms), vec![start - Duration::hours(1)]);
+
+        // Events without alarms get the lead times
+        let events = [event("abc", start, Vec::new())];
+        let reminded = reminders(&events, &lead_times, true, &paris);
+        assert_eq!(due_times(&reminded), vec![start - Duration::hours(1)]);
+
+        // Rooms without lead times are reminded at alarms only
+        assert!(reminders(&events, &[], true, &paris).is_empty());
+    }
+
+    #[test]
+    fn triggers_alarms_relative_to_the_end_or_at_a_time() {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        let at = Utc.with_ymd_and_hms(2024, 11, 3, 9, 0, 0).unwrap();
+        let events = [event(
+            "abc",
+            start,
+            vec![
+                Alarm::Relative {
+                    seconds: -10 * 60,
+                    from_end: true,
+                },
+                Alarm::At(at),
+            ],
+        )];
+
+        let reminded = reminders(&events, &[], true, &chrono_tz::UTC);
+        assert_eq!(
+            due_times(&reminded),
+            vec![at, start + Duration::minutes(110)]
+        );
+        assert_eq!(reminded[1].lead_time(), -Duration::minutes(110));
+    }
+
+    #[test]
+    fn starts_all_day_events_at_local_midnight_across_dst() {
+        let paris = chrono_tz::Europe::Paris;
+        let date = |month, day| NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        let lead_times = [Duration::hours(1)];
+
+        // Midnight is still in winter time on the day clocks go forward, and in summer time on
+        // the day they go back
+        for (date, midnight) in [
+            (date(3, 31), Utc.with_ymd_and_hms(2024, 3, 30, 23, 0, 0)),
+            (date(10, 27), Utc.with_ymd_and_hms(2024, 10, 26, 22, 0, 0)),
+        ] {
+            let midnight = midnight.unwrap();
+            let reminded = reminders(&[all_day(date)], &lead_times, true, &paris);
+            assert_eq!(reminded[0].start, midnight);
+            assert_eq!(due_times(&reminded), vec![midnight - Duration::hours(1)]);
+        }
+    }
+
+    #[test]
+    fn sorts_reminders_by_due_time() {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        let events = [
+            event("late", start + Duration::minutes(30), Vec::new()),
+            event("early", start, Vec::new()),
+            event("same", start, Vec::new()),
+        ];
+        let lead_times = [Duration::minutes(10), Duration::hours(1)];
+
+        let reminded = reminders(&events, &lead_times, true, &chrono_tz::UTC);
+        let order: Vec<(&str, DateTime<Utc>)> = reminded
+            .iter()
+            .map(|reminder| (reminder.event.uid(), reminder.due_at))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("early", start - Duration::hours(1)),
+                ("same", start - Duration::hours(1)),
+                ("late", start - Duration::minutes(30)),
+                ("early", start - Duration::minutes(10)),
+                ("same", start - Duration::minutes(10)),
+                ("late", start + Duration::minutes(20)),
+            ]
+        );
+    }
+
+    #[test]
+    fn posts_the_reminders_due_within_the_grace_period() {
+        let now = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        let lead_times = [-20, -10, -5, 0, 5, 10, 20].map(Duration::minutes);
+        let reminded = reminders(
+            &[event("abc", now, Vec::new())],
+            &lead_times,
+            true,
+            &chrono_tz::UTC,
+        );
+
+        let (due, next_due) = due_reminders(reminded, &now);
+        assert_eq!(
+            due_times(&due),
+            vec![now - Duration::minutes(10), now - Duration::minutes(5), now]
+        );
+        assert_eq!(next_due, Some(now + Duration::minutes(5)));
+
+        let (due, next_due) = due_reminders(Vec::new(), &now);
+        assert!(due.is_empty() && next_due.is_none());
+    }
+}
@@ -0,0 +1,315 @@
+//! Rendering of the messages posted to rooms, as a plain text body along with the matching HTML.
+//!
+//! Text read from calendars is escaped, so that it shows as is, and only tags and attributes of
+//! the HTML subset Matrix clients accept are produced.
+
+use url::Url;
+
+/// Schemes of the links Matrix clients keep in HTML messages
+const LINK_SCHEMES: [&str; 5] = ["https", "http", "ftp", "mailto", "magnet"];
+
+/// Schemes of the addresses turned into links when found in text
+const LINKIFIED_PREFIXES: [&str; 2] = ["https://", "http://"];
+
+/// A message built as both plain text and HTML
+#[derive(Debug, Default)]
+pub struct Message {
+    body: String,
+    html: String,
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Text shown as is
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.body.push_str(text);
+        self.html.push_str(&escape_html(text));
+        self
+    }
+
+    pub fn strong(&mut self, text: &str) -> &mut Self {
+        self.body.push_str(text);
+        self.html.push_str("<strong>");
+        self.html.push_str(&escape_html(text));
+        self.html.push_str("</strong>");
+        self
+    }
+
+    /// Text shown in a `#rrggbb` color, or as is when the color is not one
+    pub fn colored(&mut self, text: &str, color: Option<&str>) -> &mut Self {
+        match color.filter(|color| is_color(color)) {
+            Some(color) => {
+                self.body.push_str(text);
+                self.html.push_str(&format!(
+                    "<font data-mx-color=\"{}\">{}</font>",
+                    color,
+                    escape_html(text)
+                ));
+                self
+            }
+            None => self.text(text),
+        }
+    }
+
+    /// Text in which web addresses, such as that of a video call, are turned into links
+    pub fn linkified(&mut self, text: &str) -> &mut Self {
+        self.body.push_str(text);
+        self.html.push_str(&linkify(text));
+        self
+    }
+
+    /// A link to `url`, shown as text when clients would not keep it, such as a `javascript:` one
+    pub fn link(&mut self, url: &Url) -> &mut Self {
+        self.body.push_str(url.as_str());
+        self.html.push_str(&link_html(url, url.as_str()));
+        self
+    }
+
+    pub fn line_break(&mut self) -> &mut Self {
+        self.body.push('\n');
+        self.html.push_str("<br />");
+        self
+    }
+
+    pub fn heading(&mut self, text: &str) -> &mut Self {
+        self.start_block();
+        self.body.push_str(text);
+        self.html.push_str("<h3>");
+        self.html.push_str(&escape_html(text));
+        self.html.push_str("</h3>");
+        self
+    }
+
+    /// A paragraph, separated from the previous one by a blank line in the plain text
+    pub fn paragraph(&mut self, content: impl FnOnce(&mut Self)) -> &mut Self {
+        self.start_block();
+        self.html.push_str("<p>");
+        content(self);
+        self.html.push_str("</p>");
+        self
+    }
+
+    fn start_block(&mut self) {
+        if !self.body.is_empty() {
+            self.body.push_str("\n\n");
+        }
+    }
+
+    /// The plain text body and the HTML of the message
+    pub fn into_parts(self) -> (String, String) {
+        (self.body, self.html)
+    }
+}
+
+/// Collapses the whitespace of a text, such as a multi-line description, and cuts it to
+/// `max_chars` characters at a word boundary
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > 0 => &cut[..space],
+        _ => &cut,
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `color` is a `#rrggbb` color
+pub fn is_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn link_html(url: &Url, text: &str) -> String {
+    if LINK_SCHEMES.contains(&url.scheme()) {
+        format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(url.as_str()),
+            escape_html(text)
+        )
+    } else {
+        escape_html(text)
+    }
+}
+
+/// Escapes a text, turning the web addresses it contains into links
+pub fn linkify(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    while let Some(start) = LINKIFIED_PREFIXES
+        .iter()
+        .filter_map(|prefix| rest.find(prefix))
+        .min()
+    {
+        let length = rest[start..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"'))
+            .unwrap_or(rest.len() - start);
+        // Punctuation closing a sentence or a parenthesis is not part of a link
+        let link =
+            rest[start..start + length].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+
+        html += &escape_html(&rest[..start]);
+        html += &match Url::parse(link) {
+            Ok(url) => link_html(&url, link),
+            Err(_) => escape_html(link),
+        };
+        rest = &rest[start + link.len()..];
+    }
+    html + &escape_html(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(build: impl FnOnce(&mut Message)) -> (String, String) {
+        let mut message = Message::new();
+        build(&mut message);
+        message.into_parts()
+    }
+
+    #[test]
+    fn escapes_markup_in_text() {
+        let (body, html) = render(|message| {
+            message.paragraph(|message| {
+                message
+                    .strong("<script>alert(1)</script>")
+                    .text(" & <b onmouseover=\"x\">'bold'</b>");
+            });
+        });
+        assert_eq!(
+            body,
+            "<script>alert(1)</script> & <b onmouseover=\"x\">'bold'</b>"
+        );
+        assert_eq!(
+            html,
+            "<p><strong>&lt;script&gt;alert(1)&lt;/script&gt;</strong> &amp; \
+             &lt;b onmouseover=&quot;x&quot;&gt;&#39;bold&#39;&lt;/b&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn escapes_entities_again() {
+        let (body, html) = render(|message| {
+            message.text("&lt;p&gt; &#x3C;");
+        });
+        assert_eq!(body, "&lt;p&gt; &#x3C;");
+        assert_eq!(html, "&amp;lt;p&amp;gt; &amp;#x3C;");
+    }
+
+    #[test]
+    fn separates_blocks() {
+        let (body, html) = render(|message| {
+            message.heading("Events <today>");
+            message.paragraph(|message| {
+                message.text("one").line_break().text("two");
+            });
+            message.paragraph(|message| {
+                message.text("three");
+            });
+        });
+        assert_eq!(body, "Events <today>\n\none\ntwo\n\nthree");
+        assert_eq!(
+            html,
+            "<h3>Events &lt;today&gt;</h3><p>one<br />two</p><p>three</p>"
+        );
+    }
+
+    #[test]
+    fn only_uses_valid_colors() {
+        let (_, html) = render(|message| {
+            message.colored("[Team]", Some("#3366ff"));
+        });
+        assert_eq!(html, "<font data-mx-color=\"#3366ff\">[Team]</font>");
+
+        let (body, html) = render(|message| {
+            message.colored("[<i>Team</i>]", Some("red\" onclick=\"alert(1)"));
+        });
+        assert_eq!(body, "[<i>Team</i>]");
+        assert_eq!(html, "[&lt;i&gt;Team&lt;/i&gt;]");
+    }
+
+    #[test]
+    fn links_only_permitted_schemes() {
+        let (_, html) = render(|message| {
+            message.link(&Url::parse("https://example.org/a?b=1&c=2").unwrap());
+        });
+        assert_eq!(
+            html,
+            "<a href=\"https://example.org/a?b=1&amp;c=2\">https://example.org/a?b=1&amp;c=2</a>"
+        );
+
+        for url in [
+            "javascript:alert(document.cookie)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox",
+        ] {
+            let url = Url::parse(url).unwrap();
+            let (body, html) = render(|message| {
+                message.link(&url);
+            });
+            assert_eq!(body, url.as_str());
+            assert!(!html.contains("<a"), "{} was linked: {}", url, html);
+            assert!(!html.contains('<'), "{} left markup: {}", url, html);
+        }
+    }
+
+    #[test]
+    fn linkifies_web_addresses() {
+        let (body, html) = render(|message| {
+            message.linkified("Zoom (https://zoom.us/j/123?pwd=a&b=c). Room <1>");
+        });
+        assert_eq!(body, "Zoom (https://zoom.us/j/123?pwd=a&b=c). Room <1>");
+        assert_eq!(
+            html,
+            "Zoom (<a href=\"https://zoom.us/j/123?pwd=a&amp;b=c\">\
+             https://zoom.us/j/123?pwd=a&amp;b=c</a>). Room &lt;1&gt;"
+        );
+    }
+
+    #[test]
+    fn linkified_addresses_cannot_break_out() {
+        let (_, html) = render(|message| {
+            message.linkified(
+                "https://evil.example/\"><img src=x onerror=alert(1)> \
+                 http://x.org/'onmouseover='alert(1)' javascript:alert(1) https://",
+            );
+        });
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("href=\"javascript"), "{}", html);
+        assert!(!html.contains("'onmouseover"), "{}", html);
+        assert!(!html.contains("\"><"), "{}", html);
+        assert_eq!(html.matches("<a ").count(), 2, "{}", html);
+        assert_eq!(html.matches("</a>").count(), 2, "{}", html);
+    }
+
+    #[test]
+    fn truncates_at_word_boundaries() {
+        assert_eq!(truncate("short\n\n  text ", 20), "short text");
+        assert_eq!(truncate("one two, three four", 14), "one two…");
+        assert_eq!(truncate("ééééé ééééé", 8), "ééééé…");
+        assert_eq!(truncate("abcdefghij", 4), "abcd…");
+    }
+}
@@ -0,0 +1,255 @@
+//! Cron schedules of the messages posted to a room, evaluated on the wall clock of its zone
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+/// Names of the days of the week, by their number in standard cron
+const WEEKDAYS: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A standard cron expression (`minute hour day-of-month month day-of-week`), such as
+/// `30 8 * * Mon-Fri`
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    expression: String,
+    /// The schedules of the cron crate, any of which matches. The crate requires both the day
+    /// of the month and the day of the week to match, while standard cron matches days on
+    /// either when both are restricted, so such expressions are split in two.
+    crons: Vec<cron::Schedule>,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let invalid = |reason: String| {
+            format!(
+                "invalid schedule {}: {}, expected e.g. \"30 8 * * Mon-Fri\"",
+                expression, reason
+            )
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid(format!("{} fields instead of 5", fields.len())));
+        };
+        // The cron crate takes seconds first, and numbers the days of the week from 1 (Sunday)
+        // rather than from 0, so numbers are given to it as names
+        let weekday = weekday
+            .split(',')
+            .map(weekday_names)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?
+            .join(",");
+        // As in Vixie cron, a field starting with `*` (such as `*/2`) does not restrict the days
+        let days = if day.starts_with('*') || weekday.starts_with('*') {
+            vec![(day, weekday.as_str())]
+        } else {
+            vec![(day, "*"), ("*", weekday.as_str())]
+        };
+        let crons = days
+            .into_iter()
+            .map(|(day, weekday)| {
+                cron::Schedule::from_str(&format!(
+                    "0 {} {} {} {} {}",
+                    minute, hour, day, month, weekday
+                ))
+                .map_err(|err| invalid(err.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            expression: fields.join(" "),
+            crons,
+        })
+    }
+
+    /// The first time after `after` that this schedule matches the wall clock of `timezone`.
+    ///
+    /// A time skipped by a DST transition is moved an hour later, and a time that occurs twice
+    /// only matches the first time.
+    pub fn next_after(&self, after: &DateTime<Utc>, timezone: &Tz) -> Option<DateTime<Utc>> {
+        let local_after = after.with_timezone(timezone).naive_local();
+        self.crons
+            .iter()
+            .filter_map(|cron| {
+                cron.after(&local_after.and_utc())
+                    .take(1000)
+                    .filter_map(|local| to_utc(&local.naive_utc(), timezone))
+                    .find(|time| time > after)
+            })
+            .min()
+    }
+
+    /// The last time this schedule matches after `after` and up to `until`, if any
+    pub fn last_between(
+        &self,
+        after: &DateTime<Utc>,
+        until: &DateTime<Utc>,
+        timezone: &Tz,
+    ) -> Option<DateTime<Utc>> {
+        let mut last = None;
+        let mut time = *after;
+        while let Some(next) = self.next_after(&time, timezone) {
+            if next > *until {
+                break;
+            }
+            last = Some(next);
+            time = next;
+        }
+        last
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// Replaces the day numbers of an item of a day-of-week field, such as `1-5` or `0/2`, by names
+fn weekday_names(item: &str) -> Result<String, String> {
+    let (range, step) = match item.split_once('/') {
+        Some((range, step)) => (range, Some(step)),
+        None => (item, None),
+    };
+    let name = |day: &str| match day.parse::<usize>() {
+        Ok(number) => WEEKDAYS
+            .get(number)
+            .map(|name| name.to_string())
+            .ok_or_else(|| format!("no day of the week {}", number)),
+        Err(_) => Ok(day.to_string()),
+    };
+
+    let range = match range.split_once('-') {
+        // 7 is Sunday as well, which comes first for the cron crate
+        Some((first, "7")) if first != "0" && first != "7" => {
+            format!("{}-Sat,Sun", name(first)?)
+        }
+        Some((first, last)) => format!("{}-{}", name(first)?, name(last)?),
+        None => name(range)?,
+    };
+    Ok(match step {
+        Some(step) => format!("{}/{}", range, step),
+        None => range,
+    })
+}
+
+/// The time a wall clock time of `timezone` occurs, moving times skipped by a DST transition an
+/// hour later
+fn to_utc(local: &NaiveDateTime, timezone: &Tz) -> Option<DateTime<Utc>> {
+    local
+        .and_local_timezone(*timezone)
+        .earliest()
+        .or_else(|| {
+            (*local + Duration::hours(1))
+                .and_local_timezone(*timezone)
+                .earliest()
+        })
+        .map(|time| time.to_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use chrono_tz::Europe::Paris;
+
+    fn paris(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Paris
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .earliest()
+            .unwrap()
+            .to_utc()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn next(expression: &str, after: DateTime<Utc>) -> DateTime<Utc> {
+        Schedule::parse(expression)
+            .unwrap()
+            .next_after(&after, &Paris)
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_the_wall_clock_time_across_dst() {
+        assert_eq!(
+            next("0 8 * * *", paris(2024, 3, 30, 9, 0)),
+            paris(2024, 3, 31, 8, 0)
+        );
+        assert_eq!(paris(2024, 3, 31, 8, 0), utc(2024, 3, 31, 6, 0));
+        assert_eq!(
+            next("0 8 * * *", paris(2024, 10, 26, 9, 0)),
+            utc(2024, 10, 27, 7, 0)
+        );
+    }
+
+    #[test]
+    fn moves_times_skipped_by_dst_an_hour_later() {
+        assert_eq!(
+            next("30 2 * * *", paris(2024, 3, 30, 9, 0)),
+            utc(2024, 3, 31, 1, 30)
+        );
+    }
+
+    #[test]
+    fn matches_repeated_times_once() {
+        let first = next("30 2 * * *", paris(2024, 10, 26, 9, 0));
+        assert_eq!(first, utc(2024, 10, 27, 0, 30));
+        assert_eq!(next("30 2 * * *", first), utc(2024, 10, 28, 1, 30));
+    }
+
+    #[test]
+    fn numbers_days_of_the_week_from_sunday() {
+        // 2024-03-01 is a Friday
+        let after = paris(2024, 3, 1, 12, 0);
+        assert_eq!(next("0 9 * * 0", after), paris(2024, 3, 3, 9, 0));
+        assert_eq!(next("0 9 * * 7", after), paris(2024, 3, 3, 9, 0));
+        assert_eq!(next("0 9 * * 1-5", after), paris(2024, 3, 4, 9, 0));
+        assert_eq!(next("0 9 * * 6-7", after), paris(2024, 3, 2, 9, 0));
+        assert_eq!(next("0 9 * * Mon", after), paris(2024, 3, 4, 9, 0));
+    }
+
+    #[test]
+    fn matches_either_day_when_both_are_restricted() {
+        // The 13th of the month, or any Friday
+        let schedule = "0 9 13 * 5";
+        assert_eq!(
+            next(schedule, paris(2024, 3, 1, 12, 0)),
+            paris(2024, 3, 8, 9, 0)
+        );
+        assert_eq!(
+            next(schedule, paris(2024, 3, 8, 12, 0)),
+            paris(2024, 3, 13, 9, 0)
+        );
+        // A stepped field does not restrict the days
+        assert_eq!(
+            next("0 9 */2 * 5", paris(2024, 3, 1, 12, 0)),
+            paris(2024, 3, 15, 9, 0)
+        );
+    }
+
+    #[test]
+    fn finds_the_last_time_in_a_range() {
+        let schedule = Schedule::parse("0 8 * * *").unwrap();
+        assert_eq!(
+            schedule.last_between(&paris(2024, 3, 1, 0, 0), &paris(2024, 3, 4, 7, 0), &Paris),
+            Some(paris(2024, 3, 3, 8, 0))
+        );
+        assert_eq!(
+            schedule.last_between(&paris(2024, 3, 1, 9, 0), &paris(2024, 3, 2, 7, 0), &Paris),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(Schedule::parse("0 8 * *").is_err());
+        assert!(Schedule::parse("0 0 8 * * *").is_err());
+        assert!(Schedule::parse("0 25 * * *").is_err());
+        assert!(Schedule::parse("0 8 * * 8").is_err());
+    }
+}
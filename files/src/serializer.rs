@@ -0,0 +1,387 @@
+//! Serialization of events into iCal data (RFC5545), to write them to the server
+
+use chrono::{DateTime, Utc};
+
+use crate::event::{Event, EventTime};
+
+/// Longest line allowed by RFC5545, in octets, excluding the line break
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Serializes a single (non-recurring) event into a `VCALENDAR` object.
+///
+/// Times are written in UTC, which every client understands without a `VTIMEZONE`.
+pub fn serialize(event: &Event) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//matrix-calendar-bot//EN".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", escape_text(event.uid())),
+        format!("DTSTAMP:{}", format_utc(&Utc::now())),
+        format!("DTSTART{}", format_event_time(event.dtstart())),
+        format!("DTEND{}", format_event_time(event.dtend())),
+        format!("SUMMARY:{}", escape_text(event.name())),
+    ];
+    if let Some(location) = event.location() {
+        lines.push(format!("LOCATION:{}", escape_text(location)));
+    }
+    if let Some(description) = event.description() {
+        lines.push(format!("DESCRIPTION:{}", escape_text(description)));
+    }
+    if let Some(link) = event.link() {
+        lines.push(format!("URL:{}", link));
+    }
+    if let Some(status) = event.status() {
+        lines.push(format!("STATUS:{}", status.to_ical()));
+    }
+    if let Some(created) = event.creation_date() {
+        lines.push(format!("CREATED:{}", format_utc(created)));
+    }
+    lines.push(format!(
+        "LAST-MODIFIED:{}",
+        format_utc(event.last_modified())
+    ));
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect()
+}
+
+/// Changes the events of a resource, keeping the rest of its data as is. A new title or
+/// location applies to every instance of a recurring event, and an empty location is removed.
+///
+/// The content lines of the `VEVENT`s are edited in place: only the `SUMMARY`, `LOCATION`,
+/// `DTSTART`, `DTEND` (along with a `DURATION` it replaces) and `LAST-MODIFIED` lines are
+/// rewritten, and every other line is copied byte for byte, with its parameters and folding.
+pub fn update(
+    data: &str,
+    title: Option<&str>,
+    location: Option<&str>,
+    times: Option<(EventTime, EventTime)>,
+) -> Result<String, String> {
+    let lines = content_lines(data);
+    if !lines
+        .iter()
+        .any(|line| line.unfolded.eq_ignore_ascii_case("BEGIN:VCALENDAR"))
+    {
+        return Err("No calendar data".to_string());
+    }
+    let event_count = lines
+        .iter()
+        .filter(|line| line.unfolded.eq_ignore_ascii_case("BEGIN:VEVENT"))
+        .count();
+    if times.is_some() && event_count != 1 {
+        return Err("Only the time of a single event can be changed".to_string());
+    }
+
+    let mut replacements: Vec<(&str, Option<String>)> = Vec::new();
+    if let Some(title) = title {
+        replacements.push(("SUMMARY", Some(format!("SUMMARY:{}", escape_text(title)))));
+    }
+    if let Some(location) = location {
+        replacements.push((
+            "LOCATION",
+            Some(location)
+                .filter(|location| !location.is_empty())
+                .map(|location| format!("LOCATION:{}", escape_text(location))),
+        ));
+    }
+    if let Some((start, end)) = &times {
+        replacements.push((
+            "DTSTART",
+            Some(format!("DTSTART{}", format_event_time(start))),
+        ));
+        replacements.push(("DTEND", Some(format!("DTEND{}", format_event_time(end)))));
+        replacements.push(("DURATION", None));
+    }
+    replacements.push((
+        "LAST-MODIFIED",
+        Some(format!("LAST-MODIFIED:{}", format_utc(&Utc::now()))),
+    ));
+
+    let mut updated = String::with_capacity(data.len());
+    // The components the current line is nested in
+    let mut components: Vec<String> = Vec::new();
+    // The replaced properties of the current event
+    let mut written: Vec<&str> = Vec::new();
+    for line in &lines {
+        let name = line.name();
+        let in_event = components
+            .last()
+            .is_some_and(|component| component == "VEVENT");
+        if name == "BEGIN" {
+            components.push(line.value().to_ascii_uppercase());
+            if components
+                .last()
+                .is_some_and(|component| component == "VEVENT")
+            {
+                written.clear();
+            }
+        } else if name == "END" {
+            if in_event && line.value().eq_ignore_ascii_case("VEVENT") {
+                // Properties the event did not have yet
+                for (property, replacement) in &replacements {
+                    if let (false, Some(replacement)) = (written.contains(property), replacement) {
+                        updated.push_str(&fold_line(replacement));
+                    }
+                }
+            }
+            components.pop();
+        } else if in_event {
+            if let Some((property, replacement)) =
+                replacements.iter().find(|(property, _)| *property == name)
+            {
+                // Only the first occurrence of a property is kept
+                if !written.contains(property) {
+                    written.push(property);
+                    if let Some(replacement) = replacement {
+                        updated.push_str(&fold_line(replacement));
+                    }
+                }
+                continue;
+            }
+        }
+        updated.push_str(line.raw);
+    }
+    Ok(updated)
+}
+
+/// A content line, along with the physical lines it was folded into
+struct ContentLine<'a> {
+    /// The physical lines, with their line breaks
+    raw: &'a str,
+    unfolded: String,
+}
+
+impl ContentLine<'_> {
+    /// The name of the property, in upper case
+    fn name(&self) -> String {
+        let end = self
+            .unfolded
+            .find([';', ':'])
+            .unwrap_or(self.unfolded.len());
+        self.unfolded[..end].to_ascii_uppercase()
+    }
+
+    /// The value, after the first colon outside of a quoted parameter value
+    fn value(&self) -> &str {
+        let mut quoted = false;
+        for (index, c) in self.unfolded.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ':' if !quoted => return self.unfolded[index + 1..].trim(),
+                _ => {}
+            }
+        }
+        ""
+    }
+}
+
+/// Splits iCal data into its content lines, joining the lines folded onto the next ones
+fn content_lines(data: &str) -> Vec<ContentLine<'_>> {
+    let mut lines: Vec<ContentLine> = Vec::new();
+    let mut start = 0;
+    for physical in data.split_inclusive('\n') {
+        let end = start + physical.len();
+        let text = physical.trim_end_matches(['\r', '\n']);
+        match (text.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(line)) => {
+                line.raw = &data[start - line.raw.len()..end];
+                line.unfolded.push_str(continuation);
+            }
+            _ => lines.push(ContentLine {
+                raw: physical,
+                unfolded: text.to_string(),
+            }),
+        }
+        start = end;
+    }
+    lines
+}
+
+/// Formats the parameters and value of a `DTSTART` or `DTEND` property
+fn format_event_time(time: &EventTime) -> String {
+    match time {
+        EventTime::Date(date) => format!(";VALUE=DATE:{}", date.format("%Y%m%d")),
+        EventTime::DateTime(datetime) => format!(":{}", format_utc(datetime)),
+    }
+}
+
+fn format_utc(datetime: &DateTime<Utc>) -> String {
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a `TEXT` value (RFC5545 section 3.3.11)
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Folds a content line into lines of at most 75 octets, without splitting a character, and
+/// terminates it with CRLF
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / MAX_LINE_LENGTH * 3 + 2);
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            // The leading space counts towards the length of the continuation line
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use ical::property::Property;
+
+    const ATTENDEE: &str = "ATTENDEE;CN=\"Doe, John\";SENT-BY=\"mailto:a@b.c\":mailto:j@d.e\r\n";
+    const DESCRIPTION: &str = "DESCRIPTION:A description long enough to be folded over two li\r\n \
+         nes\\, with an escaped comma\r\n";
+
+    fn resource() -> String {
+        [
+            "BEGIN:VCALENDAR\r\n",
+            "VERSION:2.0\r\n",
+            "BEGIN:VTIMEZONE\r\n",
+            "TZID:Europe/Paris\r\n",
+            "BEGIN:STANDARD\r\n",
+            "DTSTART:19701025T030000\r\n",
+            "TZOFFSETFROM:+0200\r\n",
+            "TZOFFSETTO:+0100\r\n",
+            "END:STANDARD\r\n",
+            "END:VTIMEZONE\r\n",
+            "BEGIN:VEVENT\r\n",
+            "UID:abc\r\n",
+            "DTSTART;TZID=Europe/Paris:20241104T180000\r\n",
+            "DURATION:PT2H\r\n",
+            "SUMMARY:Board meeting\r\n",
+            "ORGANIZER;CN=\"Smith; Jane\":mailto:jane@example.org\r\n",
+            ATTENDEE,
+            DESCRIPTION,
+            "BEGIN:VALARM\r\n",
+            "ACTION:DISPLAY\r\n",
+            "DESCRIPTION:Reminder\r\n",
+            "TRIGGER:-PT15M\r\n",
+            "END:VALARM\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n",
+        ]
+        .concat()
+    }
+
+    fn parse_attendee(data: &str) -> Property {
+        let calendar = ical::IcalParser::new(data.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        calendar.events[0]
+            .properties
+            .iter()
+            .find(|prop| prop.name == "ATTENDEE")
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn keeps_untouched_lines_byte_for_byte() {
+        let data = resource();
+        let start = Utc.with_ymd_and_hms(2024, 11, 5, 17, 0, 0).unwrap();
+        let updated = update(
+            &data,
+            Some("Board meeting, rescheduled"),
+            Some("Community Hall"),
+            Some((
+                EventTime::DateTime(start),
+                EventTime::DateTime(start + chrono::Duration::hours(1)),
+            )),
+        )
+        .unwrap();
+
+        for line in [
+            ATTENDEE,
+            DESCRIPTION,
+            "ORGANIZER;CN=\"Smith; Jane\":mailto:jane@example.org\r\n",
+            "DTSTART:19701025T030000\r\n",
+            "DESCRIPTION:Reminder\r\n",
+            "TRIGGER:-PT15M\r\n",
+        ] {
+            assert!(
+                updated.contains(line),
+                "{:?} is missing from {}",
+                line,
+                updated
+            );
+        }
+        assert!(updated.contains("SUMMARY:Board meeting\\, rescheduled\r\n"));
+        assert!(updated.contains("DTSTART:20241105T170000Z\r\n"));
+        assert!(updated.contains("DTEND:20241105T180000Z\r\n"));
+        assert!(updated.contains("LOCATION:Community Hall\r\n"));
+        assert!(updated.contains("LAST-MODIFIED:"));
+        assert!(!updated.contains("DURATION"));
+        assert!(!updated.contains("Europe/Paris:20241104"));
+        assert_eq!(updated.matches("SUMMARY").count(), 1);
+        assert!(updated.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+
+        let (before, after) = (parse_attendee(&data), parse_attendee(&updated));
+        assert_eq!(before.params, after.params);
+        assert_eq!(before.value, after.value);
+    }
+
+    #[test]
+    fn removes_an_empty_location() {
+        let data = resource().replace("SUMMARY:", "LOCATION:Hall\r\nSUMMARY:");
+        let updated = update(&data, None, Some(""), None).unwrap();
+        assert!(!updated.contains("LOCATION"));
+        assert!(updated.contains("DURATION:PT2H\r\n"));
+        assert!(updated.contains("SUMMARY:Board meeting\r\n"));
+    }
+
+    #[test]
+    fn keeps_lf_line_breaks() {
+        let data = resource().replace("\r\n", "\n");
+        let updated = update(&data, Some("Renamed"), None, None).unwrap();
+        assert!(updated.contains(&ATTENDEE.replace("\r\n", "\n")));
+        assert!(updated.contains("SUMMARY:Renamed\r\n"));
+    }
+
+    #[test]
+    fn only_reschedules_single_events() {
+        let data = resource().replace(
+            "END:VCALENDAR",
+            "BEGIN:VEVENT\r\nUID:abc\r\nRECURRENCE-ID:20241111T180000Z\r\nEND:VEVENT\r\nEND:VCALENDAR",
+        );
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 11, 5).unwrap();
+        let times = Some((
+            EventTime::Date(date),
+            EventTime::Date(date.succ_opt().unwrap()),
+        ));
+        assert!(update(&data, None, None, times).is_err());
+        let updated = update(&data, Some("Renamed"), None, None).unwrap();
+        assert_eq!(updated.matches("SUMMARY:Renamed").count(), 2);
+    }
+
+    #[test]
+    fn folds_long_lines_at_75_octets() {
+        let folded = fold_line(&format!("SUMMARY:{}", "é".repeat(60)));
+        for line in folded.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_LENGTH, "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,614 @@
+//! Persistent copy of the calendars in a SQLite database, so that their events survive restarts
+//! and can still be shown while a server is unreachable
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use url::Url;
+
+use crate::event::{CalendarLabel, Series};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS calendars (
+        url TEXT PRIMARY KEY,
+        source TEXT NOT NULL,
+        label TEXT NOT NULL,
+        sync_token TEXT,
+        ctag TEXT,
+        synced_at INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS resources (
+        url TEXT PRIMARY KEY,
+        calendar TEXT NOT NULL REFERENCES calendars (url) ON DELETE CASCADE,
+        etag TEXT,
+        data TEXT NOT NULL,
+        series TEXT
+    );
+    CREATE INDEX IF NOT EXISTS resources_calendar ON resources (calendar);
+    CREATE TABLE IF NOT EXISTS sent_reminders (
+        room_id TEXT NOT NULL,
+        uid TEXT NOT NULL,
+        start INTEGER NOT NULL,
+        due_at INTEGER NOT NULL,
+        PRIMARY KEY (room_id, uid, start, due_at)
+    );
+    CREATE TABLE IF NOT EXISTS sent_digests (
+        room_id TEXT NOT NULL,
+        digest TEXT NOT NULL,
+        last_sent INTEGER NOT NULL,
+        PRIMARY KEY (room_id, digest)
+    );
+    CREATE TABLE IF NOT EXISTS pinned_agendas (
+        room_id TEXT PRIMARY KEY,
+        event_id TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS user_timezones (
+        user_id TEXT PRIMARY KEY,
+        timezone TEXT NOT NULL
+    );
+";
+
+/// A calendar object resource as last read from the server
+#[derive(Debug)]
+pub struct StoredResource {
+    pub etag: Option<String>,
+    /// The raw iCal data
+    pub data: String,
+    /// `None` for resources that hold no events, such as tasks
+    pub series: Option<Series>,
+}
+
+/// What is known of a calendar collection as of its last sync
+#[derive(Debug)]
+pub struct StoredCalendar {
+    pub sync_token: Option<String>,
+    pub ctag: Option<String>,
+    pub synced_at: DateTime<Utc>,
+    pub resources: HashMap<Url, StoredResource>,
+}
+
+#[derive(Debug)]
+pub struct EventStore {
+    connection: Mutex<Connection>,
+}
+
+impl EventStore {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, Box<dyn Error>> {
+        self.connection
+            .lock()
+            .map_err(|_| "The event store is poisoned".into())
+    }
+
+    /// Records the calendars a source was resolved into. Calendars it no longer designates are
+    /// removed along with their events.
+    pub fn save_calendars(
+        &self,
+        source: &str,
+        calendars: &[(Url, CalendarLabel)],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        let stored: Vec<String> = transaction
+            .prepare("SELECT url FROM calendars WHERE source = ?1")?
+            .query_map([source], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for url in stored {
+            if !calendars
+                .iter()
+                .any(|(calendar, _)| calendar.as_str() == url)
+            {
+                transaction.execute("DELETE FROM calendars WHERE url = ?1", [&url])?;
+            }
+        }
+
+        for (url, label) in calendars {
+            transaction.execute(
+                "INSERT INTO calendars (url, source, label) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (url) DO UPDATE SET source = excluded.source, label = excluded.label",
+                params![url.as_str(), source, serde_json::to_string(label)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The calendars a source was last resolved into
+    pub fn calendars(&self, source: &str) -> Result<Vec<(Url, CalendarLabel)>, Box<dyn Error>> {
+        let connection = self.connection()?;
+        let mut statement =
+            connection.prepare("SELECT url, label FROM calendars WHERE source = ?1")?;
+        let rows = statement.query_map([source], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut calendars = Vec::new();
+        for row in rows {
+            let (url, label) = row?;
+            calendars.push((url.parse()?, serde_json::from_str(&label)?));
+        }
+        Ok(calendars)
+    }
+
+    /// The events of a calendar as of its last sync, or `None` if it was never synced.
+    ///
+    /// Resources whose events cannot be read back, such as after a change to their format, are
+    /// left out, and the sync state is dropped so that they are fetched again.
+    pub fn load(&self, calendar: &Url) -> Result<Option<StoredCalendar>, Box<dyn Error>> {
+        let connection = self.connection()?;
+        let Some((mut sync_token, mut ctag, synced_at)) = connection
+            .query_row(
+                "SELECT sync_token, ctag, synced_at FROM calendars
+                 WHERE url = ?1 AND synced_at IS NOT NULL",
+                [calendar.as_str()],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut statement = connection
+            .prepare("SELECT url, etag, data, series FROM resources WHERE calendar = ?1")?;
+        let rows = statement.query_map([calendar.as_str()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+
+        let mut resources = HashMap::new();
+        for row in rows {
+            let (url, etag, data, series) = row?;
+            let series = match series.as_deref().map(serde_json::from_str).transpose() {
+                Ok(series) => series,
+                Err(err) => {
+                    log::warn!("Unable to read the stored events of {}: {}", url, err);
+                    sync_token = None;
+                    ctag = None;
+                    continue;
+                }
+            };
+            resources.insert(url.parse()?, StoredResource { etag, data, series });
+        }
+
+        Ok(Some(StoredCalendar {
+            sync_token,
+            ctag,
+            synced_at: DateTime::from_timestamp(synced_at, 0).unwrap_or_default(),
+            resources,
+        }))
+    }
+
+    /// Records the outcome of a sync of a calendar: its new sync state, and the resources that
+    /// were written or removed. The time of the sync is only updated when it `succeeded`.
+    pub fn save(
+        &self,
+        calendar: &Url,
+        sync_token: Option<&str>,
+        ctag: Option<&str>,
+        succeeded: bool,
+        written: &[(&Url, &StoredResource)],
+        removed: &[Url],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        for url in removed {
+            transaction.execute("DELETE FROM resources WHERE url = ?1", [url.as_str()])?;
+        }
+        for (url, resource) in written {
+            let series = resource
+                .series
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO resources (url, calendar, etag, data, series)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    url.as_str(),
+                    calendar.as_str(),
+                    resource.etag,
+                    resource.data,
+                    series
+                ],
+            )?;
+        }
+        transaction.execute(
+            "UPDATE calendars SET sync_token = ?2, ctag = ?3,
+             synced_at = CASE WHEN ?4 THEN ?5 ELSE synced_at END
+             WHERE url = ?1",
+            params![
+                calendar.as_str(),
+                sync_token,
+                ctag,
+                succeeded,
+                Utc::now().timestamp()
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Whether the reminder due at `due_at` of the occurrence of `uid` starting at `start` was
+    /// posted to a room
+    pub fn reminder_sent(
+        &self,
+        room_id: &str,
+        uid: &str,
+        start: &DateTime<Utc>,
+        due_at: &DateTime<Utc>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let connection = self.connection()?;
+        let sent = connection
+            .query_row(
+                "SELECT 1 FROM sent_reminders
+                 WHERE room_id = ?1 AND uid = ?2 AND start = ?3 AND due_at = ?4",
+                params![room_id, uid, start.timestamp(), due_at.timestamp()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(sent.is_some())
+    }
+
+    /// Records that a reminder was posted to a room, and forgets those due before `expired`
+    pub fn record_reminder(
+        &self,
+        room_id: &str,
+        uid: &str,
+        start: &DateTime<Utc>,
+        due_at: &DateTime<Utc>,
+        expired: &DateTime<Utc>,
+    ) -> Result<(), Box<dyn Error>> {
+        let connection = self.connection()?;
+        connection.execute(
+            "INSERT OR IGNORE INTO sent_reminders (room_id, uid, start, due_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![room_id, uid, start.timestamp(), due_at.timestamp()],
+        )?;
+        connection.execute(
+            "DELETE FROM sent_reminders WHERE due_at < ?1",
+            [expired.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The scheduled time of the last digest posted to a room, identified by its key
+    pub fn last_digest(
+        &self,
+        room_id: &str,
+        digest: &str,
+    ) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+        let connection = self.connection()?;
+        let last_sent: Option<i64> = connection
+            .query_row(
+                "SELECT last_sent FROM sent_digests WHERE room_id = ?1 AND digest = ?2",
+                params![room_id, digest],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(last_sent.and_then(|last_sent| DateTime::from_timestamp(last_sent, 0)))
+    }
+
+    /// Records the scheduled time of the last digest posted to a room, identified by its key
+    pub fn record_digest(
+        &self,
+        room_id: &str,
+        digest: &str,
+        sent: &DateTime<Utc>,
+    ) -> Result<(), Box<dyn Error>> {
+        let connection = self.connection()?;
+        connection.execute(
+            "INSERT OR REPLACE INTO sent_digests (room_id, digest, last_sent) VALUES (?1, ?2, ?3)",
+            params![room_id, digest, sent.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The ID of the agenda message pinned in a room, and the text it was last set to
+    pub fn pinned_agenda(&self, room_id: &str) -> Result<Option<(String, String)>, Box<dyn Error>> {
+        let connection = self.connection()?;
+        Ok(connection
+            .query_row(
+                "SELECT event_id, body FROM pinned_agendas WHERE room_id = ?1",
+                [room_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    /// Records the agenda message pinned in a room, and the text it was set to
+    pub fn record_pinned_agenda(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        body: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let connection = self.connection()?;
+        connection.execute(
+            "INSERT OR REPLACE INTO pinned_agendas (room_id, event_id, body) VALUES (?1, ?2, ?3)",
+            params![room_id, event_id, body],
+        )?;
+        Ok(())
+    }
+
+    /// The time zone a user set for their direct messages
+    pub fn user_timezone(&self, user_id: &str) -> Result<Option<Tz>, Box<dyn Error>> {
+        let connection = self.connection()?;
+        let timezone: Option<String> = connection
+            .query_row(
+                "SELECT timezone FROM user_timezones WHERE user_id = ?1",
+                [user_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(timezone.map(|timezone| timezone.parse()).transpose()?)
+    }
+
+    /// Records the time zone of a user, or forgets it with `None`
+    pub fn record_user_timezone(
+        &self,
+        user_id: &str,
+        timezone: Option<&Tz>,
+    ) -> Result<(), Box<dyn Error>> {
+        let connection = self.connection()?;
+        match timezone {
+            Some(timezone) => connection.execute(
+                "INSERT OR REPLACE INTO user_timezones (user_id, timezone) VALUES (?1, ?2)",
+                params![user_id, timezone.name()],
+            )?,
+            None => {
+                connection.execute("DELETE FROM user_timezones WHERE user_id = ?1", [user_id])?
+            }
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn store() -> EventStore {
+        EventStore::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn calendar() -> Url {
+        Url::parse("https://dav.example.org/cal/work/").unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 4, hour, minute, 0).unwrap()
+    }
+
+    fn resource(uid: &str) -> StoredResource {
+        let data = [
+            "BEGIN:VCALENDAR",
+            "VERSION:2.0",
+            "BEGIN:VEVENT",
+            &format!("UID:{}", uid),
+            "SUMMARY:Meeting",
+            "DTSTAMP:20240101T000000Z",
+            "DTSTART:20241104T180000Z",
+            "DTEND:20241104T190000Z",
+            "RRULE:FREQ=WEEKLY;COUNT=3",
+            "END:VEVENT",
+            "END:VCALENDAR",
+            "",
+        ]
+        .join("\r\n");
+        let url = calendar().join(&format!("{}.ics", uid)).unwrap();
+        let series = crate::parser::parse(&data, url, &chrono_tz::UTC).unwrap();
+        StoredResource {
+            etag: Some("\"1\"".to_string()),
+            data,
+            series: Some(series),
+        }
+    }
+
+    /// A store holding the calendar, synced with resources `a` and `b`
+    fn synced_store() -> EventStore {
+        let store = store();
+        let label = CalendarLabel::new("Work".to_string(), Some("#FF2968FF"));
+        store
+            .save_calendars("work", &[(calendar(), label.clone())])
+            .unwrap();
+        assert_eq!(store.calendars("work").unwrap(), vec![(calendar(), label)]);
+        assert!(store.load(&calendar()).unwrap().is_none());
+
+        let (a, b) = (resource("a"), resource("b"));
+        let (a_url, b_url) = (
+            calendar().join("a.ics").unwrap(),
+            calendar().join("b.ics").unwrap(),
+        );
+        store
+            .save(
+                &calendar(),
+                Some("token"),
+                Some("ctag"),
+                true,
+                &[(&a_url, &a), (&b_url, &b)],
+                &[],
+            )
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn loads_the_resources_and_sync_state_saved() {
+        let store = synced_store();
+        let stored = store.load(&calendar()).unwrap().unwrap();
+        assert_eq!(stored.sync_token.as_deref(), Some("token"));
+        assert_eq!(stored.ctag.as_deref(), Some("ctag"));
+        assert_eq!(stored.resources.len(), 2);
+
+        let a = &stored.resources[&calendar().join("a.ics").unwrap()];
+        let expected = resource("a");
+        assert_eq!(a.etag, expected.etag);
+        assert_eq!(a.data, expected.data);
+        let (series, expected) = (a.series.as_ref().unwrap(), expected.series.unwrap());
+        let (start, end) = (time(0, 0), time(0, 0) + chrono::Duration::weeks(4));
+        assert_eq!(series.occurrences(&start, &end).len(), 3);
+        assert_eq!(
+            series.occurrences(&start, &end),
+            expected.occurrences(&start, &end)
+        );
+        assert_eq!(series.master().unwrap().name(), "Meeting");
+
+        // A failed sync keeps its changes, but not its time
+        let synced_at = stored.synced_at;
+        store
+            .save(
+                &calendar(),
+                None,
+                None,
+                false,
+                &[],
+                &[calendar().join("b.ics").unwrap()],
+            )
+            .unwrap();
+        let stored = store.load(&calendar()).unwrap().unwrap();
+        assert_eq!(stored.sync_token, None);
+        assert_eq!(stored.synced_at, synced_at);
+        assert_eq!(stored.resources.len(), 1);
+
+        // Calendars a source no longer designates are removed with their events
+        store.save_calendars("work", &[]).unwrap();
+        assert!(store.calendars("work").unwrap().is_empty());
+        assert!(store.load(&calendar()).unwrap().is_none());
+    }
+
+    #[test]
+    fn drops_the_sync_state_along_with_unreadable_series() {
+        let store = synced_store();
+        store
+            .connection()
+            .unwrap()
+            .execute(
+                "UPDATE resources SET series = '{' WHERE url = ?1",
+                [calendar().join("a.ics").unwrap().as_str()],
+            )
+            .unwrap();
+
+        let stored = store.load(&calendar()).unwrap().unwrap();
+        assert_eq!(
+            stored.resources.keys().collect::<Vec<_>>(),
+            vec![&calendar().join("b.ics").unwrap()]
+        );
+        // So that the next sync fetches the resource again
+        assert_eq!(stored.sync_token, None);
+        assert_eq!(stored.ctag, None);
+    }
+
+    #[test]
+    fn records_the_reminders_sent() {
+        let store = store();
+        let (start, due_at) = (time(18, 0), time(17, 45));
+        let sent = |room_id: &str, uid: &str, start: &DateTime<Utc>, due_at: &DateTime<Utc>| {
+            store.reminder_sent(room_id, uid, start, due_at).unwrap()
+        };
+        assert!(!sent("!room:a", "abc", &start, &due_at));
+
+        store
+            .record_reminder("!room:a", "abc", &start, &due_at, &time(17, 0))
+            .unwrap();
+        // Recording it twice, as after a failed check, is harmless
+        store
+            .record_reminder("!room:a", "abc", &start, &due_at, &time(17, 0))
+            .unwrap();
+        assert!(sent("!room:a", "abc", &start, &due_at));
+        assert!(!sent("!room:b", "abc", &start, &due_at));
+        assert!(!sent("!room:a", "def", &start, &due_at));
+        assert!(!sent("!room:a", "abc", &time(19, 0), &due_at));
+        assert!(!sent("!room:a", "abc", &start, &time(17, 0)));
+
+        // Reminders due before the expiry are forgotten
+        store
+            .record_reminder("!room:a", "def", &start, &time(17, 50), &time(17, 46))
+            .unwrap();
+        assert!(!sent("!room:a", "abc", &start, &due_at));
+        assert!(sent("!room:a", "def", &start, &time(17, 50)));
+    }
+
+    #[test]
+    fn records_the_digests_sent() {
+        let store = store();
+        assert_eq!(store.last_digest("!room:a", "weekly").unwrap(), None);
+
+        store
+            .record_digest("!room:a", "weekly", &time(9, 0))
+            .unwrap();
+        store
+            .record_digest("!room:a", "daily", &time(8, 0))
+            .unwrap();
+        store
+            .record_digest("!room:a", "weekly", &time(9, 30))
+            .unwrap();
+        assert_eq!(
+            store.last_digest("!room:a", "weekly").unwrap(),
+            Some(time(9, 30))
+        );
+        assert_eq!(
+            store.last_digest("!room:a", "daily").unwrap(),
+            Some(time(8, 0))
+        );
+        assert_eq!(store.last_digest("!room:b", "weekly").unwrap(), None);
+    }
+
+    #[test]
+    fn records_pinned_agendas_and_user_time_zones() {
+        let store = store();
+        assert_eq!(store.pinned_agenda("!room:a").unwrap(), None);
+        store
+            .record_pinned_agenda("!room:a", "$first", "Upcoming Events")
+            .unwrap();
+        store
+            .record_pinned_agenda("!room:a", "$second", "No events")
+            .unwrap();
+        assert_eq!(
+            store.pinned_agenda("!room:a").unwrap(),
+            Some(("$second".to_string(), "No events".to_string()))
+        );
+        assert_eq!(store.pinned_agenda("!room:b").unwrap(), None);
+
+        assert_eq!(store.user_timezone("@alice:a").unwrap(), None);
+        store
+            .record_user_timezone("@alice:a", Some(&chrono_tz::Europe::Paris))
+            .unwrap();
+        assert_eq!(
+            store.user_timezone("@alice:a").unwrap(),
+            Some(chrono_tz::Europe::Paris)
+        );
+        store.record_user_timezone("@alice:a", None).unwrap();
+        assert_eq!(store.user_timezone("@alice:a").unwrap(), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! Templates of the agenda messages posted to a room, rendered with minijinja.
+//!
+//! An agenda is made of a header, an entry per event or else the empty state, and a footer, each
+//! rendered as plain text and as HTML. Calendar values are escaped in the HTML templates, except
+//! when passed through the `linkify` filter, which escapes them itself.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use minijinja::{context, AutoEscape, Environment, Error, ErrorKind, State, Value};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::event::{Event, EventTime};
+use crate::format_calendar_label;
+use crate::locale::Locale;
+use crate::render::{self, Message};
+
+/// The parts of an agenda, with their default plain text and HTML templates
+const PARTS: [(&str, &str, &str); 4] = [
+    ("header", "{{ heading }}", "<h3>{{ heading }}</h3>"),
+    (
+        "event",
+        "{{ event.number }}. \
+         {% if event.calendar %}[{{ event.calendar.name }}] {% endif %}{{ event.name }}:
+{{ event.times }}
+{% if event.location %}
+{{ messages.location }}: {{ event.location }}
+{% endif %}
+{% if event.description %}
+{{ event.description | truncate(200) }}
+{% endif %}
+{% if event.link %}
+{{ event.link }}
+{% endif %}",
+        "<p>{{ event.number }}. \
+         {% if event.calendar.color %}\
+         <font data-mx-color=\"{{ event.calendar.color }}\">[{{ event.calendar.name }}]</font> \
+         {% elif event.calendar %}[{{ event.calendar.name }}] {% endif %}\
+         <strong>{{ event.name }}</strong>:<br />{{ event.times }}\
+         {% if event.location %}<br />{{ messages.location }}: {{ event.location | linkify }}{% endif %}\
+         {% if event.description %}\
+         <br />{{ event.description | truncate(200) | linkify }}\
+         {% endif %}\
+         {% if event.link %}<br />{{ event.link | linkify }}{% endif %}</p>",
+    ),
+    ("empty", "{{ empty_message }}", "<p>{{ empty_message }}</p>"),
+    ("footer", "", ""),
+];
+
+/// The compiled templates of the agendas of a room
+#[derive(Clone, Debug)]
+pub struct AgendaTemplates {
+    environment: Arc<Environment<'static>>,
+}
+
+/// The values of an event available to templates
+#[derive(Serialize)]
+struct EventValues<'a> {
+    /// The number of the event in the agenda, which commands refer to it by
+    number: usize,
+    uid: &'a str,
+    name: &'a str,
+    location: Option<&'a str>,
+    description: Option<&'a str>,
+    link: Option<&'a str>,
+    /// `tentative`, `confirmed` or `cancelled`
+    status: Option<String>,
+    calendar: Option<CalendarValues<'a>>,
+    /// The start as `YYYY-MM-DD` for all-day events, or else as an RFC 3339 time in UTC
+    start: String,
+    end: String,
+    all_day: bool,
+    /// The start and end, formatted like the built-in agenda
+    times: String,
+    /// The day the event starts relative to today, such as `today`, `tomorrow` or `Friday`
+    day: String,
+    last_modified: String,
+    created: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CalendarValues<'a> {
+    name: &'a str,
+    color: Option<&'a str>,
+}
+
+impl Default for AgendaTemplates {
+    fn default() -> Self {
+        Self::new(&HashMap::new()).expect("valid default templates")
+    }
+}
+
+impl AgendaTemplates {
+    /// Compiles the templates of a room. `custom` holds the templates replacing the default
+    /// ones, by the name of their part, with an `_html` suffix for the HTML variant.
+    pub fn new(custom: &HashMap<String, String>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        for key in custom.keys() {
+            let part = key.strip_suffix("_html").unwrap_or(key);
+            if !PARTS.iter().any(|(name, _, _)| *name == part) {
+                errors.push(format!(
+                    "unknown template {}, expected one of header, event, empty and footer, or \
+                     their _html variant",
+                    key
+                ));
+            }
+        }
+
+        let mut environment = Environment::new();
+        environment.set_trim_blocks(true);
+        environment.set_lstrip_blocks(true);
+        environment.set_auto_escape_callback(|name| {
+            if name.ends_with("_html") {
+                AutoEscape::Html
+            } else {
+                AutoEscape::None
+            }
+        });
+        environment.add_filter("linkify", |text: &str| {
+            Value::from_safe_string(render::linkify(text))
+        });
+        environment.add_filter("truncate", |text: &str, max_chars: usize| {
+            render::truncate(text, max_chars)
+        });
+        environment.add_filter("date", format_date);
+
+        for (part, plain, html) in PARTS {
+            for (name, default) in [(part.to_string(), plain), (format!("{}_html", part), html)] {
+                let source = custom.get(&name).cloned().unwrap_or(default.to_string());
+                if let Err(err) = environment.add_template_owned(name.clone(), source) {
+                    errors.push(format!("templates.{}: {}", name, err));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            environment: Arc::new(environment),
+        })
+    }
+
+    /// Renders an agenda of `events` as plain text and HTML, with times shown in `timezone` and
+    /// written in the conventions of `locale`
+    pub fn render(
+        &self,
+        events: &[Event],
+        heading: &str,
+        empty: &str,
+        timezone: &Tz,
+        locale: &Locale,
+        now: &DateTime<Utc>,
+    ) -> Result<(String, String), String> {
+        let today = now.with_timezone(timezone).date_naive();
+        let events: Vec<EventValues> = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| EventValues::new(index + 1, event, timezone, locale, &today))
+            .collect();
+        let base = context! {
+            heading,
+            empty_message => empty,
+            count => events.len(),
+            events => Value::from_serialize(&events),
+            timezone => timezone.name(),
+            locale => locale.name(),
+            messages => locale.messages(),
+        };
+
+        let mut parts = vec![self.render_part("header", &base)?];
+        if events.is_empty() {
+            parts.push(self.render_part("empty", &base)?);
+        }
+        for event in &events {
+            parts.push(self.render_part("event", &context! { event, ..base.clone() })?);
+        }
+        parts.push(self.render_part("footer", &base)?);
+
+        let body = parts
+            .iter()
+            .map(|(body, _)| body.trim())
+            .filter(|body| !body.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let html = parts.into_iter().map(|(_, html)| html).collect();
+        Ok((body, html))
+    }
+
+    /// Renders an agenda like [`AgendaTemplates::render`], or in the built-in format when the
+    /// templates fail
+    pub fn render_or_default(
+        &self,
+        events: &[Event],
+        heading: &str,
+        empty: &str,
+        timezone: &Tz,
+        locale: &Locale,
+        now: &DateTime<Utc>,
+    ) -> (String, String) {
+        self.render(events, heading, empty, timezone, locale, now)
+            .unwrap_or_else(|err| {
+                log::error!("{}", err);
+                format_default_agenda(events, heading, empty, timezone, locale)
+            })
+    }
+
+    fn render_part(&self, part: &str, values: &Value) -> Result<(String, String), String> {
+        let render = |name: &str| {
+            self.environment
+                .get_template(name)
+                .and_then(|template| template.render(values))
+                .map_err(|err| format!("Unable to render the {} template: {:#}", name, err))
+        };
+        Ok((render(part)?, render(&format!("{}_html", part))?))
+    }
+}
+
+impl<'a> EventValues<'a> {
+    fn new(
+        number: usize,
+        event: &'a Event,
+        timezone: &Tz,
+        locale: &Locale,
+        today: &NaiveDate,
+    ) -> Self {
+        let start_date = match event.dtstart() {
+            EventTime::Date(date) => *date,
+            EventTime::DateTime(datetime) => datetime.with_timezone(timezone).date_naive(),
+        };
+        Self {
+            number,
+            uid: event.uid(),
+            name: event.name(),
+            location: event.location().map(String::as_str),
+            description: event.description().map(String::as_str),
+            link: event.link().map(|link| link.as_str()),
+            status: event.status().map(|status| status.to_ical().to_lowercase()),
+            calendar: event.calendar().map(|calendar| CalendarValues {
+                name: calendar.name(),
+                color: calendar.color().filter(|color| render::is_color(color)),
+            }),
+            start: format_event_time(event.dtstart()),
+            end: format_event_time(event.dtend()),
+            all_day: event.dtstart().as_date().is_some(),
+            times: locale.format_event_times(event.dtstart(), event.dtend(), timezone),
+            day: locale.relative_day(&start_date, today),
+            last_modified: event.last_modified().to_rfc3339(),
+            created: event.creation_date().map(DateTime::to_rfc3339),
+        }
+    }
+}
+
+/// Renders an agenda in the built-in format, which the default templates reproduce
+fn format_default_agenda(
+    events: &[Event],
+    heading: &str,
+    empty: &str,
+    timezone: &Tz,
+    locale: &Locale,
+) -> (String, String) {
+    let mut message = Message::new();
+    message.heading(heading);
+
+    if events.is_empty() {
+        message.paragraph(|message| {
+            message.text(empty);
+        });
+    };
+
+    for (index, event) in events.iter().enumerate() {
+        message.paragraph(|message| {
+            message.text(&format!("{}. ", index + 1));
+            format_calendar_label(message, event);
+            message
+                .strong(event.name())
+                .text(":")
+                .line_break()
+                .text(&locale.format_event_times(event.dtstart(), event.dtend(), timezone));
+
+            if let Some(location) = event.location() {
+                message
+                    .line_break()
+                    .text(&format!("{}: ", locale.message("location", &[])))
+                    .linkified(location);
+            }
+            if let Some(description) = event.description() {
+                let description = render::truncate(description, DESCRIPTION_MAX_CHARS);
+                if !description.is_empty() {
+                    message.line_break().linkified(&description);
+                }
+            }
+            if let Some(link) = event.link() {
+                message.line_break().link(link);
+            }
+        });
+    }
+
+    message.into_parts()
+}
+
+/// How much of the description of an event is shown in an agenda
+const DESCRIPTION_MAX_CHARS: usize = 200;
+
+fn format_event_time(time: &EventTime) -> String {
+    match time {
+        EventTime::Date(date) => date.to_string(),
+        EventTime::DateTime(datetime) => datetime.to_rfc3339(),
+    }
+}
+
+/// The `date` filter: formats a `start` or `end` value with a chrono format string, such as
+/// `{{ event.start | date("%H:%M") }}`, in the zone and language of the room
+fn format_date(state: &State, value: &str, format: &str) -> Result<String, Error> {
+    let timezone: Tz = state
+        .lookup("timezone")
+        .and_then(|timezone| timezone.as_str()?.parse().ok())
+        .unwrap_or(chrono_tz::UTC);
+    let locale = state
+        .lookup("locale")
+        .and_then(|locale| chrono::Locale::try_from(locale.as_str()?).ok())
+        .unwrap_or(chrono::Locale::POSIX);
+    let invalid_format = |_| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("invalid date format {}", format),
+        )
+    };
+
+    let mut formatted = String::new();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        write!(
+            formatted,
+            "{}",
+            datetime
+                .with_timezone(&timezone)
+                .format_localized(format, locale)
+        )
+        .map_err(invalid_format)?;
+    } else if let Ok(date) = value.parse::<NaiveDate>() {
+        write!(formatted, "{}", date.format_localized(format, locale)).map_err(invalid_format)?;
+    } else {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("{} is not a date", value),
+        ));
+    }
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::CalendarLabel;
+    use crate::locale::Catalogs;
+    use chrono::TimeZone;
+    use url::Url;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 11, 4, 9, 0, 0).unwrap()
+    }
+
+    fn event(name: &str, location: Option<&str>, description: Option<&str>) -> Event {
+        let start = Utc.with_ymd_and_hms(2024, 11, 4, 18, 0, 0).unwrap();
+        Event::new_timed(
+            name.to_string(),
+            "abc".to_string(),
+            start,
+            start + chrono::Duration::hours(1),
+            location.map(str::to_string),
+            description.map(str::to_string),
+            Url::parse("https://example.org/cal/abc.ics").unwrap(),
+            start,
+            None,
+        )
+    }
+
+    fn locale(name: &str) -> Locale {
+        Locale::new(name, &Catalogs::load(None).unwrap()).unwrap()
+    }
+
+    fn templates(custom: &[(&str, &str)]) -> Result<AgendaTemplates, Vec<String>> {
+        AgendaTemplates::new(
+            &custom
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+        )
+    }
+
+    fn render(templates: &AgendaTemplates, events: &[Event]) -> Result<(String, String), String> {
+        templates.render(
+            events,
+            "Upcoming Events",
+            "No events",
+            &chrono_tz::Europe::Paris,
+            &locale("fr_FR"),
+            &now(),
+        )
+    }
+
+    #[test]
+    fn escapes_calendar_values_in_html_only() {
+        let events = [event(
+            "<b>Party & co",
+            Some("<i>Hall"),
+            Some("Bring \"snacks\""),
+        )];
+        let (body, html) = render(&AgendaTemplates::default(), &events).unwrap();
+        assert!(body.contains("<b>Party & co"));
+        assert!(body.contains("<i>Hall"));
+        assert!(html.contains("<strong>&lt;b&gt;Party &amp; co</strong>"));
+        assert!(html.contains("&lt;i&gt;Hall"));
+        assert!(html.contains("Bring &quot;snacks&quot;"));
+        assert!(!html.contains("<b>") && !html.contains("<i>"));
+
+        // Custom HTML templates are escaped too
+        let custom = templates(&[("event_html", "<p>{{ event.name }}</p>")]).unwrap();
+        let (_, html) = render(&custom, &events).unwrap();
+        assert!(html.contains("<p>&lt;b&gt;Party &amp; co</p>"));
+    }
+
+    #[test]
+    fn turns_addresses_into_links() {
+        let events = [event(
+            "Meeting",
+            Some("Online: https://meet.example/abc?x=1&y=2."),
+            None,
+        )];
+        let (body, html) = render(&AgendaTemplates::default(), &events).unwrap();
+        assert!(body.contains("Online: https://meet.example/abc?x=1&y=2."));
+        assert!(html.contains(
+            "Online: <a href=\"https://meet.example/abc?x=1&amp;y=2\">\
+             https://meet.example/abc?x=1&amp;y=2</a>."
+        ));
+    }
+
+    #[test]
+    fn only_shows_valid_calendar_colors() {
+        let colored = |color: &str| {
+            let mut event = event("Meeting", None, None);
+            // Labels read back from the store skip the normalization of `CalendarLabel::new`
+            let label = serde_json::json!({ "name": "Work", "color": color });
+            event.set_calendar(serde_json::from_value::<CalendarLabel>(label).unwrap());
+            render(&AgendaTemplates::default(), &[event]).unwrap().1
+        };
+        assert!(colored("#ff2968").contains("<font data-mx-color=\"#ff2968\">[Work]</font>"));
+        for color in ["#FF2968FF", "red", "#ff2968\" onclick=\"x"] {
+            let html = colored(color);
+            assert!(!html.contains("data-mx-color"), "{}", html);
+            assert!(html.contains("[Work]"));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_templates() {
+        let errors = templates(&[("headr", "{{ heading }}"), ("event_htm", "")]).unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .any(|err| err.contains("unknown template headr")));
+        assert!(errors
+            .iter()
+            .any(|err| err.contains("unknown template event_htm")));
+
+        let errors = templates(&[("footer_html", "{{ heading")]).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("templates.footer_html: "));
+
+        assert!(templates(&[("header", "{{ count }} events"), ("empty_html", "")]).is_ok());
+    }
+
+    #[test]
+    fn formats_dates_in_the_zone_and_language_of_the_room() {
+        let custom = templates(&[(
+            "event",
+            "{{ event.start | date(\"%A %H:%M\") }} / {{ event.start[:10] | date(\"%d %B\") }}",
+        )])
+        .unwrap();
+        let (body, _) = render(&custom, &[event("Meeting", None, None)]).unwrap();
+        assert_eq!(body, "Upcoming Events\n\nlundi 19:00 / 04 novembre");
+
+        let custom = templates(&[("event", "{{ event.name | date(\"%H:%M\") }}")]).unwrap();
+        let err = render(&custom, &[event("Meeting", None, None)]).unwrap_err();
+        assert!(err.contains("Meeting is not a date"), "{}", err);
+    }
+
+    #[test]
+    fn falls_back_to_the_built_in_format() {
+        let custom =
+            templates(&[("event_html", "{{ event.location | date(\"%H:%M\") }}")]).unwrap();
+        let events = [event("Meeting", Some("Hall"), None)];
+        assert!(render(&custom, &events).is_err());
+
+        let (body, html) = custom.render_or_default(
+            &events,
+            "Upcoming Events",
+            "No events",
+            &chrono_tz::Europe::Paris,
+            &locale("fr_FR"),
+            &now(),
+        );
+        assert!(body.starts_with("Upcoming Events"));
+        assert!(body.contains("1. Meeting:"));
+        assert!(html.contains("<strong>Meeting</strong>"));
+        assert!(html.contains("Hall"));
+    }
+}
@@ -0,0 +1,429 @@
+//! Time zones of calendar events, either from the IANA database or from embedded `VTIMEZONE`s
+
+use chrono::{
+    DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use ical::parser::ical::component::IcalTimeZone;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::event::EventTime;
+use crate::recurrence::{Recurrence, RecurrenceRule};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EventTimeZone {
+    /// A zone of the IANA time zone database
+    Iana(Tz),
+    /// A zone defined by a `VTIMEZONE` component of the calendar data
+    Custom(CustomTimeZone),
+}
+
+impl EventTimeZone {
+    /// Looks up a `TZID` in the IANA database.
+    ///
+    /// Some clients prefix the IANA name with a vendor path (e.g.
+    /// `/mozilla.org/20050126_1/Europe/Berlin`), so trailing path components are tried as well.
+    pub fn from_iana_name(tzid: &str) -> Option<Self> {
+        let tzid = tzid.trim_matches('"');
+        let components: Vec<&str> = tzid.split('/').collect();
+        (0..components.len())
+            .filter_map(|i| components[i..].join("/").parse::<Tz>().ok())
+            .next()
+            .map(EventTimeZone::Iana)
+    }
+
+    /// Converts a local (wall clock) time of this zone into UTC.
+    ///
+    /// Ambiguous times resolve to their first occurrence, and times that fall in a DST gap are
+    /// interpreted with the offset in effect before the gap, as specified by RFC5545.
+    pub fn to_utc(&self, local: &NaiveDateTime) -> DateTime<Utc> {
+        match self {
+            EventTimeZone::Iana(tz) => match tz.from_local_datetime(local) {
+                LocalResult::Single(datetime) | LocalResult::Ambiguous(datetime, _) => {
+                    datetime.with_timezone(&Utc)
+                }
+                LocalResult::None => {
+                    let offset = tz
+                        .offset_from_local_datetime(&(*local - Duration::hours(3)))
+                        .earliest()
+                        .map(|offset| offset.fix())
+                        .unwrap_or_else(|| tz.offset_from_utc_datetime(local).fix());
+                    (*local - Duration::seconds(offset.local_minus_utc().into())).and_utc()
+                }
+            },
+            EventTimeZone::Custom(custom) => {
+                (*local - Duration::seconds(custom.offset_at_local(local).into())).and_utc()
+            }
+        }
+    }
+
+    /// Converts a UTC time into the local (wall clock) time of this zone
+    pub fn to_local(&self, utc: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            EventTimeZone::Iana(tz) => utc.with_timezone(tz).naive_local(),
+            EventTimeZone::Custom(custom) => {
+                let naive = utc.naive_utc();
+                naive + Duration::seconds(custom.offset_at_utc(&naive).into())
+            }
+        }
+    }
+}
+
+/// A `STANDARD` or `DAYLIGHT` sub-component of a `VTIMEZONE`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Observance {
+    /// Local time of the first onset, expressed with `offset_from`
+    dtstart: NaiveDateTime,
+    offset_from: i32,
+    offset_to: i32,
+    recurrence: Recurrence,
+}
+
+/// The onset of an observance
+#[derive(Clone, Debug)]
+struct Transition {
+    /// Local time of the onset, expressed with `offset_from`
+    onset: NaiveDateTime,
+    offset_from: i32,
+    offset_to: i32,
+}
+
+impl Transition {
+    fn utc_onset(&self) -> NaiveDateTime {
+        self.onset - Duration::seconds(self.offset_from.into())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "ZoneDefinition")]
+pub struct CustomTimeZone {
+    tzid: String,
+    observances: Vec<Observance>,
+    /// The transitions until the end of [`PRECOMPUTED_UNTIL_YEAR`], sorted by onset
+    #[serde(skip)]
+    transitions: Vec<Transition>,
+}
+
+/// The stored form of a [`CustomTimeZone`], from which its transitions are computed again
+#[derive(Deserialize)]
+struct ZoneDefinition {
+    tzid: String,
+    observances: Vec<Observance>,
+}
+
+impl From<ZoneDefinition> for CustomTimeZone {
+    fn from(definition: ZoneDefinition) -> Self {
+        Self::new(definition.tzid, definition.observances)
+    }
+}
+
+impl CustomTimeZone {
+    /// Builds a zone from a `VTIMEZONE` component
+    pub fn from_vtimezone(vtimezone: &IcalTimeZone) -> Result<Self, String> {
+        let tzid = vtimezone
+            .properties
+            .iter()
+            .find(|prop| prop.name == "TZID")
+            .and_then(|prop| prop.value.clone())
+            .ok_or("Missing TZID in VTIMEZONE")?;
+
+        let mut observances = Vec::new();
+        for transition in &vtimezone.transitions {
+            let mut dtstart = None;
+            let mut offset_from = None;
+            let mut offset_to = None;
+            let mut recurrence = Recurrence::default();
+            let mut rdates = Vec::new();
+
+            for prop in &transition.properties {
+                let Some(value) = prop.value.as_deref() else {
+                    continue;
+                };
+                match prop.name.as_str() {
+                    "DTSTART" => {
+                        dtstart = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()
+                    }
+                    "TZOFFSETFROM" => offset_from = parse_utc_offset(value),
+                    "TZOFFSETTO" => offset_to = parse_utc_offset(value),
+                    "RRULE" => recurrence.add_rule(RecurrenceRule::parse(value, None)?),
+                    "RDATE" => rdates.extend(value.split(',').filter_map(|rdate| {
+                        NaiveDateTime::parse_from_str(rdate, "%Y%m%dT%H%M%S").ok()
+                    })),
+                    _ => {}
+                }
+            }
+
+            let dtstart =
+                dtstart.ok_or_else(|| format!("Missing DTSTART in VTIMEZONE {}", tzid))?;
+            let offset_from =
+                offset_from.ok_or_else(|| format!("Missing TZOFFSETFROM in VTIMEZONE {}", tzid))?;
+            let offset_to =
+                offset_to.ok_or_else(|| format!("Missing TZOFFSETTO in VTIMEZONE {}", tzid))?;
+
+            // Onsets are expanded in local time, and the expansion converts RDATEs from UTC
+            for rdate in rdates {
+                let rdate = rdate - Duration::seconds(offset_from.into());
+                recurrence.add_rdate(EventTime::DateTime(rdate.and_utc()));
+            }
+
+            observances.push(Observance {
+                dtstart,
+                offset_from,
+                offset_to,
+                recurrence,
+            });
+        }
+
+        if observances.is_empty() {
+            return Err(format!(
+                "No STANDARD or DAYLIGHT component in VTIMEZONE {}",
+                tzid
+            ));
+        }
+
+        Ok(Self::new(tzid, observances))
+    }
+
+    fn new(tzid: String, observances: Vec<Observance>) -> Self {
+        // Observances usually start long ago (1601 for Outlook), so they are expanded once
+        // rather than for every time converted
+        let transitions = expand_transitions(&observances, precomputed_end());
+        Self {
+            tzid,
+            observances,
+            transitions,
+        }
+    }
+
+    pub fn tzid(&self) -> &str {
+        &self.tzid
+    }
+
+    /// The offset of the earliest observance, used before any onset
+    fn standard_offset(&self) -> i32 {
+        self.observances
+            .iter()
+            .min_by_key(|observance| observance.dtstart)
+            .map(|observance| observance.offset_from)
+            .unwrap_or(0)
+    }
+
+    /// The transitions that can be in effect at `local`
+    fn transitions_until(&self, local: &NaiveDateTime) -> Cow<'_, [Transition]> {
+        if *local < precomputed_end() {
+            Cow::Borrowed(&self.transitions)
+        } else {
+            Cow::Owned(expand_transitions(
+                &self.observances,
+                *local + Duration::days(1),
+            ))
+        }
+    }
+
+    /// The UTC offset in seconds of a local time: the one of the observance with the most
+    /// recent onset. Times skipped or repeated by a transition are interpreted with the offset
+    /// before it (RFC5545 3.3.5).
+    fn offset_at_local(&self, local: &NaiveDateTime) -> i32 {
+        let transitions = self.transitions_until(local);
+        let count = transitions.partition_point(|transition| transition.onset <= *local);
+        let Some(transition) = count.checked_sub(1).map(|index| &transitions[index]) else {
+            return self.standard_offset();
+        };
+        let skipped = Duration::seconds((transition.offset_to - transition.offset_from).into());
+        if *local < transition.onset + skipped {
+            transition.offset_from
+        } else {
+            transition.offset_to
+        }
+    }
+
+    /// The UTC offset in seconds in effect at a UTC time
+    fn offset_at_utc(&self, utc: &NaiveDateTime) -> i32 {
+        // A day later is past the local time of `utc` at any offset
+        let transitions = self.transitions_until(&(*utc + Duration::days(1)));
+        let count = transitions.partition_point(|transition| transition.utc_onset() <= *utc);
+        match count.checked_sub(1) {
+            Some(index) => transitions[index].offset_to,
+            None => self.standard_offset(),
+        }
+    }
+}
+
+/// Transitions are precomputed until the end of this year, and expanded for each conversion
+/// after it
+const PRECOMPUTED_UNTIL_YEAR: i32 = 2100;
+
+fn precomputed_end() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(PRECOMPUTED_UNTIL_YEAR + 1, 1, 1)
+        .unwrap_or(NaiveDate::MAX)
+        .and_time(NaiveTime::MIN)
+}
+
+/// The onsets of `observances` before `end`, sorted
+fn expand_transitions(observances: &[Observance], end: NaiveDateTime) -> Vec<Transition> {
+    let mut transitions: Vec<Transition> = observances
+        .iter()
+        .flat_map(|observance| {
+            // UNTIL values (and RDATEs, see above) are in UTC, while onsets are in local time
+            let to_local = |time: &EventTime| {
+                time.naive_utc() + Duration::seconds(observance.offset_from.into())
+            };
+            observance
+                .recurrence
+                .occurrence_starts(observance.dtstart, end, to_local)
+                .into_iter()
+                .filter(|onset| *onset < end)
+                .map(|onset| Transition {
+                    onset,
+                    offset_from: observance.offset_from,
+                    offset_to: observance.offset_to,
+                })
+        })
+        .collect();
+    transitions.sort_by_key(|transition| transition.onset);
+    transitions
+}
+
+/// Parses a UTC offset such as `+0200` or `-053000` into seconds
+fn parse_utc_offset(value: &str) -> Option<i32> {
+    let (sign, digits) = match value.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[0..2].parse().ok()?;
+    let minutes: i32 = digits[2..4].parse().ok()?;
+    let seconds: i32 = digits.get(4..6).map_or(Ok(0), str::parse).ok()?;
+    Some(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Datelike;
+
+    /// The zone Outlook sends for Central Europe, with rules starting in 1601
+    const OUTLOOK_ZONE: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VTIMEZONE\r
+TZID:W. Europe Standard Time\r
+BEGIN:STANDARD\r
+DTSTART:16010101T030000\r
+TZOFFSETFROM:+0200\r
+TZOFFSETTO:+0100\r
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=10\r
+END:STANDARD\r
+BEGIN:DAYLIGHT\r
+DTSTART:16010101T020000\r
+TZOFFSETFROM:+0100\r
+TZOFFSETTO:+0200\r
+RRULE:FREQ=YEARLY;INTERVAL=1;BYDAY=-1SU;BYMONTH=3\r
+END:DAYLIGHT\r
+END:VTIMEZONE\r
+END:VCALENDAR\r
+";
+
+    fn vtimezone(data: &str) -> Result<CustomTimeZone, String> {
+        let calendar = ical::IcalParser::new(data.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        CustomTimeZone::from_vtimezone(&calendar.timezones[0])
+    }
+
+    fn zones() -> [EventTimeZone; 2] {
+        [
+            EventTimeZone::Custom(vtimezone(OUTLOOK_ZONE).unwrap()),
+            EventTimeZone::Iana(chrono_tz::Europe::Paris),
+        ]
+    }
+
+    fn local(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        local(date).and_utc()
+    }
+
+    #[test]
+    fn reads_vtimezones() {
+        let zone = vtimezone(OUTLOOK_ZONE).unwrap();
+        assert_eq!(zone.tzid(), "W. Europe Standard Time");
+        assert_eq!(zone.observances.len(), 2);
+
+        // The transitions are not stored, but computed again when a zone is read back
+        let stored: CustomTimeZone =
+            serde_json::from_str(&serde_json::to_string(&zone).unwrap()).unwrap();
+        assert_eq!(stored.transitions.len(), zone.transitions.len());
+        assert!(!stored.transitions.is_empty());
+
+        let missing_offset = OUTLOOK_ZONE.replace("TZOFFSETTO:+0200\r\n", "");
+        assert!(vtimezone(&missing_offset).is_err());
+    }
+
+    #[test]
+    fn converts_like_the_iana_zone() {
+        let [custom, iana] = zones();
+        for year in [1999, 2024, 2037] {
+            let mut time = utc(&format!("{}-01-01 00:00", year));
+            while time.year() == year {
+                assert_eq!(custom.to_local(&time), iana.to_local(&time), "{}", time);
+                let local = iana.to_local(&time);
+                assert_eq!(custom.to_utc(&local), iana.to_utc(&local), "{}", local);
+                time += Duration::minutes(30);
+            }
+        }
+    }
+
+    #[test]
+    fn converts_times_after_the_precomputed_transitions() {
+        let zone = EventTimeZone::Custom(vtimezone(OUTLOOK_ZONE).unwrap());
+        assert_eq!(
+            zone.to_local(&utc("2150-07-01 12:00")),
+            local("2150-07-01 14:00")
+        );
+        assert_eq!(
+            zone.to_utc(&local("2150-12-01 12:00")),
+            utc("2150-12-01 11:00")
+        );
+    }
+
+    #[test]
+    fn moves_times_in_a_dst_gap_forward() {
+        // 02:30 does not exist on the last Sunday of March, and is read with the winter offset
+        for zone in zones() {
+            assert_eq!(
+                zone.to_utc(&local("2024-03-31 02:30")),
+                utc("2024-03-31 01:30")
+            );
+            assert_eq!(
+                zone.to_local(&utc("2024-03-31 01:30")),
+                local("2024-03-31 03:30")
+            );
+        }
+    }
+
+    #[test]
+    fn reads_repeated_times_as_their_first_occurrence() {
+        // 02:30 happens twice on the last Sunday of October
+        for zone in zones() {
+            assert_eq!(
+                zone.to_utc(&local("2024-10-27 02:30")),
+                utc("2024-10-27 00:30")
+            );
+            assert_eq!(
+                zone.to_local(&utc("2024-10-27 00:30")),
+                local("2024-10-27 02:30")
+            );
+            assert_eq!(
+                zone.to_local(&utc("2024-10-27 01:30")),
+                local("2024-10-27 02:30")
+            );
+        }
+    }
+}